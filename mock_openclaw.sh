@@ -2,5 +2,5 @@
 # Read input (which we ignore in this mock)
 cat > /dev/null
 
-# Respond with success
-echo '{"action": "grant", "response": {"status": "ok", "message": "Request granted"}}'
+# Accept every phase; `res` is the GIFT result after execute
+echo '{"accept": true, "res": {"status": "ok", "message": "Request granted"}}'
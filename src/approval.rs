@@ -0,0 +1,240 @@
+use crate::envelope::{HandlerRequest, Phase};
+use crate::payload::{KnockPayload, WishPayload};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+
+/// How long operators have to answer, from the `[approval]` config section.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ApprovalConfig {
+    /// `retry` hint, in seconds, sent with the `busy` answer when nobody
+    /// approved in time.
+    pub retry: u64,
+    /// Seconds after which items are dropped, answered or not.
+    pub expire: u64,
+}
+
+impl Default for ApprovalConfig {
+    fn default() -> Self {
+        Self { retry: 300, expire: 24 * 60 * 60 }
+    }
+}
+
+/// An operator's answer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Resolution {
+    Approved,
+    /// Declined with a reason name.
+    Denied(String),
+}
+
+/// The outcome of `ApprovalQueue::wait`.
+#[derive(Debug, PartialEq)]
+pub enum Approval {
+    Resolved(Resolution),
+    /// Still pending; the requester should retry after this many seconds.
+    Waiting(u64),
+}
+
+/// A queued item as shown by `wishp pending`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingItem {
+    pub id: String,
+    pub peer: String,
+    pub phase: Phase,
+    pub conv: String,
+    /// KNOCK `prev`, or the WISH `task.act`.
+    pub preview: Option<String>,
+    pub offer: Option<serde_json::Value>,
+    /// Unix time the request was queued.
+    pub queued: u64,
+}
+
+struct Item {
+    info: PendingItem,
+    /// Unix time after which the item is dropped.
+    expires: u64,
+    key: [u8; 32],
+    resolution: Option<Resolution>,
+    waiter: Option<oneshot::Sender<Resolution>>,
+}
+
+/// KNOCKs and WISHes waiting for an operator. A requester that gives up
+/// and sends the same message again later picks up the answer.
+#[derive(Default)]
+pub struct ApprovalQueue {
+    items: Mutex<Vec<Item>>,
+}
+
+impl ApprovalQueue {
+    /// Queues `request`, or finds the same message queued by an earlier
+    /// attempt, and waits up to `limit` for an operator's answer.
+    pub async fn wait(&self, request: &HandlerRequest, limit: Duration, config: &ApprovalConfig) -> Approval {
+        let key = message_key(request);
+        let receiver = {
+            let mut items = self.items.lock().unwrap();
+            expire(&mut items);
+
+            match items.iter().position(|i| i.key == key) {
+                Some(i) if items[i].resolution.is_some() => {
+                    return Approval::Resolved(items[i].resolution.clone().unwrap_or(Resolution::Approved));
+                }
+                Some(i) => {
+                    let (sender, receiver) = oneshot::channel();
+                    items[i].info.conv = request.conv.clone();
+                    items[i].waiter = Some(sender);
+                    receiver
+                }
+                None => {
+                    let (sender, receiver) = oneshot::channel();
+                    let info = PendingItem {
+                        id: hex::encode(rand::random::<[u8; 4]>()),
+                        peer: request.peer.id.clone(),
+                        phase: request.phase,
+                        conv: request.conv.clone(),
+                        preview: preview(request),
+                        offer: request.msg.payload.get("offer").cloned(),
+                        queued: now(),
+                    };
+                    eprintln!("Awaiting approval: {} {} from {}", info.id, request.phase.name(), info.peer);
+                    let expires = info.queued.saturating_add(config.expire);
+                    items.push(Item { info, expires, key, resolution: None, waiter: Some(sender) });
+                    receiver
+                }
+            }
+        };
+
+        match tokio::time::timeout(limit, receiver).await {
+            Ok(Ok(resolution)) => Approval::Resolved(resolution),
+            _ => Approval::Waiting(config.retry),
+        }
+    }
+
+    pub fn list(&self) -> Vec<PendingItem> {
+        let mut items = self.items.lock().unwrap();
+        expire(&mut items);
+        items
+            .iter()
+            .filter(|i| i.resolution.is_none())
+            .map(|i| i.info.clone())
+            .collect()
+    }
+
+    /// Answers item `id`. A requester still waiting gets the answer now;
+    /// the answer also stands for the same message sent again until the
+    /// item expires, so retries need no second approval.
+    pub fn resolve(&self, id: &str, resolution: Resolution) -> Result<()> {
+        let mut items = self.items.lock().unwrap();
+        let item = items
+            .iter_mut()
+            .find(|i| i.info.id == id && i.resolution.is_none())
+            .ok_or_else(|| anyhow!("No pending request {}", id))?;
+
+        if let Some(waiter) = item.waiter.take() {
+            let _ = waiter.send(resolution.clone());
+        }
+        item.resolution = Some(resolution);
+        Ok(())
+    }
+}
+
+fn expire(items: &mut Vec<Item>) {
+    let now = now();
+    items.retain(|i| i.expires > now);
+}
+
+/// Identifies a message across attempts, leaving out the per-connection
+/// key exchange fields.
+fn message_key(request: &HandlerRequest) -> [u8; 32] {
+    let mut payload = request.msg.payload.clone();
+    payload.remove("eph_key");
+    payload.remove("auth");
+    let payload: std::collections::BTreeMap<_, _> = payload.into_iter().collect();
+
+    let mut hasher = Sha256::new();
+    hasher.update(request.peer.id.as_bytes());
+    hasher.update([request.msg.stage]);
+    hasher.update(serde_json::to_vec(&payload).unwrap_or_default());
+    hasher.finalize().into()
+}
+
+fn preview(request: &HandlerRequest) -> Option<String> {
+    match request.phase {
+        Phase::Knock => request.msg.parse::<KnockPayload>().ok()?.prev,
+        _ => request.msg.parse::<WishPayload>().ok()?.action().map(str::to_string),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::{PeerInfo, ENVELOPE_VERSION};
+    use crate::protocol::Message;
+    use std::collections::HashMap;
+
+    fn knock(eph: u8) -> HandlerRequest {
+        let payload = HashMap::from([
+            ("c".to_string(), serde_json::json!(1)),
+            ("pri".to_string(), serde_json::json!(2)),
+            ("prev".to_string(), serde_json::json!("Summarize a paper")),
+            ("eph_key".to_string(), serde_json::json!([eph])),
+            ("auth".to_string(), serde_json::json!([eph])),
+        ]);
+        HandlerRequest {
+            v: ENVELOPE_VERSION,
+            phase: Phase::Knock,
+            conv: format!("conv-{}", eph),
+            peer: PeerInfo { id: "quest-1".to_string(), fp: None, addr: None },
+            round: 0,
+            msg: Message {
+                stage: 1,
+                counter: 1,
+                timestamp: 0,
+                from: "quest-1".to_string(),
+                to: "me".to_string(),
+                payload,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_approve_while_waiting() {
+        let queue = ApprovalQueue::default();
+        let request = knock(1);
+        let config = ApprovalConfig::default();
+        let (approval, _) = tokio::join!(queue.wait(&request, Duration::from_secs(5), &config), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let pending = queue.list();
+            assert_eq!(pending.len(), 1);
+            assert_eq!(pending[0].preview.as_deref(), Some("Summarize a paper"));
+            queue.resolve(&pending[0].id, Resolution::Approved).unwrap();
+        });
+        assert_eq!(approval, Approval::Resolved(Resolution::Approved));
+        assert!(queue.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_answer_kept_for_retry() {
+        let queue = ApprovalQueue::default();
+        let config = ApprovalConfig { retry: 60, ..ApprovalConfig::default() };
+        let approval = queue.wait(&knock(1), Duration::from_millis(10), &config).await;
+        assert_eq!(approval, Approval::Waiting(60));
+
+        let id = queue.list()[0].id.clone();
+        queue.resolve(&id, Resolution::Denied("busy".to_string())).unwrap();
+        assert!(queue.list().is_empty());
+        assert!(queue.resolve(&id, Resolution::Approved).is_err());
+
+        // The retry uses a fresh key exchange but is the same request.
+        let approval = queue.wait(&knock(2), Duration::from_millis(10), &config).await;
+        assert_eq!(approval, Approval::Resolved(Resolution::Denied("busy".to_string())));
+    }
+}
@@ -0,0 +1,321 @@
+use crate::protocol;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const BLOCKLIST_VERSION: u8 = 1;
+
+/// Why an agent was blocked (spec §12.3).
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(into = "u8", try_from = "u8")]
+pub enum BlockReason {
+    Spam = 1,
+    MalformedMessages = 2,
+    SizeViolations = 3,
+    RateLimitViolations = 4,
+    SuspiciousBehavior = 5,
+    ManualBlock = 6,
+}
+
+impl BlockReason {
+    pub fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(BlockReason::Spam),
+            2 => Ok(BlockReason::MalformedMessages),
+            3 => Ok(BlockReason::SizeViolations),
+            4 => Ok(BlockReason::RateLimitViolations),
+            5 => Ok(BlockReason::SuspiciousBehavior),
+            6 => Ok(BlockReason::ManualBlock),
+            _ => Err(anyhow!("Invalid block reason: {}", value)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BlockReason::Spam => "spam",
+            BlockReason::MalformedMessages => "malformed_messages",
+            BlockReason::SizeViolations => "size_violations",
+            BlockReason::RateLimitViolations => "rate_limit_violations",
+            BlockReason::SuspiciousBehavior => "suspicious_behavior",
+            BlockReason::ManualBlock => "manual_block",
+        }
+    }
+
+    /// Violations of this kind before an automatic block (spec §12.4).
+    fn threshold(self) -> u16 {
+        match self {
+            BlockReason::SizeViolations => 3,
+            BlockReason::RateLimitViolations => 10,
+            BlockReason::ManualBlock => 1,
+            _ => 5,
+        }
+    }
+}
+
+impl From<BlockReason> for u8 {
+    fn from(reason: BlockReason) -> u8 {
+        reason as u8
+    }
+}
+
+impl TryFrom<u8> for BlockReason {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        BlockReason::from_u8(value)
+    }
+}
+
+impl std::str::FromStr for BlockReason {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        [
+            BlockReason::Spam,
+            BlockReason::MalformedMessages,
+            BlockReason::SizeViolations,
+            BlockReason::RateLimitViolations,
+            BlockReason::SuspiciousBehavior,
+            BlockReason::ManualBlock,
+        ]
+        .into_iter()
+        .find(|r| r.name() == s || (*r as u8).to_string() == s)
+        .ok_or_else(|| anyhow!("Unknown block reason: {}", s))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(into = "u8", try_from = "u8")]
+pub enum BlockedBy {
+    Manual = 1,
+    Automatic = 2,
+}
+
+impl From<BlockedBy> for u8 {
+    fn from(by: BlockedBy) -> u8 {
+        by as u8
+    }
+}
+
+impl TryFrom<u8> for BlockedBy {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(BlockedBy::Manual),
+            2 => Ok(BlockedBy::Automatic),
+            _ => Err(anyhow!("Invalid blocked_by: {}", value)),
+        }
+    }
+}
+
+/// A blocked agent, in the spec §12.2 layout.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BlocklistEntry {
+    pub id: String,
+    /// SHA-256 of the agent's public key, when it is in the keyring.
+    #[serde(default)]
+    pub fp: Option<[u8; 32]>,
+    pub r: BlockReason,
+    pub at: u32,
+    pub by: BlockedBy,
+    pub c: u16,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BlocklistFile {
+    ver: u8,
+    updated: u32,
+    entries: Vec<BlocklistEntry>,
+}
+
+/// Blocked agents, persisted after every change. Violations below the
+/// blocking threshold are only counted in memory.
+pub struct Blocklist {
+    entries: HashMap<String, BlocklistEntry>,
+    violations: HashMap<String, u16>,
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl Blocklist {
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let modified = file_modified(&path);
+        let entries = read_entries(&path)?;
+        Ok(Self {
+            entries,
+            violations: HashMap::new(),
+            path,
+            modified,
+        })
+    }
+
+    /// Re-reads the file if something else (e.g. `wishp block`) changed it
+    /// since we last loaded or saved it.
+    pub fn refresh(&mut self) -> Result<()> {
+        let modified = file_modified(&self.path);
+        if modified == self.modified {
+            return Ok(());
+        }
+        self.entries = read_entries(&self.path)?;
+        self.modified = modified;
+        Ok(())
+    }
+
+    pub fn is_blocked(&self, agent_id: &str) -> bool {
+        self.entries.contains_key(agent_id)
+    }
+
+    pub fn list(&self) -> Vec<&BlocklistEntry> {
+        let mut entries: Vec<_> = self.entries.values().collect();
+        entries.sort_by_key(|e| e.at);
+        entries
+    }
+
+    /// Counts a violation and blocks the agent once the reason's threshold
+    /// is reached. Returns whether this call blocked it.
+    pub fn add_violation(&mut self, agent_id: &str, reason: BlockReason, fp: Option<[u8; 32]>) -> Result<bool> {
+        self.refresh()?;
+        if self.is_blocked(agent_id) {
+            return Ok(false);
+        }
+
+        let count = self.violations.entry(agent_id.to_string()).or_insert(0);
+        *count += 1;
+        if *count < reason.threshold() {
+            return Ok(false);
+        }
+
+        let count = self.violations.remove(agent_id).unwrap_or_default();
+        self.insert(agent_id, reason, BlockedBy::Automatic, count, fp)?;
+        Ok(true)
+    }
+
+    pub fn block(&mut self, agent_id: &str, reason: BlockReason, fp: Option<[u8; 32]>) -> Result<()> {
+        self.refresh()?;
+        let count = self.violations.remove(agent_id).unwrap_or_default();
+        self.insert(agent_id, reason, BlockedBy::Manual, count, fp)
+    }
+
+    /// Returns whether the agent was blocked.
+    pub fn unblock(&mut self, agent_id: &str) -> Result<bool> {
+        self.refresh()?;
+        self.violations.remove(agent_id);
+        if self.entries.remove(agent_id).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    fn insert(
+        &mut self,
+        agent_id: &str,
+        reason: BlockReason,
+        by: BlockedBy,
+        count: u16,
+        fp: Option<[u8; 32]>,
+    ) -> Result<()> {
+        self.entries.insert(agent_id.to_string(), BlocklistEntry {
+            id: agent_id.to_string(),
+            fp,
+            r: reason,
+            at: protocol::current_timestamp(),
+            by,
+            c: count,
+        });
+        self.save()
+    }
+
+    fn save(&mut self) -> Result<()> {
+        let file = BlocklistFile {
+            ver: BLOCKLIST_VERSION,
+            updated: protocol::current_timestamp(),
+            entries: self.list().into_iter().cloned().collect(),
+        };
+        let data = rmp_serde::to_vec_named(&file)?;
+
+        // Write then rename so a daemon reloading the file never sees it
+        // half-written.
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, data)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.modified = file_modified(&self.path);
+        Ok(())
+    }
+}
+
+fn file_modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn read_entries(path: &Path) -> Result<HashMap<String, BlocklistEntry>> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let data = std::fs::read(path)?;
+    let file: BlocklistFile = rmp_serde::from_slice(&data)
+        .map_err(|e| anyhow!("Cannot read blocklist {}: {}", path.display(), e))?;
+    if file.ver > BLOCKLIST_VERSION {
+        return Err(anyhow!("Unsupported blocklist version {}", file.ver));
+    }
+    Ok(file.entries.into_iter().map(|e| (e.id.clone(), e)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempFile;
+
+    #[test]
+    fn test_blocks_after_threshold() {
+        let path = TempFile::new("blocklist", "threshold");
+        let mut blocklist = Blocklist::load(path.to_path_buf()).unwrap();
+
+        for _ in 0..2 {
+            assert!(!blocklist.add_violation("spam-bot-123", BlockReason::SizeViolations, None).unwrap());
+        }
+        assert!(!blocklist.is_blocked("spam-bot-123"));
+        assert!(!path.exists());
+
+        assert!(blocklist.add_violation("spam-bot-123", BlockReason::SizeViolations, Some([1u8; 32])).unwrap());
+        assert!(blocklist.is_blocked("spam-bot-123"));
+
+        let reloaded = Blocklist::load(path.to_path_buf()).unwrap();
+        let entry = reloaded.list()[0];
+        assert_eq!(entry.id, "spam-bot-123");
+        assert_eq!(entry.r, BlockReason::SizeViolations);
+        assert_eq!(entry.by, BlockedBy::Automatic);
+        assert_eq!(entry.c, 3);
+        assert_eq!(entry.fp, Some([1u8; 32]));
+    }
+
+    #[test]
+    fn test_refresh_picks_up_other_writers() {
+        let path = TempFile::new("blocklist", "refresh");
+        let mut daemon = Blocklist::load(path.to_path_buf()).unwrap();
+
+        let mut cli = Blocklist::load(path.to_path_buf()).unwrap();
+        cli.block("quest-8d3a1f", BlockReason::ManualBlock, None).unwrap();
+
+        daemon.refresh().unwrap();
+        assert!(daemon.is_blocked("quest-8d3a1f"));
+
+        // Make sure the second write gets a different mtime.
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(cli.unblock("quest-8d3a1f").unwrap());
+
+        daemon.refresh().unwrap();
+        assert!(!daemon.is_blocked("quest-8d3a1f"));
+    }
+
+    #[test]
+    fn test_reason_names() {
+        assert_eq!("spam".parse::<BlockReason>().unwrap(), BlockReason::Spam);
+        assert_eq!("4".parse::<BlockReason>().unwrap(), BlockReason::RateLimitViolations);
+        assert!("bogus".parse::<BlockReason>().is_err());
+    }
+}
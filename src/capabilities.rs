@@ -0,0 +1,242 @@
+use crate::payload::{Category, CapabilityDigest, KnockPayload, WishPayload};
+use crate::schema::{self, Invalid};
+use anyhow::{anyhow, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Where `wishp capabilities` keeps the lists it fetched, one file per peer.
+pub const CACHE_DIR: &str = "~/.wish-protocol/capabilities";
+
+/// KNOCK `prev` and WISH `task.act` of a question-category conversation
+/// asking for the capability list.
+pub const PROBE: &str = "capabilities";
+
+/// KNOCK category of a capability probe: question.
+pub const PROBE_CATEGORY: Category = Category::Question;
+
+/// An action the agent offers, from `[[actions]]` or a handler.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct Capability {
+    /// The WISH `task.act` it answers.
+    pub act: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub desc: Option<String>,
+    /// JSON Schema the WISH `task.par` must match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub par: Option<serde_json::Value>,
+    /// JSON Schemas for `task.con` and `task.data`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub con: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+    /// Largest WISH payload accepted, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    /// Indicative cost, in the units of GRANT `est_c`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<u32>,
+    /// Indicative time in seconds, as GRANT `est_t`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+}
+
+/// The actions an agent offers and their digest `h`, which the WELCOME
+/// carries so requesters can tell when a fetched list is out of date.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Capabilities {
+    pub h: String,
+    pub actions: Vec<Capability>,
+}
+
+impl Capability {
+    /// Checks that the action's schemas use only keywords we enforce.
+    pub fn check_schemas(&self) -> Result<()> {
+        let fields = [("par", &self.par), ("con", &self.con), ("data", &self.data)];
+        for (name, field_schema) in fields {
+            if let Some(field_schema) = field_schema {
+                schema::check_supported(field_schema, name).map_err(|e| anyhow!("Action {}: {}", self.act, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Capabilities {
+    pub fn new(actions: Vec<Capability>) -> Self {
+        let encoded = serde_json::to_vec(&actions).unwrap_or_default();
+        let h = hex::encode(&Sha256::digest(&encoded)[..8]);
+        Self { h, actions }
+    }
+
+    /// The WELCOME `cap` field: the digest and the number of actions.
+    pub fn digest(&self) -> CapabilityDigest {
+        CapabilityDigest { h: self.h.clone(), n: self.actions.len() }
+    }
+
+    pub fn find(&self, act: &str) -> Option<&Capability> {
+        self.actions.iter().find(|c| c.act == act)
+    }
+
+    /// Checks a WISH against the advertised actions: its
+    /// `task.act` must be listed, `task.par`, `task.con` and `task.data`
+    /// must match the action's schemas, and the payload must fit
+    /// `max_size`. A WISH without a task asks for no action we offer.
+    pub fn check(&self, wish: &WishPayload) -> Result<(), Mismatch> {
+        let Some(task) = &wish.task else {
+            return Err(Mismatch::Unsupported(format!("the WISH names no action (offered: {})", self.offered())));
+        };
+        let act = task
+            .get("act")
+            .and_then(|a| a.as_str())
+            .ok_or_else(|| Mismatch::Invalid(Invalid::new("task.act", "expected string")))?;
+        let Some(capability) = self.find(act) else {
+            return Err(Mismatch::Unsupported(format!("{:?} is not offered (offered: {})", act, self.offered())));
+        };
+        let fields = [("par", &capability.par), ("con", &capability.con), ("data", &capability.data)];
+        for (name, field_schema) in fields {
+            if let Some(field_schema) = field_schema {
+                let value = task.get(name).unwrap_or(&serde_json::Value::Null);
+                schema::validate(field_schema, value, &format!("task.{}", name)).map_err(Mismatch::Invalid)?;
+            }
+        }
+        if let Some(max) = capability.max_size {
+            let size = rmp_serde::to_vec_named(wish).map(|b| b.len() as u64).unwrap_or(u64::MAX);
+            if size > max {
+                return Err(Mismatch::Unsupported(format!("WISH is {} bytes; {} takes at most {}", size, act, max)));
+            }
+        }
+        Ok(())
+    }
+
+    fn offered(&self) -> String {
+        self.actions.iter().map(|c| c.act.as_str()).collect::<Vec<_>>().join(", ")
+    }
+
+    /// The list fetched from `agent_id` earlier, if any.
+    pub fn load_cached(agent_id: &str) -> Result<Option<Self>> {
+        let path = cache_path(agent_id);
+        match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map(Some)
+                .map_err(|e| anyhow!("Invalid capability cache {}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save_cached(&self, agent_id: &str) -> Result<()> {
+        let path = cache_path(agent_id);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&temp, &path)?;
+        Ok(())
+    }
+}
+
+/// Why a WISH does not fit the advertised actions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The action is not offered, or the WISH is over its size limit.
+    Unsupported(String),
+    /// A task field does not match the action's schema.
+    Invalid(Invalid),
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Unsupported(msg) => write!(f, "task.act: {}", msg),
+            Mismatch::Invalid(invalid) => invalid.fmt(f),
+        }
+    }
+}
+
+/// Whether a KNOCK opens a capability probe.
+pub fn is_probe(knock: &KnockPayload) -> bool {
+    knock.c == PROBE_CATEGORY && knock.prev.as_deref() == Some(PROBE)
+}
+
+/// The WISH payload of a capability probe, with the KNOCK fields
+/// `send_message` takes from it.
+pub fn probe_payload() -> HashMap<String, serde_json::Value> {
+    HashMap::from([
+        ("c".to_string(), serde_json::json!(PROBE_CATEGORY)),
+        ("prev".to_string(), serde_json::json!(PROBE)),
+        ("task".to_string(), serde_json::json!({"act": PROBE})),
+    ])
+}
+
+fn cache_path(agent_id: &str) -> PathBuf {
+    PathBuf::from(shellexpand::tilde(CACHE_DIR).into_owned()).join(format!("{}.json", agent_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn capabilities() -> Capabilities {
+        Capabilities::new(vec![
+            Capability {
+                act: "summarize".to_string(),
+                desc: Some("Summarize a text".to_string()),
+                par: Some(json!({"type": "object", "required": ["lang"], "properties": {"lang": {"type": "string"}}})),
+                con: None,
+                data: Some(json!({"type": "string", "maxLength": 100})),
+                max_size: Some(200),
+                cost: Some(5),
+                time: Some(30),
+            },
+            Capability {
+                act: "ping".to_string(),
+                desc: None,
+                par: None,
+                con: None,
+                data: None,
+                max_size: None,
+                cost: None,
+                time: None,
+            },
+        ])
+    }
+
+    fn wish(task: serde_json::Value) -> WishPayload {
+        WishPayload { task: Some(task), ..WishPayload::default() }
+    }
+
+    #[test]
+    fn test_check() {
+        let capabilities = capabilities();
+        let check = |task| capabilities.check(&wish(task)).map_err(|e| e.to_string());
+        assert_eq!(check(json!({"act": "summarize", "par": {"lang": "en"}, "data": "text"})), Ok(()));
+        assert_eq!(check(json!({"act": "ping"})), Ok(()));
+        let taskless = capabilities.check(&WishPayload::default());
+        assert_eq!(taskless, Err(Mismatch::Unsupported("the WISH names no action (offered: summarize, ping)".to_string())));
+
+        assert_eq!(check(json!({"act": "summarize", "par": {}, "data": ""})), Err("task.par.lang: required".to_string()));
+        assert_eq!(check(json!({"act": "summarize", "par": {"lang": "en"}})), Err("task.data: expected string, got null".to_string()));
+        assert_eq!(check(json!({"par": {}})), Err("task.act: expected string".to_string()));
+        let unknown = capabilities.check(&wish(json!({"act": "translate"})));
+        assert_eq!(unknown, Err(Mismatch::Unsupported(r#""translate" is not offered (offered: summarize, ping)"#.to_string())));
+        let large = check(json!({"act": "summarize", "par": {"lang": "en", "notes": "x".repeat(300)}, "data": ""}));
+        assert!(large.unwrap_err().contains("takes at most 200"));
+    }
+
+    #[test]
+    fn test_digest_follows_actions() {
+        let capabilities = capabilities();
+        assert_eq!(capabilities.h, Capabilities::new(capabilities.actions.clone()).h);
+        assert_eq!(capabilities.digest().n, 2);
+
+        let mut actions = capabilities.actions.clone();
+        actions[1].time = Some(1);
+        assert_ne!(capabilities.h, Capabilities::new(actions).h);
+    }
+}
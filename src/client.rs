@@ -1,51 +1,256 @@
-use crate::crypto;
-use crate::protocol::{self, Message, Stage, PROTOCOL_VERSION};
+use crate::capabilities::{self, Capabilities};
+use crate::crypto::{self, Role};
+use crate::daemon::{Config, TimeoutConfig};
+use crate::keyring::Keyring;
+use crate::payload::{
+    Category, GiftPayload, GrantPayload, KnockPayload, Priority, StagePayload, Status, ThankContext, ThankPayload, WelcomePayload,
+    WishPayload, WrapPayload,
+};
+use crate::protocol::{
+    self, CounterOption, CounterProposal, Endpoint, ErrorCode, Message, Stage, WishUrl,
+};
+use crate::session::{self, Session};
 use anyhow::{anyhow, Result};
+use serde::Serialize;
 use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio_rustls::rustls::{self, pki_types::ServerName, ClientConfig};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::WebPkiSupportedAlgorithms;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{self, ClientConfig, DigitallySignedStruct, SignatureScheme};
 use tokio_rustls::TlsConnector;
 
+/// A peer to deliver to: the endpoints to try in order and an optional
+/// pinned TLS certificate fingerprint.
+pub struct Destination {
+    pub agent_id: String,
+    pub endpoints: Vec<Endpoint>,
+    pub tls_fingerprint: Option<String>,
+}
+
+impl Destination {
+    /// Resolves a `wishp send` target. A wish URL is used as given; a bare
+    /// agent ID is looked up in the keyring address book, falling back to
+    /// the local daemon when the peer has no endpoints recorded.
+    pub fn resolve(target: &str, config: &Config) -> Result<Self> {
+        let keyring_path = shellexpand::tilde(&config.keys.keyring_path).into_owned();
+        let keyring = Keyring::load(PathBuf::from(keyring_path))?;
+
+        let (agent_id, mut endpoints) = if target.contains('@') {
+            let url = WishUrl::parse(target)?;
+            (url.agent_id, vec![url.endpoint])
+        } else {
+            protocol::validate_agent_id(target)?;
+            let endpoints = keyring
+                .get_entry(target)
+                .map(|e| e.endpoints.clone())
+                .unwrap_or_default();
+            (target.to_string(), endpoints)
+        };
+
+        if endpoints.is_empty() {
+            endpoints.push(Endpoint::Direct {
+                host: "localhost".to_string(),
+                port: config.network.listen_port,
+            });
+        }
+
+        let tls_fingerprint = keyring
+            .get_entry(&agent_id)
+            .and_then(|e| e.tls_fingerprint.clone());
+
+        Ok(Self {
+            agent_id,
+            endpoints,
+            tls_fingerprint,
+        })
+    }
+}
+
+/// The last message received from the peer (GIFT, or the WELCOME/GRANT
+/// that declined) and, if the request was negotiated, what was agreed.
+pub struct SendOutcome {
+    pub response: Message,
+    pub agreed: Option<AgreedTask>,
+}
+
+impl SendOutcome {
+    /// What `wishp send` prints: the response payload, plus `agreed` after
+    /// a negotiation.
+    pub fn output(&self) -> serde_json::Value {
+        let mut output = serde_json::json!(self.response.payload);
+        if let Some(agreed) = &self.agreed {
+            output["agreed"] = serde_json::json!(agreed);
+        }
+        output
+    }
+}
+
+#[derive(Serialize)]
+pub struct AgreedTask {
+    pub rev: u8,
+    pub sel_opt: u8,
+    pub task: serde_json::Value,
+}
+
+/// Sends a wish and waits for its GIFT. If `cancel` completes while the
+/// task runs, the responder is asked to stop and its cancelled GIFT is
+/// returned.
 pub async fn send_message(
-    agent_id: &str,
+    destination: &Destination,
     input_payload: HashMap<String, serde_json::Value>,
-    config: &crate::daemon::Config,
-) -> Result<Message> {
-    let addr = format!("127.0.0.1:{}", config.network.listen_port);
-
-    let connector = create_tls_connector()?;
-    let stream = TcpStream::connect(&addr).await?;
-    let domain = ServerName::try_from("localhost")
-        .map_err(|_| anyhow!("Invalid domain"))?
-        .to_owned();
-    let mut stream = connector.connect(domain, stream).await?;
+    config: &Config,
+    selector: &dyn OptionSelector,
+    cancel: impl Future<Output = ()>,
+) -> Result<SendOutcome> {
+    let knock = knock_for(&input_payload)?;
+    let wish = WishPayload::from_map(&input_payload)?;
+
+    let probe = capabilities::is_probe(&knock);
+    let (mut stream, mut session, welcome) = handshake(destination, knock, config).await?;
+    if !probe {
+        check_digest(&destination.agent_id, &welcome.parse()?);
+    }
+    match converse(&mut stream, &mut session, &config.timeouts, welcome, wish, selector, cancel).await {
+        Ok(outcome) => Ok(outcome),
+        Err(e) => Err(session.fail(&mut stream, e, ErrorCode::InternalError).await),
+    }
+}
 
-    let (my_eph_secret, my_eph_public) = crypto::generate_ephemeral_key();
-    let my_id = &config.agent.id;
-    let peer_id = agent_id;
+/// Checks a `wishp send` input before it is sent or queued: its KNOCK
+/// fields and the WISH must both be valid payloads.
+pub fn check_input(input: &HashMap<String, serde_json::Value>) -> Result<()> {
+    knock_for(input)?;
+    WishPayload::from_map(input)?;
+    Ok(())
+}
 
-    let mut counter = 1u32;
-    let timestamp = protocol::current_timestamp();
+/// The KNOCK for a `wishp send` input: its `c`, `pri` and `prev`, by
+/// default a normal-priority task request.
+fn knock_for(input: &HashMap<String, serde_json::Value>) -> Result<KnockPayload> {
+    let mut fields = HashMap::from([
+        ("c".to_string(), serde_json::json!(1)),
+        ("pri".to_string(), serde_json::json!(2)),
+        ("eph_key".to_string(), serde_json::json!([])),
+        ("auth".to_string(), serde_json::json!([])),
+    ]);
+    for key in ["c", "pri", "prev"] {
+        if let Some(value) = input.get(key) {
+            fields.insert(key.to_string(), value.clone());
+        }
+    }
+    KnockPayload::from_map(&fields)
+}
 
-    let mut knock_payload = HashMap::new();
-    if let Some(c) = input_payload.get("c") {
-        knock_payload.insert("c".to_string(), c.clone());
-    } else {
-        knock_payload.insert("c".to_string(), serde_json::json!(1));
+/// Asks the peer for the actions it offers in a question-category
+/// conversation, and caches the list for checking later wishes.
+pub async fn fetch_capabilities(destination: &Destination, config: &Config) -> Result<Capabilities> {
+    let payload = capabilities::probe_payload();
+    let outcome = send_message(destination, payload, config, &FirstOption, std::future::pending()).await?;
+    let response = &outcome.response;
+    if response.stage != Stage::Gift.to_u8() {
+        let msg = response.payload.get("msg").and_then(|m| m.as_str()).unwrap_or("no reason given");
+        return Err(anyhow!("{} declined the capability probe: {}", destination.agent_id, msg));
     }
-    if let Some(pri) = input_payload.get("pri") {
-        knock_payload.insert("pri".to_string(), pri.clone());
-    } else {
-        knock_payload.insert("pri".to_string(), serde_json::json!(2));
+    let gift: GiftPayload = response.parse()?;
+    let fetched: Capabilities = serde_json::from_value(gift.res)
+        .map_err(|e| anyhow!("Invalid capability list from {}: {}", destination.agent_id, e))?;
+    fetched.save_cached(&destination.agent_id)?;
+    Ok(fetched)
+}
+
+/// Warns when the WELCOME advertises a different capability digest than
+/// the list cached for the peer.
+fn check_digest(agent_id: &str, welcome: &WelcomePayload) {
+    let Some(digest) = &welcome.cap else {
+        return;
+    };
+    if let Ok(Some(cached)) = Capabilities::load_cached(agent_id) {
+        if cached.h != digest.h {
+            eprintln!("Note: {} has changed its capabilities; run `wishp capabilities {}` to refresh", agent_id, agent_id);
+        }
     }
-    if let Some(prev) = input_payload.get("prev") {
-        knock_payload.insert("prev".to_string(), prev.clone());
+}
+
+/// Delivers the GIFT of a deferred task in a new conversation: a KNOCK
+/// carrying the GIFT's ticket `tkt`, then the GIFT itself. Returns the
+/// WELCOME if the requester declined.
+pub async fn deliver_gift(
+    destination: &Destination,
+    gift: &GiftPayload,
+    config: &Config,
+) -> Result<Option<Message>> {
+    let ticket = gift.tkt.clone().ok_or_else(|| anyhow!("Deferred GIFT has no ticket"))?;
+    let knock = KnockPayload {
+        tkt: Some(ticket),
+        ..KnockPayload::new(Category::TaskRequest, Priority::Normal)
+    };
+
+    let (mut stream, mut session, welcome) = handshake(destination, knock, config).await?;
+    if welcome.parse::<WelcomePayload>()?.st != Status::Accept {
+        let thank = ThankPayload::new(ThankContext::Decline, true, None);
+        let _ = session.send(&mut stream, &thank).await;
+        return Ok(Some(welcome));
     }
 
-    let my_eph_bytes: Vec<u8> = my_eph_public.as_bytes().to_vec();
-    knock_payload.insert("eph_key".to_string(), serde_json::json!(my_eph_bytes));
+    let delivered = async {
+        session.send(&mut stream, gift).await?;
+        let (thank, _) = session.receive_within(&mut stream, config.timeouts.grant(), Stage::Thank).await?;
+        if thank.stage != Stage::Thank.to_u8() {
+            return Err(session::unexpected_stage("THANK", thank.stage));
+        }
+        Ok(())
+    }
+    .await;
+    match delivered {
+        Ok(()) => Ok(None),
+        Err(e) => Err(session.fail(&mut stream, e, ErrorCode::InternalError).await),
+    }
+}
+
+/// Connects, sends `knock` with the key exchange filled in, and checks the
+/// WELCOME, which is returned with the session it opens.
+async fn handshake(
+    destination: &Destination,
+    mut knock: KnockPayload,
+    config: &Config,
+) -> Result<(PeerStream, Session, Message)> {
+    let timeouts = &config.timeouts;
+    let mut stream = connect_any(destination, timeouts.tls_handshake()).await?;
+
+    let my_id = &config.agent.id;
+    let peer_id = destination.agent_id.as_str();
+
+    let identity = crypto::load_identity(&config.keys.private_key_path, &config.keys.public_key_path)?;
+    let keyring_path = shellexpand::tilde(&config.keys.keyring_path).into_owned();
+    let peer_static = *Keyring::load(PathBuf::from(keyring_path))?
+        .get(peer_id)
+        .ok_or_else(|| anyhow!("Public key for {} not found in keyring", peer_id))?;
+
+    let (my_eph_secret, my_eph_public) = crypto::generate_ephemeral_key();
+
+    let mut counter = 1u32;
+    let timestamp = protocol::current_timestamp();
+
+    knock.eph_key = my_eph_public.as_bytes().to_vec();
+
+    let knock_auth = crypto::knock_auth(
+        &identity,
+        &peer_static,
+        my_eph_public.as_bytes(),
+        timestamp,
+        my_id,
+        peer_id,
+    )?;
+    knock.auth = knock_auth.to_vec();
 
     let knock = Message {
         stage: Stage::Knock.to_u8(),
@@ -53,13 +258,18 @@ pub async fn send_message(
         timestamp,
         from: my_id.clone(),
         to: peer_id.to_string(),
-        payload: knock_payload,
+        payload: knock.to_map(),
     };
 
     let encoded_knock = protocol::encode_message(&knock)?;
     protocol::send_framed_message(&mut stream, &encoded_knock).await?;
 
-    let welcome_bytes = protocol::receive_framed_message(&mut stream).await?;
+    let welcome_bytes = session::within(
+        timeouts.welcome(),
+        Stage::Welcome,
+        protocol::receive_framed_message(&mut stream),
+    )
+    .await?;
     let welcome = protocol::decode_message(&welcome_bytes)?;
 
     if welcome.stage != Stage::Welcome.to_u8() {
@@ -71,158 +281,379 @@ pub async fn send_message(
     }
     counter = welcome.counter;
 
-    let peer_eph_val = welcome
-        .payload
-        .get("eph_key")
-        .ok_or_else(|| anyhow!("Missing eph_key in WELCOME"))?;
+    let welcome_payload: WelcomePayload = welcome.parse()?;
+    let peer_eph_array = protocol::key_field(&welcome_payload.eph_key, "eph_key")?;
 
-    let peer_eph_bytes: Vec<u8> = serde_json::from_value(peer_eph_val.clone())
-        .map_err(|_| anyhow!("Invalid eph_key format"))?;
+    let mut session_key = crypto::derive_session_key(
+        Role::Requester,
+        &identity,
+        &my_eph_secret,
+        &peer_static,
+        &peer_eph_array,
+        my_id,
+        peer_id,
+    )?;
 
-    if peer_eph_bytes.len() != 32 {
-        return Err(anyhow!("Invalid eph_key length: {}", peer_eph_bytes.len()));
+    let expected_auth = crypto::welcome_auth(&session_key, &peer_eph_array)?;
+    if let Err(e) = crypto::verify_auth(&expected_auth, &welcome_payload.auth) {
+        crypto::zeroize_key(&mut session_key);
+        return Err(e);
     }
 
-    let mut peer_eph_array = [0u8; 32];
-    peer_eph_array.copy_from_slice(&peer_eph_bytes);
-
-    let mut session_key =
-        crypto::derive_session_key(&my_eph_secret, &peer_eph_array, my_id, peer_id)?;
-
     drop(my_eph_secret);
 
-    let status = welcome
-        .payload
-        .get("st")
-        .and_then(|v| v.as_u64())
-        .unwrap_or(1) as u8;
-
-    if status == 2 {
-        counter += 1;
-        let thank_payload = build_thank_payload(2, true, None);
-        send_encrypted_message(
-            &mut stream,
-            Stage::Thank,
-            &session_key,
-            counter,
-            my_id,
-            peer_id,
-            thank_payload,
-        )
-        .await?;
+    let session = Session::new(session_key, counter, my_id, peer_id);
+    crypto::zeroize_key(&mut session_key);
 
-        crypto::zeroize_key(&mut session_key);
-        return Ok(welcome);
+    Ok((stream, session, welcome))
+}
+
+/// Runs the encrypted part of the conversation, from the WELCOME status to
+/// our THANK.
+async fn converse(
+    stream: &mut PeerStream,
+    session: &mut Session,
+    timeouts: &TimeoutConfig,
+    welcome: Message,
+    mut wish: WishPayload,
+    selector: &dyn OptionSelector,
+    cancel: impl Future<Output = ()>,
+) -> Result<SendOutcome> {
+    // Declined, or busy with a `retry` hint.
+    if matches!(welcome.parse::<WelcomePayload>()?.st, Status::Decline | Status::Busy) {
+        session.send(stream, &ThankPayload::new(ThankContext::Decline, true, None)).await?;
+        return Ok(SendOutcome { response: welcome, agreed: None });
     }
 
-    counter += 1;
-    let wish_payload = input_payload;
+    let mut rev = 0u8;
+    let mut agreed = None;
 
-    send_encrypted_message(
-        &mut stream,
-        Stage::Wish,
-        &session_key,
-        counter,
-        my_id,
-        peer_id,
-        wish_payload,
-    )
-    .await?;
+    let (grant, grant_payload) = loop {
+        session.send(stream, &wish).await?;
 
-    let grant = receive_encrypted_message(&mut stream, &session_key, &mut counter, peer_id, my_id).await?;
+        let (grant, _) = session.receive_within(stream, timeouts.grant(), Stage::Grant).await?;
+        let grant_payload: GrantPayload = grant.parse()?;
 
-    if grant.stage != Stage::Grant.to_u8() {
-        return Err(anyhow!("Expected GRANT, got stage {}", grant.stage));
+        if grant_payload.st != Status::Negotiate {
+            break (grant, grant_payload);
+        }
+
+        let selection = match grant_payload.counter.clone() {
+            Some(proposal) if rev < protocol::MAX_NEGOTIATION_ROUNDS => selector
+                .select(rev + 1, &wish, &grant_payload, &proposal)?
+                .map(|selection| (selection, proposal)),
+            Some(_) => {
+                eprintln!("Negotiation round limit reached, declining.");
+                None
+            }
+            None => {
+                eprintln!("GRANT requested negotiation without options, declining.");
+                None
+            }
+        };
+
+        let Some((selection, proposal)) = selection else {
+            session.send(stream, &ThankPayload::new(ThankContext::Decline, true, None)).await?;
+            return Ok(SendOutcome { response: grant, agreed: None });
+        };
+
+        let option = proposal
+            .option(selection.sel_opt)
+            .ok_or_else(|| anyhow!("Selected unknown option {}", selection.sel_opt))?;
+
+        rev += 1;
+        revise_wish(&mut wish, rev, option, selection.task);
+        agreed = Some(AgreedTask {
+            rev,
+            sel_opt: option.id,
+            task: wish.task.clone().unwrap_or_default(),
+        });
+    };
+
+    if grant_payload.st == Status::Decline {
+        session.send(stream, &ThankPayload::new(ThankContext::Decline, true, None)).await?;
+        return Ok(SendOutcome { response: grant, agreed: None });
     }
 
-    let grant_status = grant
-        .payload
-        .get("st")
-        .and_then(|v| v.as_u64())
-        .unwrap_or(1) as u8;
+    // A ticket means the GIFT comes later in a conversation of its own.
+    if grant_payload.tkt.is_some() {
+        session.send(stream, &ThankPayload::new(ThankContext::Success, false, None)).await?;
+        return Ok(SendOutcome { response: grant, agreed });
+    }
 
-    if grant_status == 2 {
-        counter += 1;
-        let thank_payload = build_thank_payload(2, true, None);
-        send_encrypted_message(
-            &mut stream,
-            Stage::Thank,
-            &session_key,
-            counter,
-            my_id,
-            peer_id,
-            thank_payload,
-        )
-        .await?;
+    let est_time = grant_payload.est_t.unwrap_or(0);
+    let mut deadline = timeouts.gift(est_time);
+    let mut cancelled = false;
+    tokio::pin!(cancel);
+
+    let gift = loop {
+        let next = tokio::select! {
+            first = session::within(deadline, Stage::Gift, session::next_byte(stream)) => first?,
+            _ = &mut cancel, if !cancelled => {
+                // Our THANK is the last message we send; the responder
+                // answers with whatever GIFT it has.
+                eprintln!("Cancelling the task");
+                session.interrupt(stream, &ThankPayload::new(ThankContext::Cancel, true, None)).await?;
+                cancelled = true;
+                deadline = timeouts.grant();
+                continue;
+            }
+        };
+        let (msg, _) = session::within(deadline, Stage::Gift, session.receive_rest(next, stream)).await?;
+        match Stage::from_u8(msg.stage)? {
+            Stage::Wrap if cancelled => {}
+            Stage::Wrap => {
+                let wrap: WrapPayload = msg.parse()?;
+                let progress = wrap.prog.unwrap_or(0);
+                let detail: Vec<&str> = [&wrap.stat, &wrap.msg].into_iter().flatten().map(String::as_str).collect();
+                if detail.is_empty() {
+                    eprintln!("Progress: {}%", progress);
+                } else {
+                    eprintln!("Progress: {}% ({})", progress, detail.join(": "));
+                }
+                let eta = wrap.eta.map(u64::from).unwrap_or(est_time);
+                deadline = timeouts.gift(eta);
+            }
+            Stage::Gift => break msg,
+            _ => return Err(session::unexpected_stage("GIFT", msg.stage)),
+        }
+    };
 
-        crypto::zeroize_key(&mut session_key);
-        return Ok(grant);
+    if !cancelled {
+        session.send(stream, &ThankPayload::new(ThankContext::Success, false, Some("Thank you!"))).await?;
     }
 
-    if grant_status == 4 {
-        if let Some(counter_proposal) = grant.payload.get("counter") {
-            eprintln!("Negotiation requested:");
-            eprintln!("{}", serde_json::to_string_pretty(counter_proposal)?);
-            eprintln!("Note: Negotiation requires manual intervention.");
-            eprintln!("Rejecting request.");
+    Ok(SendOutcome { response: gift, agreed })
+}
+
+/// An option picked from a counter-proposal. `task` replaces the WISH task
+/// outright; when absent the option's `mod` map is merged into it.
+pub struct Selection {
+    pub sel_opt: u8,
+    pub task: Option<serde_json::Value>,
+}
+
+/// Chooses how to answer a GRANT with status 4 (negotiate). Returning
+/// `None` declines and closes the conversation with THANK.
+pub trait OptionSelector: Send + Sync {
+    fn select(
+        &self,
+        round: u8,
+        wish: &WishPayload,
+        grant: &GrantPayload,
+        proposal: &CounterProposal,
+    ) -> Result<Option<Selection>>;
+}
+
+/// Always takes the first option offered.
+pub struct FirstOption;
+
+impl OptionSelector for FirstOption {
+    fn select(
+        &self,
+        _round: u8,
+        _wish: &WishPayload,
+        _grant: &GrantPayload,
+        proposal: &CounterProposal,
+    ) -> Result<Option<Selection>> {
+        Ok(proposal.opts.first().map(|o| Selection { sel_opt: o.id, task: None }))
+    }
+}
+
+/// Runs an external program with `{"round", "wish", "grant"}` as JSON on
+/// stdin. It answers `{"sel_opt": <id>, "task": <optional task>}`, or
+/// `{"sel_opt": null}` to decline.
+pub struct ScriptSelector {
+    pub path: String,
+}
+
+impl OptionSelector for ScriptSelector {
+    fn select(
+        &self,
+        round: u8,
+        wish: &WishPayload,
+        grant: &GrantPayload,
+        _proposal: &CounterProposal,
+    ) -> Result<Option<Selection>> {
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+
+        let input = serde_json::json!({
+            "round": round,
+            "wish": wish,
+            "grant": grant,
+        });
+
+        let mut child = Command::new(&self.path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        {
+            let stdin = child.stdin.as_mut().ok_or_else(|| anyhow!("Failed to open stdin"))?;
+            stdin.write_all(input.to_string().as_bytes())?;
+        }
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(anyhow!("Selection script exited with {}", output.status));
         }
 
-        counter += 1;
-        let thank_payload = build_thank_payload(2, true, None);
-        send_encrypted_message(
-            &mut stream,
-            Stage::Thank,
-            &session_key,
-            counter,
-            my_id,
-            peer_id,
-            thank_payload,
-        )
-        .await?;
+        let answer: serde_json::Value = serde_json::from_slice(&output.stdout)
+            .map_err(|e| anyhow!("Failed to parse selection script output: {}", e))?;
 
-        crypto::zeroize_key(&mut session_key);
-        return Ok(grant);
+        let Some(sel_opt) = answer.get("sel_opt").and_then(|v| v.as_u64()) else {
+            return Ok(None);
+        };
+
+        Ok(Some(Selection {
+            sel_opt: u8::try_from(sel_opt).map_err(|_| anyhow!("Invalid sel_opt: {}", sel_opt))?,
+            task: answer.get("task").cloned(),
+        }))
     }
+}
 
-    let mut gift: Option<Message> = None;
-    loop {
-        let msg = receive_encrypted_message(&mut stream, &session_key, &mut counter, peer_id, my_id).await?;
-        match Stage::from_u8(msg.stage)? {
-            Stage::Wrap => {
-                let progress = msg.payload.get("prog").and_then(|v| v.as_u64()).unwrap_or(0);
-                eprintln!("Progress: {}%", progress);
+/// Shows the options on stderr and reads the choice from the terminal,
+/// since stdin already carried the WISH payload.
+pub struct PromptSelector;
+
+impl OptionSelector for PromptSelector {
+    fn select(
+        &self,
+        round: u8,
+        _wish: &WishPayload,
+        grant: &GrantPayload,
+        proposal: &CounterProposal,
+    ) -> Result<Option<Selection>> {
+        use std::io::BufRead;
+
+        eprintln!("Negotiation round {} of {}:", round, protocol::MAX_NEGOTIATION_ROUNDS);
+        if let Some(reason) = &grant.r {
+            eprintln!("  Reason: {}", reason);
+        }
+        for option in &proposal.opts {
+            eprintln!("  [{}] {} {}", option.id, option.d, serde_json::Value::Object(option.modifications.clone()));
+        }
+
+        let tty = std::fs::File::open("/dev/tty")
+            .map_err(|e| anyhow!("Cannot prompt for an option: {}", e))?;
+        let mut reader = std::io::BufReader::new(tty);
+
+        loop {
+            eprint!("Select option (empty to decline): ");
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(None);
             }
-            Stage::Gift => {
-                gift = Some(msg);
-                break;
+            let line = line.trim();
+            if line.is_empty() {
+                return Ok(None);
             }
-            _ => {
-                return Err(anyhow!("Unexpected stage {} while waiting for GIFT", msg.stage));
+            match line.parse::<u8>() {
+                Ok(id) if proposal.option(id).is_some() => {
+                    return Ok(Some(Selection { sel_opt: id, task: None }));
+                }
+                _ => eprintln!("Unknown option: {}", line),
             }
         }
     }
+}
 
-    let gift = gift.ok_or_else(|| anyhow!("No GIFT received"))?;
+/// Turns the WISH into revision `rev` for the chosen option (spec §8.5).
+/// Without an explicit task the option's modifications are merged into
+/// `task.data`, or into `task.par` when the data is not a map.
+fn revise_wish(wish: &mut WishPayload, rev: u8, option: &CounterOption, task: Option<serde_json::Value>) {
+    wish.rev = rev;
+    wish.sel_opt = Some(option.id);
 
-    counter += 1;
-    let thank_payload = build_thank_payload(1, false, Some("Thank you!"));
-    send_encrypted_message(
-        &mut stream,
-        Stage::Thank,
-        &session_key,
-        counter,
-        my_id,
-        peer_id,
-        thank_payload,
-    )
-    .await?;
+    if let Some(task) = task {
+        wish.task = Some(task);
+        return;
+    }
 
-    crypto::zeroize_key(&mut session_key);
+    let task = wish.task.get_or_insert_with(|| serde_json::json!({}));
+    let Some(task) = task.as_object_mut() else {
+        return;
+    };
+
+    let field = match task.get("data") {
+        None => "data",
+        Some(data) if data.is_object() => "data",
+        Some(_) => "par",
+    };
+    let target = task
+        .entry(field.to_string())
+        .or_insert_with(|| serde_json::json!({}));
+    if let Some(target) = target.as_object_mut() {
+        for (key, value) in &option.modifications {
+            target.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
 
-    Ok(gift)
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+type PeerStream = TlsStream<Box<dyn Transport>>;
+
+/// Tries each endpoint in order and returns the first TLS connection that
+/// comes up. Nothing has been sent yet, so failing over is always safe.
+async fn connect_any(destination: &Destination, limit: Duration) -> Result<PeerStream> {
+    let mut last_error = None;
+
+    for endpoint in &destination.endpoints {
+        let attempt = connect(endpoint, destination.tls_fingerprint.as_deref());
+        let result = tokio::time::timeout(limit, attempt)
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("timed out after {} seconds", limit.as_secs())));
+        match result {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                eprintln!("Could not reach {} at {}: {}", destination.agent_id, endpoint, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| anyhow!("No endpoints known for {}", destination.agent_id)))
 }
 
-fn create_tls_connector() -> Result<TlsConnector> {
+async fn connect(endpoint: &Endpoint, tls_fingerprint: Option<&str>) -> Result<PeerStream> {
+    let (transport, server_name): (Box<dyn Transport>, &str) = match endpoint {
+        Endpoint::Direct { host, port } => {
+            (Box::new(TcpStream::connect((host.as_str(), *port)).await?), host)
+        }
+        Endpoint::Unix { path } => {
+            let path = shellexpand::tilde(path).into_owned();
+            (Box::new(UnixStream::connect(path).await?), "localhost")
+        }
+        Endpoint::Rendezvous { server, .. } => {
+            return Err(anyhow!("Rendezvous connections via {} are not supported", server));
+        }
+    };
+
+    let connector = create_tls_connector(tls_fingerprint)?;
+    let domain = ServerName::try_from(server_name)
+        .map_err(|_| anyhow!("Invalid server name: {}", server_name))?
+        .to_owned();
+
+    Ok(connector.connect(domain, transport).await?)
+}
+
+fn create_tls_connector(tls_fingerprint: Option<&str>) -> Result<TlsConnector> {
+    if let Some(fingerprint) = tls_fingerprint {
+        let verifier = PinnedCertVerifier {
+            fingerprint: fingerprint.to_string(),
+            algorithms: rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        };
+        let client_config = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        return Ok(TlsConnector::from(Arc::new(client_config)));
+    }
+
     let mut root_store = rustls::RootCertStore::empty();
 
     let ca_path = shellexpand::tilde("~/.wish-protocol/ca.pem").into_owned();
@@ -243,108 +674,104 @@ fn create_tls_connector() -> Result<TlsConnector> {
     Ok(TlsConnector::from(Arc::new(client_config)))
 }
 
-fn build_thank_payload(
-    context: u8,
-    understanding: bool,
-    feedback: Option<&str>,
-) -> HashMap<String, serde_json::Value> {
-    let mut payload = HashMap::new();
-    payload.insert("ctx".to_string(), serde_json::json!(context));
-    if context != 1 {
-        payload.insert("und".to_string(), serde_json::json!(understanding));
+/// Accepts exactly the certificate whose SHA-256 fingerprint is pinned in
+/// the keyring, in place of CA validation. Handshake signatures are still
+/// checked so the peer must hold the certificate's private key.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: String,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if crypto::cert_fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "certificate does not match pinned fingerprint".to_string(),
+            ))
+        }
     }
-    if let Some(fb) = feedback {
-        payload.insert("fb".to_string(), serde_json::json!(fb));
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
     }
-    payload
 }
 
-async fn send_encrypted_message<W>(
-    writer: &mut W,
-    stage: Stage,
-    session_key: &[u8; 32],
-    counter: u32,
-    from: &str,
-    to: &str,
-    payload: HashMap<String, serde_json::Value>,
-) -> Result<()>
-where
-    W: AsyncWriteExt + Unpin,
-{
-    let timestamp = protocol::current_timestamp();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let message = Message {
-        stage: stage.to_u8(),
-        counter,
-        timestamp,
-        from: from.to_string(),
-        to: to.to_string(),
-        payload,
-    };
+    fn option(id: u8, modifications: serde_json::Value) -> CounterOption {
+        CounterOption {
+            id,
+            d: String::new(),
+            modifications: modifications.as_object().unwrap().clone(),
+        }
+    }
 
-    let plaintext = protocol::encode_message(&message)?;
-    let aad = protocol::build_aad(PROTOCOL_VERSION, from, to);
+    #[test]
+    fn test_revise_wish_merges_modifications_into_data() {
+        let mut wish: WishPayload = serde_json::from_value(serde_json::json!({
+            "rev": 0,
+            "task": {"act": "translate", "data": {"docs": 1000}}
+        })).unwrap();
 
-    let encrypted = crypto::encrypt_message(session_key, counter, timestamp, &plaintext, &aad)?;
+        revise_wish(&mut wish, 1, &option(2, serde_json::json!({"docs": 1000, "batch": 5})), None);
 
-    let mut envelope = Vec::new();
-    envelope.extend(counter.to_be_bytes());
-    envelope.extend(timestamp.to_be_bytes());
-    envelope.extend(encrypted);
+        let wish = wish.to_map();
+        assert_eq!(wish["rev"], 1);
+        assert_eq!(wish["sel_opt"], 2);
+        assert_eq!(wish["task"]["data"], serde_json::json!({"docs": 1000, "batch": 5}));
+    }
 
-    protocol::send_framed_message(writer, &envelope).await?;
-    Ok(())
-}
+    #[test]
+    fn test_revise_wish_uses_par_for_inline_data() {
+        let mut wish: WishPayload = serde_json::from_value(serde_json::json!({
+            "task": {"act": "summarize", "data": "some text"}
+        })).unwrap();
+
+        revise_wish(&mut wish, 1, &option(1, serde_json::json!({"max_len": 100})), None);
 
-async fn receive_encrypted_message<R>(
-    reader: &mut R,
-    session_key: &[u8; 32],
-    last_counter: &mut u32,
-    expected_from: &str,
-    expected_to: &str,
-) -> Result<Message>
-where
-    R: AsyncReadExt + Unpin,
-{
-    let envelope = protocol::receive_framed_message(reader).await?;
-
-    if envelope.len() < 8 {
-        return Err(anyhow!("Envelope too short: {} bytes", envelope.len()));
-    }
-
-    let remote_counter = u32::from_be_bytes(envelope[0..4].try_into()?);
-    let remote_timestamp = u32::from_be_bytes(envelope[4..8].try_into()?);
-    let ciphertext = &envelope[8..];
-
-    if remote_counter <= *last_counter {
-        return Err(anyhow!(
-            "Replay attack detected: counter {} <= {}",
-            remote_counter,
-            *last_counter
-        ));
-    }
-    *last_counter = remote_counter;
-
-    let aad = protocol::build_aad(PROTOCOL_VERSION, expected_from, expected_to);
-    let plaintext =
-        crypto::decrypt_message(session_key, remote_counter, remote_timestamp, ciphertext, &aad)?;
-
-    let message: Message = protocol::decode_message(&plaintext)?;
-
-    if message.from != expected_from {
-        return Err(anyhow!(
-            "Message from mismatch: expected {}, got {}",
-            expected_from,
-            message.from
-        ));
-    }
-    if message.to != expected_to {
-        return Err(anyhow!(
-            "Message to mismatch: expected {}, got {}",
-            expected_to,
-            message.to
-        ));
-    }
-
-    Ok(message)
+        let wish = wish.to_map();
+        assert_eq!(wish["task"]["data"], "some text");
+        assert_eq!(wish["task"]["par"], serde_json::json!({"max_len": 100}));
+    }
+
+    #[test]
+    fn test_revise_wish_explicit_task() {
+        let mut wish = WishPayload::default();
+        let task = serde_json::json!({"act": "translate", "data": {"docs": 10}});
+
+        revise_wish(&mut wish, 3, &option(1, serde_json::json!({"docs": 100})), Some(task.clone()));
+
+        assert_eq!(wish.rev, 3);
+        assert_eq!(wish.task, Some(task));
+    }
 }
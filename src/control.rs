@@ -0,0 +1,188 @@
+use crate::blocklist::BlockReason;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Longest request line the daemon reads from a control client.
+const MAX_REQUEST_LEN: u64 = 64 * 1024;
+
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct ControlConfig {
+    pub socket: String,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            socket: "~/.wish-protocol/control.sock".to_string(),
+        }
+    }
+}
+
+impl ControlConfig {
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(shellexpand::tilde(&self.socket).into_owned())
+    }
+}
+
+/// A command from the CLI to the running daemon, sent as one JSON line.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    /// List requests awaiting approval.
+    Pending,
+    Approve { id: String },
+    Deny { id: String, reason: Option<String> },
+    /// Re-read the config file, the policy and the keyring.
+    Reload,
+    ReloadKeyring,
+    Blocklist,
+    Block { id: String, reason: BlockReason },
+    Unblock { id: String },
+    /// Rate limiter usage per peer.
+    RateLimits,
+    Conversations,
+    Cancel { id: String },
+    /// Queue a wish in the outbox.
+    Send {
+        target: String,
+        payload: HashMap<String, serde_json::Value>,
+        select_script: Option<String>,
+    },
+    Outbox,
+    Job { id: String },
+    /// The outbox job granted with ticket `id`.
+    Ticket { id: String },
+    /// Withdraw outbox job `id`, stopping the task if it is being sent.
+    CancelJob { id: String },
+    /// Stop accepting connections and exit once active conversations end.
+    Shutdown,
+}
+
+/// The daemon's answer: `data` on success, `err` otherwise.
+#[derive(Serialize, Deserialize)]
+struct Response {
+    ok: bool,
+    #[serde(default)]
+    data: serde_json::Value,
+    err: Option<String>,
+}
+
+pub type Serve = Arc<dyn Fn(Request) -> Result<serde_json::Value> + Send + Sync>;
+
+/// Creates the control socket at `path`. A socket left by a daemon that
+/// has exited is replaced; one that still answers is an error.
+pub async fn bind(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(anyhow!("A daemon is already running on {}", path.display()));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Accepts control connections on `listener` until the daemon exits. Only
+/// processes of the daemon's own user may connect: the socket file is
+/// private and each client's credentials are checked.
+pub async fn listen(listener: UnixListener, serve: Serve) -> Result<()> {
+    let uid = nix::unistd::geteuid().as_raw();
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        match stream.peer_cred() {
+            Ok(cred) if cred.uid() == uid => {}
+            Ok(cred) => {
+                eprintln!("Refused control connection from uid {}", cred.uid());
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Refused control connection: {}", e);
+                continue;
+            }
+        }
+        let serve = serve.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, serve).await {
+                eprintln!("Control client error: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_client(stream: UnixStream, serve: Serve) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader.take(MAX_REQUEST_LEN)).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str(&line).map_err(anyhow::Error::from).and_then(|r| serve(r)) {
+            Ok(data) => Response { ok: true, data, err: None },
+            Err(e) => Response { ok: false, data: serde_json::Value::Null, err: Some(e.to_string()) },
+        };
+        let mut out = serde_json::to_vec(&response)?;
+        out.push(b'\n');
+        writer.write_all(&out).await?;
+    }
+    Ok(())
+}
+
+/// Like `send`, but returns `None` when no daemon is listening on `path`, so
+/// the caller can work on the files directly.
+pub async fn send_if_running(path: &Path, request: &Request) -> Option<Result<serde_json::Value>> {
+    let stream = UnixStream::connect(path).await.ok()?;
+    Some(exchange(stream, request).await)
+}
+
+/// Sends `request` to the daemon listening on `path` and returns its data.
+pub async fn send(path: &Path, request: &Request) -> Result<serde_json::Value> {
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| anyhow!("Cannot reach the daemon at {}: {}", path.display(), e))?;
+    exchange(stream, request).await
+}
+
+async fn exchange(stream: UnixStream, request: &Request) -> Result<serde_json::Value> {
+    let (reader, mut writer) = stream.into_split();
+
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.shutdown().await?;
+
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow!("Daemon closed the control connection"))?;
+    let response: Response = serde_json::from_str(&line)?;
+    match response.err {
+        Some(err) if !response.ok => Err(anyhow!(err)),
+        _ => Ok(response.data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempFile;
+
+    #[tokio::test]
+    async fn test_bind_refuses_live_socket() {
+        let path = TempFile::new("control", "bind");
+        let listener = bind(&path).await.unwrap();
+        assert!(bind(&path).await.is_err());
+
+        // The file outlives the listener, as after a crash.
+        drop(listener);
+        assert!(path.exists());
+        assert!(bind(&path).await.is_ok());
+    }
+}
@@ -0,0 +1,161 @@
+use crate::protocol::ProtocolError;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// An active conversation as shown by `wishp conversations`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConversationInfo {
+    pub id: String,
+    pub peer: String,
+    pub addr: String,
+    /// What the conversation is doing: `knock`, `wish`, `evaluate`,
+    /// `negotiate`, `execute` or `thank`.
+    pub stage: String,
+    /// Unix time the KNOCK arrived.
+    pub started: u64,
+}
+
+struct Entry {
+    info: ConversationInfo,
+    cancel: watch::Sender<Option<ProtocolError>>,
+}
+
+/// The conversations the daemon is serving, so they can be listed and
+/// cancelled from the control socket.
+#[derive(Default)]
+pub struct Conversations {
+    active: Mutex<HashMap<String, Entry>>,
+}
+
+impl Conversations {
+    /// Adds a conversation; it is removed when the registration drops.
+    pub fn register(&self, id: &str, peer: &str, addr: SocketAddr) -> Registration<'_> {
+        let (cancel, cancelled) = watch::channel(None);
+        let info = ConversationInfo {
+            id: id.to_string(),
+            peer: peer.to_string(),
+            addr: addr.to_string(),
+            stage: "knock".to_string(),
+            started: crate::protocol::current_timestamp() as u64,
+        };
+        self.active.lock().unwrap().insert(id.to_string(), Entry { info, cancel });
+        Registration { conversations: self, id: id.to_string(), cancelled }
+    }
+
+    pub fn list(&self) -> Vec<ConversationInfo> {
+        let mut list: Vec<_> = self.active.lock().unwrap().values().map(|e| e.info.clone()).collect();
+        list.sort_by_key(|c| c.started);
+        list
+    }
+
+    pub fn len(&self) -> usize {
+        self.active.lock().unwrap().len()
+    }
+
+    /// Ends conversation `id`, sending `error` to the requester if it is
+    /// past the WELCOME.
+    pub fn cancel(&self, id: &str, error: ProtocolError) -> Result<()> {
+        let active = self.active.lock().unwrap();
+        let entry = active.get(id).ok_or_else(|| anyhow!("No active conversation {}", id))?;
+        entry.cancel.send_replace(Some(error));
+        Ok(())
+    }
+
+    /// Cancels every active conversation and returns how many there were.
+    pub fn cancel_all(&self, error: ProtocolError) -> usize {
+        let active = self.active.lock().unwrap();
+        for entry in active.values() {
+            entry.cancel.send_replace(Some(error.clone()));
+        }
+        active.len()
+    }
+
+    /// Resolves once no conversation is active.
+    pub async fn drained(&self) {
+        while self.len() > 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+/// A conversation's entry in `Conversations`.
+pub struct Registration<'a> {
+    conversations: &'a Conversations,
+    id: String,
+    cancelled: watch::Receiver<Option<ProtocolError>>,
+}
+
+impl Registration<'_> {
+    pub fn set_stage(&self, stage: &str) {
+        if let Some(entry) = self.conversations.active.lock().unwrap().get_mut(&self.id) {
+            entry.info.stage = stage.to_string();
+        }
+    }
+
+    /// Resolves with the ERROR to send once the conversation is cancelled.
+    pub async fn cancelled(&self) -> ProtocolError {
+        let mut cancelled = self.cancelled.clone();
+        if let Ok(error) = cancelled.wait_for(Option::is_some).await {
+            if let Some(error) = error.clone() {
+                return error;
+            }
+        }
+        std::future::pending().await
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.conversations.active.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ErrorCode;
+
+    #[tokio::test]
+    async fn test_cancel_and_unregister() {
+        let conversations = Conversations::default();
+        let addr: SocketAddr = "127.0.0.1:7779".parse().unwrap();
+        {
+            let registration = conversations.register("c1", "quest-1", addr);
+            registration.set_stage("execute");
+            assert_eq!(conversations.list()[0].stage, "execute");
+
+            let error = ProtocolError::new(ErrorCode::InternalError, "Cancelled");
+            conversations.cancel("c1", error.clone()).unwrap();
+            assert_eq!(registration.cancelled().await, error);
+        }
+        assert_eq!(conversations.len(), 0);
+        assert!(conversations.cancel("c1", ProtocolError::new(ErrorCode::InternalError, "")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_all_then_drain() {
+        let conversations = Conversations::default();
+        let addr: SocketAddr = "127.0.0.1:7779".parse().unwrap();
+        let error = ProtocolError::new(ErrorCode::InternalError, "Shutting down").recoverable();
+        let serve = |id: &'static str| {
+            let conversations = &conversations;
+            async move {
+                let registration = conversations.register(id, "quest-1", addr);
+                registration.cancelled().await
+            }
+        };
+
+        let (a, b, count) = tokio::join!(serve("c1"), serve("c2"), async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            conversations.cancel_all(error.clone())
+        });
+        assert_eq!(count, 2);
+        assert!(a.recov && b.recov);
+        tokio::time::timeout(Duration::from_secs(1), conversations.drained()).await.unwrap();
+    }
+}
@@ -4,8 +4,10 @@ use aes_gcm::{
 };
 use anyhow::{anyhow, Result};
 use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use rand::rngs::OsRng;
 use sha2::Sha256;
+use subtle::ConstantTimeEq;
 use x25519_dalek::{PublicKey, StaticSecret};
 
 pub fn generate_ephemeral_key() -> (StaticSecret, PublicKey) {
@@ -18,19 +20,81 @@ pub fn get_public_key(secret: &StaticSecret) -> PublicKey {
     PublicKey::from(secret)
 }
 
+/// Loads our long-term X25519 identity key and, when the public key file
+/// is present, checks that it belongs to the same keypair.
+pub fn load_identity(private_key_path: &str, public_key_path: &str) -> Result<StaticSecret> {
+    let path = shellexpand::tilde(private_key_path).into_owned();
+    let bytes = std::fs::read(&path)
+        .map_err(|e| anyhow!("Cannot read private key {}: {}", path, e))?;
+    let key: [u8; 32] = bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("Invalid private key length: {}", bytes.len()))?;
+    let secret = StaticSecret::from(key);
+
+    let public_path = shellexpand::tilde(public_key_path).into_owned();
+    if let Ok(public) = std::fs::read(&public_path) {
+        if public.as_slice() != get_public_key(&secret).as_bytes() {
+            return Err(anyhow!("Public key {} does not match private key {}", public_path, path));
+        }
+    }
+
+    Ok(secret)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Requester,
+    Responder,
+}
+
+/// Derives the session key from three ECDH results so that both the
+/// ephemeral keys and the long-term identity keys from the keyring
+/// contribute:
+///
+/// - requester ephemeral × responder static
+/// - requester static × responder ephemeral
+/// - requester ephemeral × responder ephemeral
+///
+/// Only a peer holding the private half of its keyring entry arrives at
+/// the same key.
 pub fn derive_session_key(
-    my_private: &StaticSecret,
-    peer_public: &[u8; 32],
+    role: Role,
+    my_static: &StaticSecret,
+    my_ephemeral: &StaticSecret,
+    peer_static: &[u8; 32],
+    peer_ephemeral: &[u8; 32],
     requester_id: &str,
     responder_id: &str,
 ) -> Result<[u8; 32]> {
-    let peer_public_key = PublicKey::from(*peer_public);
-    let shared_secret = my_private.diffie_hellman(&peer_public_key);
+    let peer_static = PublicKey::from(*peer_static);
+    let peer_ephemeral = PublicKey::from(*peer_ephemeral);
+
+    let (eph_static, static_eph) = match role {
+        Role::Requester => (
+            my_ephemeral.diffie_hellman(&peer_static),
+            my_static.diffie_hellman(&peer_ephemeral),
+        ),
+        Role::Responder => (
+            my_static.diffie_hellman(&peer_ephemeral),
+            my_ephemeral.diffie_hellman(&peer_static),
+        ),
+    };
+    let eph_eph = my_ephemeral.diffie_hellman(&peer_ephemeral);
+
+    for shared in [&eph_static, &static_eph, &eph_eph] {
+        if !shared.was_contributory() {
+            return Err(anyhow!("Key exchange produced a non-contributory shared secret"));
+        }
+    }
 
-    let hk = Hkdf::<Sha256>::new(
-        Some(b"WishProtocol-v2.0-SessionKey"),
-        shared_secret.as_bytes(),
-    );
+    let mut ikm = Vec::with_capacity(96);
+    ikm.extend_from_slice(eph_static.as_bytes());
+    ikm.extend_from_slice(static_eph.as_bytes());
+    ikm.extend_from_slice(eph_eph.as_bytes());
+
+    let hk = Hkdf::<Sha256>::new(Some(b"WishProtocol-v2.0-SessionKey"), &ikm);
+    ikm.fill(0);
 
     let mut session_key = [0u8; 32];
     let info = format!("{}{}", requester_id, responder_id);
@@ -41,6 +105,52 @@ pub fn derive_session_key(
     Ok(session_key)
 }
 
+/// Proof carried in KNOCK that the requester holds the static key
+/// registered for `requester_id`. It is a MAC over the requester's
+/// ephemeral key and the KNOCK timestamp, keyed by the static-static
+/// ECDH result, so the responder can check it before any handler runs.
+pub fn knock_auth(
+    my_static: &StaticSecret,
+    peer_static: &[u8; 32],
+    requester_eph: &[u8; 32],
+    timestamp: u32,
+    requester_id: &str,
+    responder_id: &str,
+) -> Result<[u8; 32]> {
+    let shared = my_static.diffie_hellman(&PublicKey::from(*peer_static));
+    if !shared.was_contributory() {
+        return Err(anyhow!("Key exchange produced a non-contributory shared secret"));
+    }
+
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(shared.as_bytes())
+        .map_err(|_| anyhow!("Invalid MAC key"))?;
+    mac.update(b"WishProtocol-v2.0-KnockAuth");
+    mac.update(requester_eph);
+    mac.update(&timestamp.to_be_bytes());
+    mac.update(requester_id.as_bytes());
+    mac.update(responder_id.as_bytes());
+
+    Ok(mac.finalize().into_bytes().into())
+}
+
+/// Key confirmation carried in WELCOME. Only a responder that derived the
+/// same session key (and therefore holds its static key) can produce it.
+pub fn welcome_auth(session_key: &[u8; 32], responder_eph: &[u8; 32]) -> Result<[u8; 32]> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(session_key)
+        .map_err(|_| anyhow!("Invalid MAC key"))?;
+    mac.update(b"WishProtocol-v2.0-WelcomeAuth");
+    mac.update(responder_eph);
+
+    Ok(mac.finalize().into_bytes().into())
+}
+
+pub fn verify_auth(expected: &[u8; 32], received: &[u8]) -> Result<()> {
+    if received.len() != expected.len() || !bool::from(expected.ct_eq(received)) {
+        return Err(anyhow!("Peer failed to prove possession of its identity key"));
+    }
+    Ok(())
+}
+
 fn build_nonce(counter: u32, timestamp: u32) -> [u8; 12] {
     let mut nonce_bytes = [0u8; 12];
     nonce_bytes[0..8].copy_from_slice(&(counter as u64).to_be_bytes());
@@ -90,6 +200,18 @@ pub fn decrypt_message(
         .map_err(|e| anyhow!("Decryption failed: {}", e))
 }
 
+/// SHA-256 fingerprint of a DER certificate, as lowercase hex.
+pub fn cert_fingerprint(der: &[u8]) -> String {
+    use sha2::Digest;
+    hex::encode(Sha256::digest(der))
+}
+
+/// SHA-256 of a static public key, as stored in blocklist entries.
+pub fn key_fingerprint(public_key: &[u8; 32]) -> [u8; 32] {
+    use sha2::Digest;
+    Sha256::digest(public_key).into()
+}
+
 pub fn zeroize_key(key: &mut [u8; 32]) {
     key.fill(0);
 }
@@ -98,24 +220,39 @@ pub fn zeroize_key(key: &mut [u8; 32]) {
 mod tests {
     use super::*;
 
+    struct Party {
+        id: &'static str,
+        static_secret: StaticSecret,
+        static_public: PublicKey,
+        eph_secret: StaticSecret,
+        eph_public: PublicKey,
+    }
+
+    fn party(id: &'static str) -> Party {
+        let (static_secret, static_public) = generate_ephemeral_key();
+        let (eph_secret, eph_public) = generate_ephemeral_key();
+        Party { id, static_secret, static_public, eph_secret, eph_public }
+    }
+
+    fn session_key(me: &Party, role: Role, peer: &Party, requester: &Party, responder: &Party) -> Result<[u8; 32]> {
+        derive_session_key(
+            role,
+            &me.static_secret,
+            &me.eph_secret,
+            peer.static_public.as_bytes(),
+            peer.eph_public.as_bytes(),
+            requester.id,
+            responder.id,
+        )
+    }
+
     #[test]
     fn test_key_exchange_and_encryption() {
-        let (alice_secret, alice_public) = generate_ephemeral_key();
-        let (bob_secret, bob_public) = generate_ephemeral_key();
-
-        let alice_session_key = derive_session_key(
-            &alice_secret,
-            bob_public.as_bytes(),
-            "alice-12345678",
-            "bob-87654321",
-        ).unwrap();
+        let alice = party("alice-12345678");
+        let bob = party("bob-87654321");
 
-        let bob_session_key = derive_session_key(
-            &bob_secret,
-            alice_public.as_bytes(),
-            "alice-12345678",
-            "bob-87654321",
-        ).unwrap();
+        let alice_session_key = session_key(&alice, Role::Requester, &bob, &alice, &bob).unwrap();
+        let bob_session_key = session_key(&bob, Role::Responder, &alice, &alice, &bob).unwrap();
 
         assert_eq!(alice_session_key, bob_session_key);
 
@@ -144,24 +281,103 @@ mod tests {
     }
 
     #[test]
-    fn test_decrypt_failure_with_wrong_key() {
-        let (alice_secret, alice_public) = generate_ephemeral_key();
-        let (bob_secret, _bob_public) = generate_ephemeral_key();
-        let (_charlie_secret, charlie_public) = generate_ephemeral_key();
+    fn test_impersonator_derives_different_key() {
+        let alice = party("alice");
+        let bob = party("bob");
+        let mallory = party("alice");
+
+        // Mallory claims to be alice but only holds her own static key.
+        let mallory_key = derive_session_key(
+            Role::Requester,
+            &mallory.static_secret,
+            &mallory.eph_secret,
+            bob.static_public.as_bytes(),
+            bob.eph_public.as_bytes(),
+            "alice",
+            "bob",
+        ).unwrap();
+        let bob_key = derive_session_key(
+            Role::Responder,
+            &bob.static_secret,
+            &bob.eph_secret,
+            alice.static_public.as_bytes(),
+            mallory.eph_public.as_bytes(),
+            "alice",
+            "bob",
+        ).unwrap();
+
+        assert_ne!(mallory_key, bob_key);
+    }
+
+    #[test]
+    fn test_low_order_point_rejected() {
+        let alice = party("alice");
+        let bob = party("bob");
+
+        let result = derive_session_key(
+            Role::Requester,
+            &alice.static_secret,
+            &alice.eph_secret,
+            bob.static_public.as_bytes(),
+            &[0u8; 32],
+            alice.id,
+            bob.id,
+        );
+
+        assert!(result.is_err());
+    }
 
-        let alice_session_key = derive_session_key(
-            &alice_secret,
-            charlie_public.as_bytes(),
+    #[test]
+    fn test_knock_auth() {
+        let alice = party("alice");
+        let bob = party("bob");
+        let mallory = party("mallory");
+
+        let sent = knock_auth(
+            &alice.static_secret,
+            bob.static_public.as_bytes(),
+            alice.eph_public.as_bytes(),
+            100,
+            "alice",
+            "bob",
+        ).unwrap();
+        let expected = knock_auth(
+            &bob.static_secret,
+            alice.static_public.as_bytes(),
+            alice.eph_public.as_bytes(),
+            100,
             "alice",
-            "charlie",
+            "bob",
         ).unwrap();
+        assert!(verify_auth(&expected, &sent).is_ok());
 
-        let bob_session_key = derive_session_key(
-            &bob_secret,
-            alice_public.as_bytes(),
+        let forged = knock_auth(
+            &mallory.static_secret,
+            bob.static_public.as_bytes(),
+            mallory.eph_public.as_bytes(),
+            100,
+            "alice",
+            "bob",
+        ).unwrap();
+        let expected = knock_auth(
+            &bob.static_secret,
+            alice.static_public.as_bytes(),
+            mallory.eph_public.as_bytes(),
+            100,
             "alice",
             "bob",
         ).unwrap();
+        assert!(verify_auth(&expected, &forged).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_failure_with_wrong_key() {
+        let alice = party("alice");
+        let bob = party("bob");
+        let charlie = party("charlie");
+
+        let alice_session_key = session_key(&alice, Role::Requester, &charlie, &alice, &charlie).unwrap();
+        let bob_session_key = session_key(&bob, Role::Responder, &alice, &alice, &bob).unwrap();
 
         let message = b"Secret";
         let counter = 1;
@@ -176,15 +392,10 @@ mod tests {
 
     #[test]
     fn test_aad_mismatch_fails() {
-        let (alice_secret, _alice_public) = generate_ephemeral_key();
-        let (_bob_secret, bob_public) = generate_ephemeral_key();
+        let alice = party("alice");
+        let bob = party("bob");
 
-        let session_key = derive_session_key(
-            &alice_secret,
-            bob_public.as_bytes(),
-            "alice",
-            "bob",
-        ).unwrap();
+        let session_key = session_key(&alice, Role::Requester, &bob, &alice, &bob).unwrap();
 
         let message = b"Secret";
         let counter = 1;
@@ -1,18 +1,54 @@
-use crate::crypto;
+use crate::approval::{Approval, ApprovalConfig, ApprovalQueue, Resolution};
+use crate::crypto::{self, Role};
+use crate::control::{self, ControlConfig};
+use crate::conversations::{Conversations, Registration};
+use crate::blocklist::{BlockReason, Blocklist};
+use crate::capabilities::{self, Capabilities, Capability, Mismatch};
+use crate::envelope::{self, CapabilitiesResponse, EvaluateResponse, ExecuteResponse, HandlerRequest, KnockResponse, Phase, PeerInfo};
+use crate::handler::{Handler, HandlerOptions, Progress};
 use crate::keyring::Keyring;
-use crate::protocol::{self, Message, Stage, PROTOCOL_VERSION};
-use anyhow::{anyhow, Result};
+use crate::outbox::{Outbox, OutboxConfig};
+use crate::payload::{
+    Category, GiftPayload, GrantPayload, KnockPayload, Priority, Reason, StagePayload, Status, ThankContext, ThankPayload, WelcomePayload,
+    WishPayload,
+};
+use crate::policy::{Decision, Policy, Subject, Verdict};
+use crate::router::{RouteConfig, Router, Target};
+use crate::scheduler::{self, CapacityConfig, Scheduler};
+use crate::ratelimit::{LimitOverrides, Limits, RateLimited, RateLimiter};
+use crate::replay::KnockCache;
+use crate::protocol::{self, CounterProposal, ErrorCode, Message, ProtocolError, RejectReason, Stage};
+use crate::session::{self, Session};
+use anyhow::{anyhow, Context, Result};
 use std::collections::HashMap;
-use std::io::Write;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
-use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Notify, OnceCell};
 use tokio_rustls::rustls::{
     pki_types::CertificateDer, pki_types::PrivateKeyDer, ServerConfig,
 };
 use tokio_rustls::TlsAcceptor;
+use x25519_dalek::StaticSecret;
+
+/// Shortest gap between two WRAP progress messages.
+const MIN_WRAP_INTERVAL: Duration = Duration::from_secs(1);
+/// How long before the requester's WELCOME or GRANT deadline we give up
+/// waiting for an operator and answer `busy`.
+const APPROVAL_MARGIN: Duration = Duration::from_secs(5);
+
+/// How long cancelled conversations get to send their ERROR before the
+/// daemon exits anyway.
+const CANCEL_WAIT: Duration = Duration::from_secs(5);
+
+/// Longest sleep between outbox checks.
+const OUTBOX_POLL: Duration = Duration::from_secs(60);
+
+/// Where `wishp` reads its config, and the daemon re-reads it on reload.
+pub const CONFIG_PATH: &str = "~/.wish-protocol/config.toml";
 
 #[derive(serde::Deserialize, Clone)]
 pub struct Config {
@@ -20,6 +56,40 @@ pub struct Config {
     pub network: NetworkConfig,
     pub openclaw: OpenClawConfig,
     pub keys: KeysConfig,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub blocklist: BlocklistConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// Routes tried in order before falling back to `openclaw`.
+    #[serde(default)]
+    pub handlers: Vec<RouteConfig>,
+    #[serde(default)]
+    pub policy: PolicyConfig,
+    #[serde(default)]
+    pub approval: ApprovalConfig,
+    #[serde(default)]
+    pub control: ControlConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub outbox: OutboxConfig,
+    #[serde(default)]
+    pub defer: DeferConfig,
+    #[serde(default)]
+    pub capacity: CapacityConfig,
+    /// Actions advertised to requesters, besides those handlers with
+    /// `capabilities = true` declare.
+    #[serde(default)]
+    pub actions: Vec<Capability>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|e| anyhow!("Invalid config {}: {}", path.display(), e))
+    }
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -35,6 +105,8 @@ pub struct NetworkConfig {
 #[derive(serde::Deserialize, Clone)]
 pub struct OpenClawConfig {
     pub path: String,
+    #[serde(flatten)]
+    pub run: HandlerOptions,
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -46,128 +118,229 @@ pub struct KeysConfig {
     pub key_path: String,
 }
 
-#[derive(Clone)]
-struct BlocklistEntry {
-    agent_id: String,
-    reason: BlockReason,
-    blocked_at: u64,
-    violation_count: u16,
+#[derive(serde::Deserialize, Clone)]
+pub struct BlocklistConfig {
+    pub path: String,
 }
 
-#[derive(Clone)]
-enum BlockReason {
-    Spam = 1,
-    MalformedMessages = 2,
-    SizeViolations = 3,
-    RateLimitViolations = 4,
-    SuspiciousBehavior = 5,
-    ManualBlock = 6,
+impl Default for BlocklistConfig {
+    fn default() -> Self {
+        Self {
+            path: "~/.wish-protocol/blocklist.msgpack".to_string(),
+        }
+    }
 }
 
-struct Blocklist {
-    entries: HashMap<String, BlocklistEntry>,
+#[derive(serde::Deserialize, Clone)]
+pub struct PolicyConfig {
+    pub path: String,
 }
 
-impl Blocklist {
-    fn new() -> Self {
+impl Default for PolicyConfig {
+    fn default() -> Self {
         Self {
-            entries: HashMap::new(),
+            path: "~/.wish-protocol/policy.toml".to_string(),
         }
     }
+}
 
-    fn is_blocked(&self, agent_id: &str) -> bool {
-        self.entries.contains_key(agent_id)
+impl PolicyConfig {
+    pub fn load(&self) -> Result<Policy> {
+        Policy::load(&PathBuf::from(shellexpand::tilde(&self.path).into_owned()))
     }
+}
 
-    fn add_violation(&mut self, agent_id: &str, reason: BlockReason) {
-        let now = protocol::current_timestamp() as u64;
+/// What happens to active conversations when the daemon stops.
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// Seconds active conversations get to finish.
+    pub grace: u64,
+    /// `retry` hint, in seconds, sent to requesters cut off after the grace
+    /// period.
+    pub retry: u64,
+}
 
-        let entry = self.entries.entry(agent_id.to_string()).or_insert(BlocklistEntry {
-            agent_id: agent_id.to_string(),
-            reason: reason.clone(),
-            blocked_at: 0,
-            violation_count: 0,
-        });
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self { grace: 30, retry: 60 }
+    }
+}
 
-        entry.violation_count += 1;
-        entry.reason = reason.clone();
+/// When a GRANT hands out a ticket and the GIFT follows in a conversation of
+/// its own. Only requesters that ask for it with `dfr` and have an endpoint
+/// in the keyring get one.
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct DeferConfig {
+    /// Smallest `est_t`, in seconds, that is deferred; 0 never defers.
+    pub after: u64,
+    /// Seconds past `est_t` the ticket stays valid.
+    pub expire: u64,
+}
 
-        let should_block = match reason {
-            BlockReason::SizeViolations => entry.violation_count >= 3,
-            BlockReason::RateLimitViolations => entry.violation_count >= 10,
-            BlockReason::MalformedMessages => entry.violation_count >= 5,
-            BlockReason::ManualBlock => true,
-            _ => entry.violation_count >= 5,
-        };
+impl Default for DeferConfig {
+    fn default() -> Self {
+        Self { after: 600, expire: 24 * 60 * 60 }
+    }
+}
+
+/// Rate limits: defaults for every peer plus `[limits.peers.<agent-id>]`
+/// overrides for partners that need more.
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct LimitsConfig {
+    #[serde(flatten)]
+    pub defaults: Limits,
+    pub state_path: String,
+    pub peers: HashMap<String, LimitOverrides>,
+}
 
-        if should_block && entry.blocked_at == 0 {
-            entry.blocked_at = now;
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            defaults: Limits::default(),
+            state_path: "~/.wish-protocol/ratelimit.msgpack".to_string(),
+            peers: HashMap::new(),
         }
     }
+}
 
-    fn block(&mut self, agent_id: &str, reason: BlockReason) {
-        let now = protocol::current_timestamp() as u64;
-        self.entries.insert(agent_id.to_string(), BlocklistEntry {
-            agent_id: agent_id.to_string(),
-            reason,
-            blocked_at: now,
-            violation_count: 1,
-        });
+impl LimitsConfig {
+    pub fn for_peer(&self, agent_id: &str) -> Limits {
+        match self.peers.get(agent_id) {
+            Some(overrides) => overrides.apply(&self.defaults),
+            None => self.defaults.clone(),
+        }
     }
 }
 
-struct RateLimiter {
-    knocks_per_hour: HashMap<String, (u32, u64)>,
-    bytes_per_hour: HashMap<String, (u64, u64)>,
+/// Stage timeouts in seconds (spec §4.3, §10.4).
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    pub tls_handshake: u64,
+    /// Waiting for WELCOME; the daemon also allows this long for the KNOCK
+    /// and the first WISH.
+    pub welcome: u64,
+    /// Waiting for GRANT; the daemon also allows this long for revised
+    /// WISHes and the closing THANK.
+    pub grant: u64,
+    /// Added to the GRANT's `est_t` (or a WRAP's `eta`) to get the GIFT
+    /// deadline.
+    pub gift_margin: u64,
 }
 
-impl RateLimiter {
-    fn new() -> Self {
+impl Default for TimeoutConfig {
+    fn default() -> Self {
         Self {
-            knocks_per_hour: HashMap::new(),
-            bytes_per_hour: HashMap::new(),
+            tls_handshake: 10,
+            welcome: 30,
+            grant: 60,
+            gift_margin: 60,
         }
     }
+}
 
-    fn check_knock(&mut self, agent_id: &str) -> Result<()> {
-        let now = protocol::current_timestamp() as u64;
-        let hour_ago = now.saturating_sub(3600);
+impl TimeoutConfig {
+    pub fn tls_handshake(&self) -> Duration {
+        Duration::from_secs(self.tls_handshake)
+    }
 
-        let (count, reset_time) = self.knocks_per_hour
-            .entry(agent_id.to_string())
-            .or_insert((0, now));
+    pub fn welcome(&self) -> Duration {
+        Duration::from_secs(self.welcome)
+    }
 
-        if *reset_time < hour_ago {
-            *count = 0;
-            *reset_time = now;
-        }
+    pub fn grant(&self) -> Duration {
+        Duration::from_secs(self.grant)
+    }
 
-        if *count >= 100 {
-            return Err(anyhow!("Rate limit exceeded: max 100 KNOCK per hour"));
-        }
+    /// Deadline for the GIFT (or the next WRAP) when `expected` seconds of
+    /// work remain.
+    pub fn gift(&self, expected: u64) -> Duration {
+        Duration::from_secs(expected.saturating_add(self.gift_margin))
+    }
+}
 
-        *count += 1;
-        Ok(())
+/// The config and what is built from it. A reload swaps in a new one;
+/// conversations in progress keep the one they started with.
+struct Settings {
+    config: Config,
+    router: Router,
+    policy: Policy,
+    /// Filled in by the first KNOCK that needs it.
+    capabilities: OnceCell<Capabilities>,
+}
+
+impl Settings {
+    fn new(config: Config) -> Result<Self> {
+        let router = Router::new(&config.handlers, Handler::new(&config.openclaw.path, &config.openclaw.run))?;
+        let policy = config.policy.load()?;
+        for action in &config.actions {
+            action.check_schemas().context("Invalid [[actions]]")?;
+        }
+        Ok(Self { config, router, policy, capabilities: OnceCell::new() })
     }
 
-    fn check_bytes(&mut self, agent_id: &str, bytes: u64) -> Result<()> {
-        let now = protocol::current_timestamp() as u64;
-        let hour_ago = now.saturating_sub(3600);
+    /// The advertised actions: `[[actions]]` followed by what the declaring
+    /// handlers answer to `request`. Handlers are asked once per config; one
+    /// that fails is left out until the next reload.
+    async fn capabilities(&self, request: &HandlerRequest) -> &Capabilities {
+        self.capabilities
+            .get_or_init(|| async {
+                let mut actions = self.config.actions.clone();
+                for handler in self.router.handlers().filter(|h| h.declares_capabilities()) {
+                    match call_openclaw::<CapabilitiesResponse>(handler, request, None).await {
+                        Ok(response) => {
+                            for action in response.actions {
+                                match action.check_schemas() {
+                                    Ok(()) => actions.push(action),
+                                    Err(e) => eprintln!("Not advertising a handler's action: {}", e),
+                                }
+                            }
+                        }
+                        Err(e) => eprintln!("Capabilities handler failed: {}", e),
+                    }
+                }
+                Capabilities::new(actions)
+            })
+            .await
+    }
+}
 
-        let (total_bytes, reset_time) = self.bytes_per_hour
-            .entry(agent_id.to_string())
-            .or_insert((0, now));
+/// State shared by all connections.
+struct Shared {
+    settings: RwLock<Arc<Settings>>,
+    approvals: ApprovalQueue,
+    conversations: Conversations,
+    outbox: Outbox,
+    scheduler: Scheduler,
+    blocklist: Mutex<Blocklist>,
+    rate_limiter: Mutex<RateLimiter>,
+    keyring: Mutex<Keyring>,
+    knocks: KnockCache,
+    identity: StaticSecret,
+    shutdown: Notify,
+}
 
-        if *reset_time < hour_ago {
-            *total_bytes = 0;
-            *reset_time = now;
-        }
+impl Shared {
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
 
-        if *total_bytes + bytes > 100 * 1024 * 1024 {
-            return Err(anyhow!("Rate limit exceeded: max 100 MB per hour"));
-        }
+    /// Re-reads the config file, the policy and the keyring. The listening
+    /// port, keys and control socket only change on restart.
+    fn reload(&self) -> Result<()> {
+        let path = PathBuf::from(shellexpand::tilde(CONFIG_PATH).into_owned());
+        let settings = Settings::new(Config::load(&path)?)?;
+        self.scheduler.configure(&settings.config.capacity)?;
+        *self.settings.write().unwrap() = Arc::new(settings);
+        self.reload_keyring()
+    }
 
-        *total_bytes += bytes;
+    fn reload_keyring(&self) -> Result<()> {
+        let path = shellexpand::tilde(&self.settings().config.keys.keyring_path).into_owned();
+        *self.keyring.lock().unwrap() = Keyring::load(PathBuf::from(path))?;
         Ok(())
     }
 }
@@ -178,11 +351,35 @@ pub async fn start_server(config: Config) -> Result<()> {
     let key = load_key(&config.keys.key_path)?;
 
     let keyring_path = shellexpand::tilde(&config.keys.keyring_path).into_owned();
-    let keyring = Arc::new(Mutex::new(Keyring::load(PathBuf::from(keyring_path))?));
-
-    let config_arc = Arc::new(config);
-    let blocklist = Arc::new(Mutex::new(Blocklist::new()));
-    let rate_limiter = Arc::new(Mutex::new(RateLimiter::new()));
+    let keyring = Mutex::new(Keyring::load(PathBuf::from(keyring_path))?);
+    let identity = crypto::load_identity(&config.keys.private_key_path, &config.keys.public_key_path)?;
+
+    let blocklist_path = shellexpand::tilde(&config.blocklist.path).into_owned();
+    let blocklist = Mutex::new(Blocklist::load(PathBuf::from(blocklist_path))?);
+
+    let rate_limit_path = shellexpand::tilde(&config.limits.state_path).into_owned();
+    let rate_limiter = Mutex::new(RateLimiter::load(PathBuf::from(rate_limit_path))?);
+
+    let outbox = Outbox::load(config.outbox.path())?;
+    let scheduler = Scheduler::new(&config.capacity)?;
+
+    let control_path = config.control.path();
+    let control = control::bind(&control_path)
+        .await
+        .with_context(|| format!("Control socket {}", control_path.display()))?;
+    let shared = Arc::new(Shared {
+        settings: RwLock::new(Arc::new(Settings::new(config)?)),
+        approvals: ApprovalQueue::default(),
+        conversations: Conversations::default(),
+        outbox,
+        scheduler,
+        blocklist,
+        rate_limiter,
+        keyring,
+        knocks: KnockCache::default(),
+        identity,
+        shutdown: Notify::new(),
+    });
 
     let server_config = ServerConfig::builder()
         .with_no_client_auth()
@@ -193,31 +390,196 @@ pub async fn start_server(config: Config) -> Result<()> {
     let listener = TcpListener::bind(&addr).await?;
     println!("Wish Protocol daemon listening on {}", addr);
 
+    let serve: control::Serve = {
+        let shared = shared.clone();
+        Arc::new(move |request| control_request(&shared, request))
+    };
+    watch_signals(shared.clone())?;
+    tokio::spawn(deliver_outbox(shared.clone()));
+    let socket_path = control_path.clone();
+    tokio::spawn(async move {
+        if let Err(e) = control::listen(control, serve).await {
+            eprintln!("Control socket {} failed: {}", socket_path.display(), e);
+        }
+    });
+
     loop {
-        let (stream, peer_addr) = listener.accept().await?;
+        let (stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shared.shutdown.notified() => break,
+        };
         let acceptor = acceptor.clone();
-        let config_clone = config_arc.clone();
-        let blocklist_clone = blocklist.clone();
-        let rate_limiter_clone = rate_limiter.clone();
-        let keyring_clone = keyring.clone();
+        let shared = shared.clone();
 
         tokio::spawn(async move {
-            match acceptor.accept(stream).await {
-                Ok(mut tls_stream) => {
-                    if let Err(e) = handle_connection(
-                        &mut tls_stream,
-                        &config_clone,
-                        blocklist_clone,
-                        rate_limiter_clone,
-                        keyring_clone,
-                    ).await {
-                        eprintln!("Error handling connection from {}: {}", peer_addr, e);
+            let handshake = shared.settings().config.timeouts.tls_handshake();
+            match tokio::time::timeout(handshake, acceptor.accept(stream)).await {
+                Err(_) => eprintln!("TLS handshake with {} timed out", peer_addr),
+                Ok(Ok(mut tls_stream)) => {
+                    let deferred = match handle_connection(&mut tls_stream, peer_addr, &shared).await {
+                        Ok(deferred) => deferred,
+                        Err(e) => {
+                            eprintln!("Error handling connection from {}: {}", peer_addr, e);
+                            None
+                        }
+                    };
+                    if let Err(e) = shared.rate_limiter.lock().unwrap().save() {
+                        eprintln!("Warning: Could not save rate limit state: {}", e);
+                    }
+                    if let Some(deferred) = deferred {
+                        let _ = tls_stream.shutdown().await;
+                        drop(tls_stream);
+                        run_deferred(&shared, deferred).await;
                     }
                 }
-                Err(e) => eprintln!("TLS accept error from {}: {}", peer_addr, e),
+                Ok(Err(e)) => eprintln!("TLS accept error from {}: {}", peer_addr, e),
             }
         });
     }
+
+    drop(listener);
+    drain(&shared).await;
+    shared.rate_limiter.lock().unwrap().save()?;
+    let _ = std::fs::remove_file(&control_path);
+    println!("Daemon stopped");
+    Ok(())
+}
+
+/// Lets active conversations finish within the grace period, then cuts off
+/// the rest with a recoverable ERROR. A second shutdown request ends the
+/// grace period early.
+async fn drain(shared: &Shared) {
+    let shutdown = shared.settings().config.shutdown.clone();
+    let active = shared.conversations.len();
+    if active == 0 {
+        return;
+    }
+
+    println!("Shutting down, waiting up to {}s for {} active conversations", shutdown.grace, active);
+    let drained = tokio::select! {
+        _ = shared.conversations.drained() => true,
+        _ = tokio::time::sleep(Duration::from_secs(shutdown.grace)) => false,
+        _ = shared.shutdown.notified() => false,
+    };
+    if drained {
+        return;
+    }
+
+    let error = ProtocolError::new(ErrorCode::InternalError, "Responder is shutting down")
+        .with_details(serde_json::json!({"retry": shutdown.retry}))
+        .recoverable();
+    let cancelled = shared.conversations.cancel_all(error);
+    println!("Cancelled {} conversations", cancelled);
+    let _ = tokio::time::timeout(CANCEL_WAIT, shared.conversations.drained()).await;
+}
+
+/// Sends queued outbox jobs as they come due.
+async fn deliver_outbox(shared: Arc<Shared>) {
+    loop {
+        let settings = shared.settings();
+        shared.outbox.expire(settings.config.outbox.keep);
+        for job in shared.outbox.take_due(protocol::current_timestamp() as u64) {
+            let shared = shared.clone();
+            tokio::spawn(async move {
+                let settings = shared.settings();
+                shared.outbox.deliver(job, &settings.config).await;
+            });
+        }
+
+        let wait = shared.outbox.next_due().map_or(OUTBOX_POLL, |due| {
+            Duration::from_secs(due.saturating_sub(protocol::current_timestamp() as u64)).clamp(Duration::from_secs(1), OUTBOX_POLL)
+        });
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = shared.outbox.woken() => {}
+        }
+    }
+}
+
+/// SIGTERM and SIGINT shut the daemon down like `wishp shutdown`; SIGHUP
+/// reloads like `wishp reload`.
+fn watch_signals(shared: Arc<Shared>) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = terminate.recv() => shared.shutdown.notify_one(),
+                _ = interrupt.recv() => shared.shutdown.notify_one(),
+                _ = hangup.recv() => match shared.reload() {
+                    Ok(()) => println!("Reloaded config and keyring"),
+                    Err(e) => eprintln!("Reload failed: {}", e),
+                },
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Answers a command from `wishp` on the control socket.
+fn control_request(shared: &Shared, request: control::Request) -> Result<serde_json::Value> {
+    use control::Request;
+
+    let done = serde_json::Value::Null;
+    match request {
+        Request::Pending => Ok(serde_json::to_value(shared.approvals.list())?),
+        Request::Approve { id } => {
+            shared.approvals.resolve(&id, Resolution::Approved)?;
+            Ok(done)
+        }
+        Request::Deny { id, reason } => {
+            let reason = reason.unwrap_or_else(|| RejectReason::PolicyViolation.name().to_string());
+            shared.approvals.resolve(&id, Resolution::Denied(reason))?;
+            Ok(done)
+        }
+        Request::Reload => {
+            shared.reload()?;
+            println!("Reloaded config and keyring");
+            Ok(done)
+        }
+        Request::ReloadKeyring => {
+            shared.reload_keyring()?;
+            Ok(done)
+        }
+        Request::Blocklist => {
+            let mut blocklist = shared.blocklist.lock().unwrap();
+            blocklist.refresh()?;
+            Ok(serde_json::to_value(blocklist.list())?)
+        }
+        Request::Block { id, reason } => {
+            protocol::validate_agent_id(&id)?;
+            let fp = shared.keyring.lock().unwrap().get(&id).map(crypto::key_fingerprint);
+            shared.blocklist.lock().unwrap().block(&id, reason, fp)?;
+            Ok(done)
+        }
+        Request::Unblock { id } => Ok(serde_json::json!(shared.blocklist.lock().unwrap().unblock(&id)?)),
+        Request::RateLimits => Ok(serde_json::to_value(shared.rate_limiter.lock().unwrap().usage())?),
+        Request::Conversations => Ok(serde_json::to_value(shared.conversations.list())?),
+        Request::Cancel { id } => {
+            let error = ProtocolError::new(ErrorCode::InternalError, "Conversation cancelled by the responder");
+            shared.conversations.cancel(&id, error)?;
+            Ok(done)
+        }
+        Request::Send { target, payload, select_script } => {
+            crate::client::check_input(&payload)?;
+            let destination = crate::client::Destination::resolve(&target, &shared.settings().config)?;
+            if shared.keyring.lock().unwrap().get(&destination.agent_id).is_none() {
+                return Err(anyhow!("Public key for {} not found in keyring", destination.agent_id));
+            }
+            Ok(serde_json::to_value(shared.outbox.enqueue(target, destination.agent_id, payload, select_script)?)?)
+        }
+        Request::Outbox => Ok(serde_json::to_value(shared.outbox.list())?),
+        Request::Job { id } => Ok(serde_json::to_value(shared.outbox.get(&id)?)?),
+        Request::Ticket { id } => Ok(serde_json::to_value(shared.outbox.by_ticket(&id)?)?),
+        Request::CancelJob { id } => Ok(serde_json::to_value(shared.outbox.cancel(&id)?)?),
+        Request::Shutdown => {
+            shared.shutdown.notify_one();
+            Ok(done)
+        }
+    }
 }
 
 fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
@@ -238,104 +600,226 @@ fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
         .map_err(|e| anyhow!("Error loading key: {}", e))?
 }
 
-async fn handle_connection<S>(
-    stream: &mut S,
-    config: &Config,
-    blocklist: Arc<Mutex<Blocklist>>,
-    rate_limiter: Arc<Mutex<RateLimiter>>,
-    _keyring: Arc<Mutex<Keyring>>,
-) -> Result<()>
+/// A task granted with a ticket, to run once its conversation has closed.
+/// It keeps the conversation's registration, so a shutdown waits for it.
+struct Deferred<'a> {
+    registration: Registration<'a>,
+    ticket: String,
+    expires: u64,
+    settings: Arc<Settings>,
+    peer: String,
+    category: Category,
+    priority: Priority,
+    est: u64,
+    labels: Vec<String>,
+    request: HandlerRequest,
+}
+
+/// Runs a deferred task and queues its GIFT, carrying the ticket, in the
+/// outbox. A failed task still gets a GIFT, with `ok` false.
+async fn run_deferred(shared: &Shared, deferred: Deferred<'_>) {
+    let registration = &deferred.registration;
+    registration.set_stage("execute");
+
+    let wish: Option<WishPayload> = deferred.request.msg.parse().ok();
+    let target = Target {
+        category: Some(deferred.category),
+        action: wish.as_ref().and_then(WishPayload::action),
+        peer: &deferred.peer,
+        labels: &deferred.labels,
+    };
+    let handler = deferred.settings.router.route(&target).1;
+    let task = scheduler::Task {
+        priority: deferred.priority,
+        category: deferred.category,
+        est: deferred.est,
+    };
+    let run = async {
+        let _slot = shared.scheduler.acquire(task, None).await;
+        call_openclaw::<ExecuteResponse>(handler, &deferred.request, None).await
+    };
+    let result = tokio::select! {
+        response = run => response.and_then(ExecuteResponse::result),
+        error = registration.cancelled() => Err(error.into()),
+    };
+
+    let gift = match result {
+        Ok(res) => build_gift_payload(res),
+        Err(e) => {
+            eprintln!("Deferred task {} for {} failed: {}", deferred.ticket, deferred.peer, e);
+            GiftPayload { ok: false, ..build_gift_payload(serde_json::json!({"err": e.to_string()})) }
+        }
+    };
+    let gift = GiftPayload { tkt: Some(deferred.ticket.clone()), ..gift };
+    if let Err(e) = shared.outbox.enqueue_gift(&deferred.peer, &gift, deferred.expires) {
+        eprintln!("Could not queue the GIFT for ticket {}: {}", deferred.ticket, e);
+    }
+}
+
+fn build_gift_payload(result: serde_json::Value) -> GiftPayload {
+    GiftPayload {
+        meta: Some(serde_json::json!({"exec_t": 1})),
+        ..GiftPayload::new(true, result)
+    }
+}
+
+/// The GIFT for a task the requester cancelled. The handler's last progress
+/// event, as a WRAP, stands in for the partial result.
+fn build_cancelled_gift(progress: Option<serde_json::Value>) -> GiftPayload {
+    let mut res = serde_json::json!({"err": "Cancelled by the requester"});
+    if let Some(event) = progress {
+        res["wrap"] = serde_json::json!(protocol::build_wrap_payload(&event));
+    }
+    GiftPayload { ok: false, cncl: true, ..build_gift_payload(res) }
+}
+
+/// Serves one incoming conversation. Returns the task to run afterwards if
+/// it was granted with a ticket.
+async fn handle_connection<'a, S>(stream: &mut S, peer_addr: SocketAddr, shared: &'a Shared) -> Result<Option<Deferred<'a>>>
 where
     S: AsyncReadExt + AsyncWriteExt + Unpin,
 {
+    let settings = shared.settings();
+    let config = &settings.config;
+    let identity = &shared.identity;
     let my_id = &config.agent.id;
 
-    let knock_bytes = protocol::receive_framed_message(stream).await?;
+    let knock_bytes = session::within(
+        config.timeouts.welcome(),
+        Stage::Knock,
+        protocol::receive_framed_message(stream),
+    )
+    .await?;
 
-    if let Err(e) = protocol::validate_size(Stage::Knock.to_u8(), knock_bytes.len()) {
-        return Err(e);
-    }
+    protocol::validate_size(Stage::Knock.to_u8(), knock_bytes.len())?;
 
     let knock = protocol::decode_message(&knock_bytes)?;
 
     if knock.stage != Stage::Knock.to_u8() {
         return Err(anyhow!("Expected KNOCK, got stage {}", knock.stage));
     }
+    let knock_payload: KnockPayload = knock.parse()?;
 
     let peer_id = &knock.from;
-
-    {
-        let blocklist = blocklist.lock().unwrap();
-        if blocklist.is_blocked(peer_id) {
-            return Err(anyhow!("Agent {} is blocked", peer_id));
-        }
-    }
-
-    {
-        let mut rate_limiter = rate_limiter.lock().unwrap();
-        if let Err(e) = rate_limiter.check_knock(peer_id) {
-            let mut blocklist = blocklist.lock().unwrap();
-            blocklist.add_violation(peer_id, BlockReason::RateLimitViolations);
-            return Err(e);
-        }
-
-        if let Err(e) = rate_limiter.check_bytes(peer_id, knock_bytes.len() as u64) {
-            let mut blocklist = blocklist.lock().unwrap();
-            blocklist.add_violation(peer_id, BlockReason::RateLimitViolations);
-            return Err(e);
-        }
-    }
+    let entry = shared.keyring.lock().unwrap().get_entry(peer_id).cloned();
+    let peer_static = entry.as_ref().map(|e| e.public_key);
+    let ticket = knock_payload.tkt.as_deref();
+    let probe = capabilities::is_probe(&knock_payload);
+    let conversation = hex::encode(rand::random::<[u8; 8]>());
+    let registration = shared.conversations.register(&conversation, peer_id, peer_addr);
+    let peer = Peer {
+        id: peer_id,
+        fp: peer_static.as_ref().map(crypto::key_fingerprint),
+        addr: peer_addr,
+        registration: &registration,
+        conversation,
+        reachable: entry.as_ref().is_some_and(|e| !e.endpoints.is_empty()),
+        labels: entry.map(|e| e.labels).unwrap_or_default(),
+        category: knock_payload.c,
+        priority: knock_payload.pri,
+        router: &settings.router,
+        policy: &settings.policy,
+        approvals: &shared.approvals,
+        approval: &config.approval,
+        limits: config.limits.for_peer(peer_id),
+        scheduler: &shared.scheduler,
+        blocklist: &shared.blocklist,
+        rate_limiter: &shared.rate_limiter,
+    };
 
     let mut counter = knock.counter;
 
-    let peer_eph_val = knock
-        .payload
-        .get("eph_key")
-        .ok_or_else(|| anyhow!("Missing eph_key in KNOCK"))?;
+    let peer_static = peer_static.ok_or_else(|| anyhow!("Agent {} is not in the keyring", peer_id))?;
 
-    let peer_eph_bytes: Vec<u8> = serde_json::from_value(peer_eph_val.clone())
-        .map_err(|_| anyhow!("Invalid eph_key format"))?;
-
-    if peer_eph_bytes.len() != 32 {
-        return Err(anyhow!("Invalid eph_key length"));
+    let peer_eph_array = protocol::key_field(&knock_payload.eph_key, "eph_key")?;
+    let expected_auth = crypto::knock_auth(
+        identity,
+        &peer_static,
+        &peer_eph_array,
+        knock.timestamp,
+        peer_id,
+        my_id,
+    )?;
+    // Not a violation: `from` is unproven here, so charging it would let
+    // anyone get the real peer blocked.
+    if let Err(e) = crypto::verify_auth(&expected_auth, &knock_payload.auth) {
+        eprintln!("KNOCK claiming to be {} from {} failed authentication", peer_id, peer_addr);
+        return Err(e);
+    }
+    // Before anything is charged to the peer or a handler sees the KNOCK.
+    let now = protocol::current_timestamp();
+    if let Err(e) = shared.knocks.check(peer_id, &peer_eph_array, knock.timestamp, now) {
+        eprintln!("Rejected KNOCK from {} ({}): {}", peer_id, peer_addr, e.msg);
+        return Err(e.into());
     }
-
-    let mut peer_eph_array = [0u8; 32];
-    peer_eph_array.copy_from_slice(&peer_eph_bytes);
 
     let (my_eph_secret, my_eph_public) = crypto::generate_ephemeral_key();
 
-    let mut session_key =
-        crypto::derive_session_key(&my_eph_secret, &peer_eph_array, peer_id, my_id)?;
+    let mut session_key = crypto::derive_session_key(
+        Role::Responder,
+        identity,
+        &my_eph_secret,
+        &peer_static,
+        &peer_eph_array,
+        peer_id,
+        my_id,
+    )?;
 
     drop(my_eph_secret);
 
-    let knock_decision = call_openclaw(&config.openclaw.path, &knock)?;
-    let should_accept = knock_decision
-        .get("accept")
-        .and_then(|v| v.as_bool())
-        .unwrap_or(true);
+    // Blocked and throttled peers still get a WELCOME so they can tell a
+    // decline from a network failure (spec §12.5, §13.3).
+    let mut welcome_payload = if is_blocked(&shared.blocklist, peer_id) {
+        WelcomePayload::decline(RejectReason::Blocked, "You are blocked")
+    } else if let Err(limited) = peer.admit_knock(knock_bytes.len()) {
+        WelcomePayload::decline(RejectReason::RateLimited, &limited.msg).with_retry(limited.retry_after)
+    } else if let Some(ticket) = ticket {
+        // The GIFT for one of our own deferred wishes, so no policy or
+        // handler is asked.
+        if shared.outbox.expects_gift(ticket, peer_id) {
+            WelcomePayload::new(Status::Accept)
+        } else {
+            WelcomePayload::decline(RejectReason::TrustIssue, "Unknown or expired ticket")
+        }
+    } else if probe {
+        // Answered by the daemon from the advertised actions, without
+        // taking an execution slot.
+        WelcomePayload::new(Status::Accept)
+    } else if let Err(retry) = shared.scheduler.admit(peer.category) {
+        let (running, queued) = shared.scheduler.load();
+        eprintln!("At capacity ({} running, {} queued); {} can retry in {}s", running, queued, peer_id, retry);
+        WelcomePayload::busy("At capacity, try again later", retry)
+    } else {
+        tokio::select! {
+            payload = knock_response(&peer, &knock, config.timeouts.welcome()) => payload,
+            error = peer.registration.cancelled() => {
+                // There is no session to send an ERROR in yet, so a
+                // recoverable cancellation is answered as busy.
+                if !error.recov {
+                    return Err(error.into());
+                }
+                WelcomePayload {
+                    retry: error.det.as_ref().and_then(|d| d.get("retry")).and_then(|r| r.as_u64()),
+                    ..WelcomePayload::decline(RejectReason::Busy, &error.msg)
+                }
+            }
+        }
+    };
+    let should_accept = welcome_payload.st == Status::Accept;
+    let advertised = if should_accept && ticket.is_none() {
+        Some(settings.capabilities(&peer.request(Phase::Capabilities, 0, &knock)).await)
+    } else {
+        None
+    };
+    if let Some(advertised) = advertised.filter(|a| !a.actions.is_empty()) {
+        welcome_payload.cap = Some(advertised.digest());
+    }
 
     counter += 1;
     let timestamp = protocol::current_timestamp();
 
-    let mut welcome_payload = HashMap::new();
-    welcome_payload.insert(
-        "eph_key".to_string(),
-        serde_json::json!(my_eph_public.as_bytes().to_vec()),
-    );
-
-    if should_accept {
-        welcome_payload.insert("st".to_string(), serde_json::json!(1));
-        welcome_payload.insert("msg".to_string(), serde_json::json!("Welcome! Please share your wish."));
-    } else {
-        welcome_payload.insert("st".to_string(), serde_json::json!(2));
-        let reason = knock_decision
-            .get("reason")
-            .and_then(|v| v.as_str())
-            .unwrap_or("busy");
-        welcome_payload.insert("r".to_string(), serde_json::json!(reason));
-    }
+    welcome_payload.eph_key = my_eph_public.as_bytes().to_vec();
+    welcome_payload.auth = crypto::welcome_auth(&session_key, my_eph_public.as_bytes())?.to_vec();
 
     let welcome = Message {
         stage: Stage::Welcome.to_u8(),
@@ -343,236 +827,784 @@ where
         timestamp,
         from: my_id.clone(),
         to: peer_id.clone(),
-        payload: welcome_payload,
+        payload: welcome_payload.to_map(),
     };
 
+    let mut session = Session::new(session_key, counter, my_id, peer_id);
+    crypto::zeroize_key(&mut session_key);
+
     let encoded_welcome = protocol::encode_message(&welcome)?;
     protocol::send_framed_message(stream, &encoded_welcome).await?;
 
     if !should_accept {
-        let _ = receive_encrypted_message(stream, &session_key, &mut counter, peer_id, my_id).await;
-        crypto::zeroize_key(&mut session_key);
-        return Ok(());
+        let _ = session.receive_within(stream, config.timeouts.grant(), Stage::Thank).await;
+        return Ok(None);
+    }
+
+    if let Some(ticket) = ticket {
+        peer.registration.set_stage("gift");
+        let received = tokio::select! {
+            received = receive_gift(stream, &mut session, config, &peer, &shared.outbox, ticket) => received,
+            error = peer.registration.cancelled() => Err(error.into()),
+        };
+        return match received {
+            Ok(()) => Ok(None),
+            Err(e) => Err(session.fail(stream, e, ErrorCode::InternalError).await),
+        };
     }
 
-    let (wish, wish_size) = receive_encrypted_message(stream, &session_key, &mut counter, peer_id, my_id).await?;
+    if let Some(advertised) = advertised.filter(|_| probe) {
+        peer.registration.set_stage("wish");
+        let served = tokio::select! {
+            served = serve_probe(stream, &mut session, config, &peer, advertised) => served,
+            error = peer.registration.cancelled() => Err(error.into()),
+        };
+        return match served {
+            Ok(()) => Ok(None),
+            Err(e) => Err(session.fail(stream, e, ErrorCode::InternalError).await),
+        };
+    }
 
-    {
-        let mut rate_limiter = rate_limiter.lock().unwrap();
-        if let Err(e) = rate_limiter.check_bytes(peer_id, wish_size as u64) {
-            let mut blocklist = blocklist.lock().unwrap();
-            blocklist.add_violation(peer_id, BlockReason::RateLimitViolations);
-            crypto::zeroize_key(&mut session_key);
-            return Err(e);
-        }
+    peer.registration.set_stage("wish");
+    let served = tokio::select! {
+        served = serve_wish(stream, &mut session, config, &peer, advertised) => served,
+        error = peer.registration.cancelled() => Err(error.into()),
+    };
+    let (ticket, expires, est, request) = match served {
+        Ok(Some(deferral)) => deferral,
+        Ok(None) => return Ok(None),
+        Err(e) => return Err(session.fail(stream, e, ErrorCode::InternalError).await),
+    };
+    let (id, category, priority, labels) = (peer.id.to_string(), peer.category, peer.priority, peer.labels);
+    Ok(Some(Deferred {
+        registration,
+        ticket,
+        expires,
+        settings: settings.clone(),
+        peer: id,
+        category,
+        priority,
+        est,
+        labels,
+        request,
+    }))
+}
+
+/// Takes the GIFT for one of our deferred wishes, announced by `ticket` in
+/// the KNOCK, and stores it with the wish in the outbox.
+async fn receive_gift<S>(
+    stream: &mut S,
+    session: &mut Session,
+    config: &Config,
+    peer: &Peer<'_>,
+    outbox: &Outbox,
+    ticket: &str,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (gift, gift_size) = session.receive_within(stream, config.timeouts.grant(), Stage::Gift).await?;
+    peer.charge(session, gift_size)?;
+
+    let gift_payload: GiftPayload = gift.parse()?;
+    if gift_payload.tkt.as_deref() != Some(ticket) {
+        return Err(ProtocolError::new(ErrorCode::InvalidFormat, "GIFT does not carry the KNOCK's ticket").into());
     }
+    outbox.receive_gift(ticket, peer.id, serde_json::json!(gift.payload))?;
+
+    session.send(stream, &ThankPayload::new(ThankContext::Success, false, None)).await?;
+    Ok(())
+}
 
-    if wish.stage != Stage::Wish.to_u8() {
-        return Err(anyhow!("Expected WISH, got stage {}", wish.stage));
+/// Answers a capability probe's WISH with the full list of advertised
+/// actions as its GIFT.
+async fn serve_probe<S>(
+    stream: &mut S,
+    session: &mut Session,
+    config: &Config,
+    peer: &Peer<'_>,
+    advertised: &Capabilities,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (wish, wish_size) = session.receive_within(stream, config.timeouts.welcome(), Stage::Wish).await?;
+    peer.charge(session, wish_size)?;
+
+    let wish = peer.parse_wish(&wish)?;
+    if wish.action() != Some(capabilities::PROBE) {
+        return Err(ProtocolError::new(ErrorCode::InvalidFormat, "A capability probe must ask for capabilities").into());
     }
 
-    let task_decision = call_openclaw(&config.openclaw.path, &wish)?;
-    let should_grant = task_decision
-        .get("accept")
-        .and_then(|v| v.as_bool())
-        .unwrap_or(true);
+    session.send(stream, &GrantPayload { est_t: Some(0), ..GrantPayload::new(Status::Accept) }).await?;
+    session.send(stream, &build_gift_payload(serde_json::json!(advertised))).await?;
+    eprintln!("Sent {} capabilities to {}", advertised.actions.len(), peer.id);
 
-    counter += 1;
-    let mut grant_payload = HashMap::new();
-
-    if should_grant {
-        grant_payload.insert("st".to_string(), serde_json::json!(1));
-        let est_time = task_decision
-            .get("estimated_time")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(60);
-        grant_payload.insert("est_t".to_string(), serde_json::json!(est_time));
+    peer.registration.set_stage("thank");
+    let (_, thank_size) = session.receive_within(stream, config.timeouts.grant(), Stage::Thank).await?;
+    let _ = peer.charge(session, thank_size);
+    Ok(())
+}
+
+fn is_blocked(blocklist: &Mutex<Blocklist>, agent_id: &str) -> bool {
+    let mut blocklist = blocklist.lock().unwrap();
+    if let Err(e) = blocklist.refresh() {
+        eprintln!("Warning: Could not reload blocklist: {}", e);
+    }
+    blocklist.is_blocked(agent_id)
+}
+
+/// Decides on a KNOCK by policy, by an operator or by asking the handler,
+/// and builds the WELCOME status. `deadline` is how long the requester
+/// waits for it.
+async fn knock_response(peer: &Peer<'_>, knock: &Message, deadline: Duration) -> WelcomePayload {
+    // A handler that cannot decide is treated as a decline: the requester
+    // has no session key until WELCOME, so an ERROR could not be read.
+    let verdict = peer.judge(None);
+    let decided = match verdict.decision {
+        Decision::Handler => {
+            let handler = peer.handler(None);
+            let request = peer.request(Phase::Knock, 0, knock);
+            match peer.approval(handler, &request, deadline).await {
+                Approval::Resolved(Resolution::Approved) => call_openclaw(handler, &request, None).await,
+                Approval::Resolved(Resolution::Denied(reason)) => Ok(KnockResponse {
+                    accept: false,
+                    reason: Some(reason),
+                    msg: Some("Declined by the operator".to_string()),
+                }),
+                Approval::Waiting(retry) => return WelcomePayload::busy("Waiting for approval", retry),
+            }
+        }
+        decision => Ok(KnockResponse {
+            accept: decision == Decision::Accept,
+            reason: verdict.reason,
+            msg: verdict.msg,
+        }),
+    };
+    let decision = match decided {
+        Ok(decision) => decision,
+        Err(e) => {
+            eprintln!("KNOCK handler failed for {}: {}", knock.from, e);
+            return WelcomePayload::decline(RejectReason::ResourceUnavailable, "Unable to consider requests right now");
+        }
+    };
+
+    if decision.accept {
+        let msg = decision.msg.unwrap_or_else(|| "Welcome! Please share your wish.".to_string());
+        WelcomePayload { msg: Some(msg), ..WelcomePayload::new(Status::Accept) }
     } else {
-        grant_payload.insert("st".to_string(), serde_json::json!(2));
-        let reason = task_decision
-            .get("reason")
-            .and_then(|v| v.as_str())
-            .unwrap_or("excessive_request");
-        grant_payload.insert("r".to_string(), serde_json::json!(reason));
-    }
-
-    send_encrypted_message(
-        stream,
-        Stage::Grant,
-        &session_key,
-        counter,
-        my_id,
-        peer_id,
-    grant_payload,
-    )
-    .await?;
+        WelcomePayload {
+            r: Some(Reason::named(decision.reason.as_deref().unwrap_or("busy"))),
+            msg: decision.msg,
+            ..WelcomePayload::new(Status::Decline)
+        }
+    }
+}
 
-    if !should_grant {
-        let _ = receive_encrypted_message(stream, &session_key, &mut counter, peer_id, my_id).await;
-        crypto::zeroize_key(&mut session_key);
-        return Ok(());
+/// The requester of the current connection, with the shared state used to
+/// account for what it sends.
+struct Peer<'a> {
+    id: &'a str,
+    /// Public key fingerprint, if the peer is in the keyring.
+    fp: Option<[u8; 32]>,
+    addr: SocketAddr,
+    /// Random ID passed to the handler on every call for this connection.
+    conversation: String,
+    registration: &'a Registration<'a>,
+    /// Keyring labels, for routing.
+    labels: Vec<String>,
+    /// KNOCK category, for routing.
+    category: Category,
+    /// Whether the keyring has endpoints to deliver a deferred GIFT to.
+    reachable: bool,
+    /// KNOCK priority, for the policy and the scheduler.
+    priority: Priority,
+    router: &'a Router,
+    policy: &'a Policy,
+    approvals: &'a ApprovalQueue,
+    approval: &'a ApprovalConfig,
+    limits: Limits,
+    scheduler: &'a Scheduler,
+    blocklist: &'a Mutex<Blocklist>,
+    rate_limiter: &'a Mutex<RateLimiter>,
+}
+
+impl Peer<'_> {
+    /// The handler for this conversation once `action` (the WISH
+    /// `task.act`) is known, or for the KNOCK with `None`.
+    fn handler(&self, action: Option<&str>) -> &Handler {
+        let target = Target {
+            category: Some(self.category),
+            action,
+            peer: self.id,
+            labels: &self.labels,
+        };
+        self.router.route(&target).1
     }
 
-    let task_result = call_openclaw(&config.openclaw.path, &wish)?;
+    /// The handler envelope for `msg`.
+    fn request(&self, phase: Phase, round: u8, msg: &Message) -> HandlerRequest {
+        HandlerRequest {
+            v: envelope::ENVELOPE_VERSION,
+            phase,
+            conv: self.conversation.clone(),
+            peer: PeerInfo {
+                id: self.id.to_string(),
+                fp: self.fp.map(hex::encode),
+                addr: Some(self.addr.to_string()),
+            },
+            round,
+            msg: msg.clone(),
+        }
+    }
 
-    counter += 1;
-    let mut gift_payload = HashMap::new();
-    gift_payload.insert("ok".to_string(), serde_json::json!(true));
-    gift_payload.insert("res".to_string(), serde_json::json!(task_result));
-
-    let mut meta = HashMap::new();
-    meta.insert("exec_t", serde_json::json!(1));
-    gift_payload.insert("meta".to_string(), serde_json::json!(meta));
-
-    send_encrypted_message(
-        stream,
-        Stage::Gift,
-        &session_key,
-        counter,
-        my_id,
-        peer_id,
-        gift_payload,
-    )
-    .await?;
+    /// Runs the policy on the KNOCK (`wish` None) or a WISH, given as the
+    /// message and its payload, and logs the rule that fired.
+    fn judge(&self, wish: Option<(&Message, &WishPayload)>) -> Verdict {
+        let size = wish.map(|(w, _)| protocol::encode_message(w).map(|b| b.len()).unwrap_or(usize::MAX));
+        let subject = Subject {
+            peer: self.id,
+            labels: &self.labels,
+            category: Some(self.category),
+            priority: Some(self.priority),
+            // Only the WISH's own offer counts, whatever the KNOCK named.
+            offer: wish.and_then(|(_, w)| w.offer_type()),
+            size,
+            time: chrono::Local::now().time(),
+        };
+        let verdict = self.policy.evaluate(&subject);
+        let what = if wish.is_some() { "WISH" } else { "KNOCK" };
+        eprintln!("Policy: {} from {}: {}", what, self.id, verdict);
+        verdict
+    }
 
-    let (thank, thank_size) = receive_encrypted_message(stream, &session_key, &mut counter, peer_id, my_id).await?;
+    /// Decodes a WISH. A malformed one counts against the peer, as a task
+    /// that fails its action's schema does.
+    fn parse_wish(&self, wish: &Message) -> Result<WishPayload> {
+        let parsed = wish.parse();
+        if let Err(e) = &parsed {
+            if wish.stage == Stage::Wish.to_u8() {
+                eprintln!("Malformed WISH from {}: {}", self.id, e);
+                self.violation(BlockReason::MalformedMessages);
+            }
+        }
+        parsed
+    }
 
-    {
-        let mut rate_limiter = rate_limiter.lock().unwrap();
-        let _ = rate_limiter.check_bytes(peer_id, thank_size as u64);
+    /// Holds `request` for an operator if `handler` wants approval for its
+    /// phase, until shortly before the requester's `deadline`.
+    async fn approval(&self, handler: &Handler, request: &HandlerRequest, deadline: Duration) -> Approval {
+        if !handler.needs_approval(request.phase) {
+            return Approval::Resolved(Resolution::Approved);
+        }
+        self.approvals
+            .wait(request, deadline.saturating_sub(APPROVAL_MARGIN), self.approval)
+            .await
     }
 
-    if thank.stage != Stage::Thank.to_u8() {
-        eprintln!("Warning: Expected THANK, got stage {}", thank.stage);
+    /// Counts a KNOCK of `bytes` against the peer's limits.
+    fn admit_knock(&self, bytes: usize) -> Result<(), RateLimited> {
+        let admitted = self
+            .rate_limiter
+            .lock()
+            .unwrap()
+            .check_knock(self.id, &self.limits, bytes as u64);
+        if admitted.is_err() {
+            self.violation(BlockReason::RateLimitViolations);
+        }
+        admitted
     }
 
-    crypto::zeroize_key(&mut session_key);
+    /// Counts a received message of `bytes` against the peer's quotas and
+    /// the conversation caps.
+    fn charge(&self, session: &Session, bytes: usize) -> Result<()> {
+        let (total_bytes, total_messages) = session.traffic();
+        self.limits
+            .check_conversation(total_bytes, total_messages)
+            .map_err(|limited| ProtocolError::new(ErrorCode::ResourceExhausted, limited.msg))?;
+
+        let allowed = self
+            .rate_limiter
+            .lock()
+            .unwrap()
+            .check_message(self.id, &self.limits, bytes as u64);
+        if let Err(limited) = allowed {
+            self.violation(BlockReason::RateLimitViolations);
+            return Err(ProtocolError::new(ErrorCode::ResourceExhausted, limited.msg)
+                .with_details(serde_json::json!({"retry": limited.retry_after}))
+                .into());
+        }
+        Ok(())
+    }
 
-    Ok(())
+    fn violation(&self, reason: BlockReason) {
+        let mut blocklist = self.blocklist.lock().unwrap();
+        match blocklist.add_violation(self.id, reason, self.fp) {
+            Ok(true) => eprintln!("Blocked {}: {}", self.id, reason.name()),
+            Ok(false) => {}
+            Err(e) => eprintln!("Warning: Could not update blocklist: {}", e),
+        }
+    }
 }
 
-fn call_openclaw(path: &str, message: &Message) -> Result<HashMap<String, serde_json::Value>> {
-    let input_json = serde_json::to_string(message)?;
+/// A ticket, its expiry, the GRANT `est_t` and the execute request of a
+/// deferred task.
+type Deferral = (String, u64, u64, HandlerRequest);
+
+/// Runs the encrypted part of an accepted conversation: WISH, negotiation,
+/// GRANT, execution and GIFT, up to the requester's THANK. A task granted
+/// with a ticket is returned instead of executed. WISHes are checked
+/// against the `advertised` actions, if any, before the policy or handler
+/// sees them.
+async fn serve_wish<S>(
+    stream: &mut S,
+    session: &mut Session,
+    config: &Config,
+    peer: &Peer<'_>,
+    advertised: Option<&Capabilities>,
+) -> Result<Option<Deferral>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let timeouts = &config.timeouts;
+    let (mut wish, wish_size) = session.receive_within(stream, timeouts.welcome(), Stage::Wish).await?;
+    peer.charge(session, wish_size)?;
+    let mut wish_payload = peer.parse_wish(&wish)?;
+
+    let mut rev = 0u8;
+    let mut offered: Option<CounterProposal> = None;
+
+    let (should_grant, mut grant_payload) = loop {
+        check_revision(&wish_payload, rev, offered.as_ref())?;
+        if let Some(decline) = check_wish(peer, advertised, &wish_payload)? {
+            break (false, decline);
+        }
+        peer.registration.set_stage("evaluate");
+
+        let verdict = peer.judge(Some((&wish, &wish_payload)));
+        let mut retry = None;
+        let decision: EvaluateResponse = match verdict.decision {
+            Decision::Handler => {
+                let handler = peer.handler(wish_payload.action());
+                let request = peer.request(Phase::Evaluate, rev, &wish);
+                match peer.approval(handler, &request, timeouts.grant()).await {
+                    Approval::Resolved(Resolution::Approved) => call_openclaw(handler, &request, None).await?,
+                    Approval::Resolved(Resolution::Denied(reason)) => EvaluateResponse {
+                        accept: false,
+                        reason: Some(reason),
+                        msg: Some("Declined by the operator".to_string()),
+                        ..EvaluateResponse::default()
+                    },
+                    Approval::Waiting(after) => {
+                        retry = Some(after);
+                        EvaluateResponse {
+                            accept: false,
+                            reason: Some(RejectReason::Busy.name().to_string()),
+                            msg: Some("Waiting for approval".to_string()),
+                            ..EvaluateResponse::default()
+                        }
+                    }
+                }
+            }
+            decision => EvaluateResponse {
+                accept: decision == Decision::Accept,
+                reason: verdict.reason,
+                msg: verdict.msg,
+                ..EvaluateResponse::default()
+            },
+        };
+        if let Some(proposal) = decision.counter {
+            if proposal.opts.is_empty() {
+                return Err(anyhow!("Counter proposal from OpenClaw has no options"));
+            }
+            if rev < protocol::MAX_NEGOTIATION_ROUNDS {
+                let grant_payload = GrantPayload {
+                    r: decision.reason.as_deref().map(Reason::named),
+                    msg: decision.msg,
+                    counter: Some(proposal.clone()),
+                    ..GrantPayload::new(Status::Negotiate)
+                };
+                session.send(stream, &grant_payload).await?;
+                peer.registration.set_stage("negotiate");
+
+                let (revised, revised_size) =
+                    session.receive_within(stream, timeouts.grant(), Stage::Wish).await?;
+                peer.charge(session, revised_size)?;
+
+                if revised.stage == Stage::Thank.to_u8() {
+                    notify_thank(peer, &wish_payload, rev, &revised).await;
+                    return Ok(None);
+                }
+                if revised.stage != Stage::Wish.to_u8() {
+                    return Err(session::unexpected_stage("revised WISH", revised.stage));
+                }
+
+                wish_payload = peer.parse_wish(&revised)?;
+                wish = revised;
+                rev += 1;
+                offered = Some(proposal);
+                continue;
+            }
+
+            // Spec §8.2: after the last round the responder must accept or decline.
+            break (
+                false,
+                GrantPayload::decline(RejectReason::ExcessiveRequest, "Negotiation round limit reached"),
+            );
+        }
+
+        let grant_payload = if decision.accept {
+            GrantPayload {
+                msg: decision.msg,
+                est_t: Some(decision.estimated_time.unwrap_or(60)),
+                est_c: decision.estimated_cost,
+                ..GrantPayload::new(Status::Accept)
+            }
+        } else {
+            GrantPayload {
+                r: Some(Reason::named(decision.reason.as_deref().unwrap_or("excessive_request"))),
+                msg: decision.msg,
+                retry,
+                ..GrantPayload::new(Status::Decline)
+            }
+        };
 
-    let mut child = Command::new(path)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
+        break (decision.accept, grant_payload);
+    };
 
-    {
-        let stdin = child.stdin.as_mut().ok_or_else(|| anyhow!("Failed to open stdin"))?;
-        stdin.write_all(input_json.as_bytes())?;
+    let est_time = grant_payload.est_t.unwrap_or(0);
+    let wants_ticket = wish_payload.dfr;
+    let defer = should_grant && wants_ticket && peer.reachable && config.defer.after > 0 && est_time >= config.defer.after;
+    let mut ticket = None;
+    if defer {
+        let id = hex::encode(rand::random::<[u8; 8]>());
+        let expires = (protocol::current_timestamp() as u64).saturating_add(est_time).saturating_add(config.defer.expire);
+        grant_payload.tkt = Some(id.clone());
+        grant_payload.exp = Some(expires);
+        ticket = Some((id, expires));
     }
 
-    let output = child.wait_with_output()?;
+    session.send(stream, &grant_payload).await?;
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(anyhow!("OpenClaw failed: {}", stderr));
+    if let Some((id, expires)) = ticket {
+        peer.registration.set_stage("thank");
+        let (thank, thank_size) = session.receive_within(stream, timeouts.grant(), Stage::Thank).await?;
+        let _ = peer.charge(session, thank_size);
+        if thank.stage != Stage::Thank.to_u8() {
+            return Err(session::unexpected_stage("THANK", thank.stage));
+        }
+        eprintln!("Deferred the task for {} as ticket {}", peer.id, id);
+        return Ok(Some((id, expires, est_time, peer.request(Phase::Execute, rev, &wish))));
     }
 
-    let output_str = String::from_utf8(output.stdout)?;
+    if !should_grant {
+        peer.registration.set_stage("thank");
+        if let Ok((thank, _)) = session.receive_within(stream, timeouts.grant(), Stage::Thank).await {
+            notify_thank(peer, &wish_payload, rev, &thank).await;
+        }
+        return Ok(None);
+    }
+
+    peer.registration.set_stage("execute");
+    let request = peer.request(Phase::Execute, rev, &wish);
+    let task = scheduler::Task {
+        priority: peer.priority,
+        category: peer.category,
+        est: est_time,
+    };
+    let handler = peer.handler(wish_payload.action());
+    let execution = execute(stream, session, handler, &request, timeouts.grant(), peer.scheduler, task)
+        .await
+        .map_err(|e| ProtocolError::from_anyhow(&e, ErrorCode::TaskFailed))?;
+
+    let task_result = match execution {
+        Execution::Finished(result) => result,
+        Execution::Cancelled { thank, size, progress } => {
+            let _ = peer.charge(session, size);
+            eprintln!("{} cancelled the task", peer.id);
+            session.send(stream, &build_cancelled_gift(progress)).await?;
+            notify_thank(peer, &wish_payload, rev, &thank).await;
+            return Ok(None);
+        }
+    };
+
+    session.send(stream, &build_gift_payload(task_result)).await?;
+    peer.registration.set_stage("thank");
 
-    if output_str.trim().is_empty() {
-        let mut result = HashMap::new();
-        result.insert("accept".to_string(), serde_json::json!(true));
-        return Ok(result);
+    let (thank, thank_size) = session.receive_within(stream, timeouts.grant(), Stage::Thank).await?;
+    let _ = peer.charge(session, thank_size);
+
+    if thank.stage != Stage::Thank.to_u8() {
+        eprintln!("Warning: Expected THANK, got stage {}", thank.stage);
+    } else {
+        notify_thank(peer, &wish_payload, rev, &thank).await;
     }
 
-    serde_json::from_str(&output_str)
-        .map_err(|e| anyhow!("Failed to parse OpenClaw response: {}", e))
+    Ok(None)
 }
 
-async fn send_encrypted_message<W>(
-    writer: &mut W,
-    stage: Stage,
-    session_key: &[u8; 32],
-    counter: u32,
-    from: &str,
-    to: &str,
-    payload: HashMap<String, serde_json::Value>,
-) -> Result<()>
+/// Checks a WISH against the advertised actions. An action we do not
+/// offer, or a WISH over its size limit, gets a `capability_mismatch`
+/// decline; a task field that does not match the action's schema is an
+/// `invalid_format` error naming the field, and counts as a malformed
+/// message.
+fn check_wish(
+    peer: &Peer<'_>,
+    advertised: Option<&Capabilities>,
+    wish: &WishPayload,
+) -> Result<Option<GrantPayload>> {
+    let Some(advertised) = advertised.filter(|a| !a.actions.is_empty()) else {
+        return Ok(None);
+    };
+    match advertised.check(wish) {
+        Ok(()) => Ok(None),
+        Err(Mismatch::Unsupported(msg)) => {
+            eprintln!("Declined WISH from {}: {}", peer.id, msg);
+            Ok(Some(GrantPayload::decline(RejectReason::CapabilityMismatch, &msg)))
+        }
+        Err(Mismatch::Invalid(invalid)) => {
+            eprintln!("Malformed WISH from {}: {}", peer.id, invalid);
+            peer.violation(BlockReason::MalformedMessages);
+            Err(ProtocolError::new(ErrorCode::InvalidFormat, invalid.to_string())
+                .with_details(serde_json::json!({"field": invalid.field}))
+                .into())
+        }
+    }
+}
+
+/// How a granted task ended.
+enum Execution {
+    Finished(serde_json::Value),
+    /// The requester cancelled with `thank`, `size` bytes. `progress` is the
+    /// handler's last progress event.
+    Cancelled { thank: Message, size: usize, progress: Option<serde_json::Value> },
+}
+
+/// Runs the granted task once `scheduler` has a slot for it, relaying its
+/// place in the queue and then the handler's progress events as WRAP, and
+/// returns the GIFT result. Events arriving faster than `MIN_WRAP_INTERVAL`
+/// are coalesced and only the latest is sent. A cancel from the requester,
+/// or its hanging up, stops the handler.
+async fn execute<S>(
+    stream: &mut S,
+    session: &mut Session,
+    handler: &Handler,
+    request: &HandlerRequest,
+    limit: Duration,
+    scheduler: &Scheduler,
+    task: scheduler::Task,
+) -> Result<Execution>
 where
-    W: AsyncWriteExt + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
 {
-    let timestamp = protocol::current_timestamp();
-
-    let message = Message {
-        stage: stage.to_u8(),
-        counter,
-        timestamp,
-        from: from.to_string(),
-        to: to.to_string(),
-        payload,
+    let (progress, mut events) = mpsc::unbounded_channel();
+    let run = async {
+        let _slot = scheduler.acquire(task, Some(&progress)).await;
+        call_openclaw::<ExecuteResponse>(handler, request, Some(&progress)).await
+    };
+    tokio::pin!(run);
+
+    let mut queued: Option<serde_json::Value> = None;
+    let mut latest: Option<serde_json::Value> = None;
+    let mut next_wrap = tokio::time::Instant::now();
+    let response = loop {
+        tokio::select! {
+            result = &mut run => break result?,
+            Some(event) = events.recv() => {
+                latest = Some(event.clone());
+                queued = Some(event);
+            }
+            first = session::next_byte(stream) => {
+                let receive = session.receive_rest(first?, stream);
+                let (thank, size) = session::within(limit, Stage::Thank, receive).await?;
+                if !protocol::is_cancel(&thank) {
+                    return Err(session::unexpected_stage("THANK", thank.stage));
+                }
+                return Ok(Execution::Cancelled { thank, size, progress: latest });
+            }
+            _ = tokio::time::sleep_until(next_wrap), if queued.is_some() => {
+                let wrap_payload = protocol::build_wrap_payload(&queued.take().unwrap_or_default());
+                if !wrap_payload.is_empty() {
+                    session.send(stream, &wrap_payload).await?;
+                    next_wrap = tokio::time::Instant::now() + MIN_WRAP_INTERVAL;
+                }
+            }
+        }
     };
 
-    let plaintext = protocol::encode_message(&message)?;
-    let aad = protocol::build_aad(PROTOCOL_VERSION, from, to);
+    response.result().map(Execution::Finished)
+}
 
-    let encrypted = crypto::encrypt_message(session_key, counter, timestamp, &plaintext, &aad)?;
+/// Passes the requester's closing THANK on to the handler of `wish`. Its
+/// answer, and any failure, only matter to the handler.
+async fn notify_thank(peer: &Peer<'_>, wish: &WishPayload, round: u8, thank: &Message) {
+    let request = peer.request(Phase::Thank, round, thank);
+    if let Err(e) = peer.handler(wish.action()).call(&serde_json::to_value(request).unwrap_or_default(), None).await {
+        eprintln!("THANK handler failed for {}: {}", peer.id, e);
+    }
+}
 
-    let mut envelope = Vec::new();
-    envelope.extend(counter.to_be_bytes());
-    envelope.extend(timestamp.to_be_bytes());
-    envelope.extend(encrypted);
+/// Checks that a WISH carries the revision we expect and, for revised
+/// WISHes, selects one of the options we offered.
+fn check_revision(wish: &WishPayload, expected_rev: u8, offered: Option<&CounterProposal>) -> Result<(), ProtocolError> {
+    let invalid = |msg: String| ProtocolError::new(ErrorCode::InvalidFormat, msg);
+    if wish.rev != expected_rev {
+        return Err(invalid(format!("Expected WISH revision {}, got {}", expected_rev, wish.rev)));
+    }
 
-    protocol::send_framed_message(writer, &envelope).await?;
+    if let Some(proposal) = offered {
+        let selected = wish.sel_opt.ok_or_else(|| invalid("Revised WISH is missing sel_opt".to_string()))?;
+        if !proposal.opts.iter().any(|o| o.id == selected) {
+            return Err(invalid(format!("Revised WISH selected unknown option {}", selected)));
+        }
+    }
     Ok(())
 }
 
-async fn receive_encrypted_message<R>(
-    reader: &mut R,
-    session_key: &[u8; 32],
-    last_counter: &mut u32,
-    expected_from: &str,
-    expected_to: &str,
-) -> Result<(Message, usize)>
+/// Calls the handler with `request` and decodes its answer for that phase.
+/// Empty output gives the phase's default answer.
+async fn call_openclaw<T>(handler: &Handler, request: &HandlerRequest, progress: Option<&Progress>) -> Result<T>
 where
-    R: AsyncReadExt + Unpin,
+    T: serde::de::DeserializeOwned + Default,
 {
-    let envelope = protocol::receive_framed_message(reader).await?;
-    let envelope_size = envelope.len();
+    let response = handler.call(&serde_json::to_value(request)?, progress).await?;
 
-    if envelope.len() < 8 {
-        return Err(anyhow!("Envelope too short: {} bytes", envelope.len()));
+    if response.is_null() {
+        return Ok(T::default());
     }
 
-    let remote_counter = u32::from_be_bytes(envelope[0..4].try_into()?);
-    let remote_timestamp = u32::from_be_bytes(envelope[4..8].try_into()?);
-    let ciphertext = &envelope[8..];
+    serde_json::from_value(response)
+        .map_err(|e| anyhow!("Invalid {} response from OpenClaw: {}", request.phase.name(), e))
+}
 
-    if remote_counter <= *last_counter {
-        return Err(anyhow!(
-            "Replay attack detected: counter {} <= {}",
-            remote_counter,
-            *last_counter
-        ));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempFile;
+
+    /// What a `Peer` borrows, for judging messages without a connection.
+    struct Fixture {
+        conversations: Conversations,
+        router: Router,
+        policy: Policy,
+        approvals: ApprovalQueue,
+        approval: ApprovalConfig,
+        scheduler: Scheduler,
+        blocklist: Mutex<Blocklist>,
+        rate_limiter: Mutex<RateLimiter>,
+        _files: [TempFile; 2],
     }
-    *last_counter = remote_counter;
 
-    let aad = protocol::build_aad(PROTOCOL_VERSION, expected_from, expected_to);
-    let plaintext =
-        crypto::decrypt_message(session_key, remote_counter, remote_timestamp, ciphertext, &aad)?;
+    impl Fixture {
+        fn new(name: &str, policy: &str) -> Self {
+            let files = [TempFile::new("daemon", &format!("{}-blocklist", name)), TempFile::new("daemon", &format!("{}-limits", name))];
+            Self {
+                conversations: Conversations::default(),
+                router: Router::new(&[], Handler::new("/bin/true", &HandlerOptions::default())).unwrap(),
+                policy: toml::from_str(policy).unwrap(),
+                approvals: ApprovalQueue::default(),
+                approval: ApprovalConfig::default(),
+                scheduler: Scheduler::new(&CapacityConfig::default()).unwrap(),
+                blocklist: Mutex::new(Blocklist::load(files[0].to_path_buf()).unwrap()),
+                rate_limiter: Mutex::new(RateLimiter::load(files[1].to_path_buf()).unwrap()),
+                _files: files,
+            }
+        }
 
-    let message: Message = protocol::decode_message(&plaintext)?;
+        fn peer<'a>(&'a self, registration: &'a Registration<'a>) -> Peer<'a> {
+            Peer {
+                id: "quest-1",
+                fp: None,
+                addr: "127.0.0.1:7779".parse().unwrap(),
+                conversation: "c1".to_string(),
+                registration,
+                labels: Vec::new(),
+                category: Category::TaskRequest,
+                reachable: false,
+                priority: Priority::Normal,
+                router: &self.router,
+                policy: &self.policy,
+                approvals: &self.approvals,
+                approval: &self.approval,
+                limits: Limits::default(),
+                scheduler: &self.scheduler,
+                blocklist: &self.blocklist,
+                rate_limiter: &self.rate_limiter,
+            }
+        }
 
-    protocol::validate_size(message.stage, plaintext.len())?;
+        fn register(&self) -> Registration<'_> {
+            self.conversations.register("c1", "quest-1", "127.0.0.1:7779".parse().unwrap())
+        }
+    }
 
-    if message.from != expected_from {
-        return Err(anyhow!(
-            "Message from mismatch: expected {}, got {}",
-            expected_from,
-            message.from
-        ));
+    fn message(stage: Stage, payload: serde_json::Value) -> Message {
+        Message {
+            stage: stage.to_u8(),
+            counter: 1,
+            timestamp: 1678886400,
+            from: "quest-1".to_string(),
+            to: "churi-7b9e4d2a".to_string(),
+            payload: serde_json::from_value(payload).unwrap(),
+        }
     }
-    if message.to != expected_to {
-        return Err(anyhow!(
-            "Message to mismatch: expected {}, got {}",
-            expected_to,
-            message.to
-        ));
+
+    #[test]
+    fn test_wish_judged_on_its_own_offer() {
+        let fixture = Fixture::new("offer", "[[rule]]\noffer = [2]\ndecision = \"accept\"\n");
+        let registration = fixture.register();
+        let peer = fixture.peer(&registration);
+        assert_eq!(peer.judge(None).decision, Decision::Accept);
+
+        let judge = |payload| {
+            let wish = message(Stage::Wish, payload);
+            peer.judge(Some((&wish, &wish.parse().unwrap())))
+        };
+        // Whatever the KNOCK named, a WISH without an offer does not satisfy
+        // the rule.
+        let verdict = judge(serde_json::json!({"task": {"act": "review"}}));
+        assert_eq!((verdict.decision, verdict.reason.as_deref()), (Decision::Decline, Some("insufficient_offer")));
+
+        let verdict = judge(serde_json::json!({"task": {"act": "review"}, "offer": {"t": 2}}));
+        assert_eq!(verdict.decision, Decision::Accept);
     }
 
-    Ok((message, envelope_size))
+    #[test]
+    fn test_malformed_wish_is_a_violation() {
+        let fixture = Fixture::new("malformed", "");
+        let registration = fixture.register();
+        let peer = fixture.peer(&registration);
+
+        let wish = message(Stage::Wish, serde_json::json!({"task": {"act": "review"}, "rev": "first"}));
+        for _ in 0..5 {
+            assert!(peer.parse_wish(&wish).is_err());
+        }
+        assert!(fixture.blocklist.lock().unwrap().is_blocked("quest-1"));
+
+        // A THANK in place of the WISH is not malformed.
+        let fixture = Fixture::new("thank", "");
+        let registration = fixture.register();
+        let peer = fixture.peer(&registration);
+        for _ in 0..5 {
+            assert!(peer.parse_wish(&message(Stage::Thank, serde_json::json!({}))).is_err());
+        }
+        assert!(!fixture.blocklist.lock().unwrap().is_blocked("quest-1"));
+    }
+
+    #[test]
+    fn test_bad_revision_is_invalid_format() {
+        let proposal: CounterProposal = serde_json::from_value(serde_json::json!({"opts": [{"id": 1}]})).unwrap();
+        let wish = |payload| serde_json::from_value::<WishPayload>(payload).unwrap();
+
+        assert!(check_revision(&wish(serde_json::json!({"rev": 1, "sel_opt": 1})), 1, Some(&proposal)).is_ok());
+        // Wrong revision, no option selected, an option not offered.
+        for payload in [
+            serde_json::json!({"rev": 0, "sel_opt": 1}),
+            serde_json::json!({"rev": 1}),
+            serde_json::json!({"rev": 1, "sel_opt": 2}),
+        ] {
+            let err = check_revision(&wish(payload), 1, Some(&proposal)).unwrap_err();
+            assert_eq!((err.code, err.recov), (ErrorCode::InvalidFormat, false));
+        }
+    }
 }
@@ -0,0 +1,188 @@
+use crate::capabilities::Capability;
+use crate::protocol::{CounterProposal, ErrorCode, Message, ProtocolError};
+use anyhow::Result;
+use schemars::{schema_for, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+/// Version of the envelope layout, sent as `v`.
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// Why the handler is being called.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// Accept or decline a KNOCK. Answer with `KnockResponse`.
+    Knock,
+    /// Decide on a WISH: grant, decline or counter-propose. Answer with
+    /// `EvaluateResponse`. Must not do the work itself.
+    Evaluate,
+    /// Do the granted work. Answer with `ExecuteResponse`.
+    Execute,
+    /// The requester's closing THANK, for information. The output is ignored.
+    Thank,
+    /// List the actions the handler offers, for handlers with
+    /// `capabilities = true`. `msg` is the KNOCK that needed the list.
+    /// Answer with `CapabilitiesResponse`.
+    Capabilities,
+}
+
+impl Phase {
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Knock => "knock",
+            Phase::Evaluate => "evaluate",
+            Phase::Execute => "execute",
+            Phase::Thank => "thank",
+            Phase::Capabilities => "capabilities",
+        }
+    }
+}
+
+/// The requester, as far as the daemon knows it.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct PeerInfo {
+    pub id: String,
+    /// Hex SHA-256 of the peer's public key, if it is in the keyring.
+    pub fp: Option<String>,
+    /// Address the connection came from.
+    pub addr: Option<String>,
+}
+
+/// What the handler reads on stdin (one-shot) or in `msg` (worker).
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct HandlerRequest {
+    pub v: u8,
+    pub phase: Phase,
+    /// Identifies the conversation across the calls made for it.
+    pub conv: String,
+    pub peer: PeerInfo,
+    /// Negotiation round: 0 for the first WISH, then the revision number.
+    pub round: u8,
+    /// The decrypted message this call is about.
+    pub msg: Message,
+}
+
+/// Answer to a `knock` call. Empty output accepts.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct KnockResponse {
+    #[serde(default = "yes")]
+    pub accept: bool,
+    /// Decline reason, a spec §9.2 name such as `busy`.
+    pub reason: Option<String>,
+    /// Text for the WELCOME `msg`.
+    pub msg: Option<String>,
+}
+
+impl Default for KnockResponse {
+    fn default() -> Self {
+        Self { accept: true, reason: None, msg: None }
+    }
+}
+
+/// Answer to an `evaluate` call. A `counter` proposal takes precedence over
+/// `accept`. Empty output grants.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct EvaluateResponse {
+    #[serde(default = "yes")]
+    pub accept: bool,
+    pub reason: Option<String>,
+    /// Seconds the work should take, sent as GRANT `est_t` (default 60).
+    pub estimated_time: Option<u64>,
+    /// Sent as GRANT `est_c`.
+    pub estimated_cost: Option<u32>,
+    pub counter: Option<CounterProposal>,
+    /// Text for the GRANT `msg`.
+    pub msg: Option<String>,
+}
+
+impl Default for EvaluateResponse {
+    fn default() -> Self {
+        Self {
+            accept: true,
+            reason: None,
+            estimated_time: None,
+            estimated_cost: None,
+            counter: None,
+            msg: None,
+        }
+    }
+}
+
+/// Answer to an `execute` call. `res` becomes the GIFT result; `err` fails
+/// the task with a `task_failed` ERROR instead.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+pub struct ExecuteResponse {
+    #[serde(default)]
+    pub res: serde_json::Value,
+    pub err: Option<String>,
+}
+
+impl ExecuteResponse {
+    /// The GIFT result, or the handler's `err` as a `task_failed` error.
+    /// The handler ran to completion, so the failure is not recoverable.
+    pub fn result(self) -> Result<serde_json::Value> {
+        match self.err {
+            Some(err) => Err(ProtocolError::new(ErrorCode::TaskFailed, err).into()),
+            None => Ok(self.res),
+        }
+    }
+}
+
+/// Answer to a `capabilities` call, added to the `[[actions]]` list.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+pub struct CapabilitiesResponse {
+    #[serde(default)]
+    pub actions: Vec<Capability>,
+}
+
+fn yes() -> bool {
+    true
+}
+
+/// The JSON Schema of the request envelope and of each phase's response,
+/// printed by `wishp handler-schema`.
+pub fn schema() -> serde_json::Value {
+    serde_json::json!({
+        "request": schema_for!(HandlerRequest),
+        "responses": {
+            "knock": schema_for!(KnockResponse),
+            "evaluate": schema_for!(EvaluateResponse),
+            "execute": schema_for!(ExecuteResponse),
+            "capabilities": schema_for!(CapabilitiesResponse),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_defaults() {
+        let knock: KnockResponse = serde_json::from_str("{}").unwrap();
+        assert_eq!(knock, KnockResponse::default());
+        assert!(knock.accept);
+
+        let evaluate: EvaluateResponse =
+            serde_json::from_str(r#"{"accept": false, "reason": "busy", "extra": 1}"#).unwrap();
+        assert!(!evaluate.accept);
+        assert_eq!(evaluate.reason.as_deref(), Some("busy"));
+    }
+
+    #[test]
+    fn test_execute_err_not_recoverable() {
+        let response = ExecuteResponse { err: Some("bad input".to_string()), ..ExecuteResponse::default() };
+        let err = ProtocolError::from_anyhow(&response.result().unwrap_err(), ErrorCode::InternalError);
+        assert_eq!((err.code, err.recov), (ErrorCode::TaskFailed, false));
+    }
+
+    #[test]
+    fn test_schema_lists_phases() {
+        let schema = schema();
+        let phases = serde_json::to_string(&schema["request"]).unwrap();
+        for phase in ["knock", "evaluate", "execute", "thank", "capabilities"] {
+            assert!(phases.contains(phase));
+        }
+        assert!(schema["responses"]["evaluate"]["properties"]["counter"].is_object());
+    }
+}
@@ -0,0 +1,414 @@
+use crate::envelope::Phase;
+use crate::protocol::{ErrorCode, ProtocolError};
+use crate::worker::Worker;
+use anyhow::{anyhow, Result};
+use nix::sys::resource::{setrlimit, Resource};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+
+/// Receives progress events (`prog`, `stat`, `msg`, `eta`) from a running
+/// handler.
+pub type Progress = mpsc::UnboundedSender<serde_json::Value>;
+
+/// `oneshot` starts the handler for every request; `worker` keeps one
+/// process running and talks to it over JSON lines (see `worker`).
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HandlerMode {
+    #[default]
+    OneShot,
+    Worker,
+}
+
+/// How a handler process is run, from the `[openclaw]` config section.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HandlerOptions {
+    pub mode: HandlerMode,
+    /// Seconds before a run is killed.
+    pub timeout: u64,
+    /// Largest stdout or stderr we capture, in bytes.
+    pub max_output: usize,
+    pub cwd: Option<String>,
+    /// Variables copied from the daemon's environment. Everything else is
+    /// cleared before the handler starts.
+    pub pass_env: Vec<String>,
+    pub env: HashMap<String, String>,
+    pub cpu_seconds: Option<u64>,
+    pub memory_bytes: Option<u64>,
+    pub open_files: Option<u64>,
+    /// Phases (`knock`, `evaluate`) an operator must approve before the
+    /// handler is called.
+    pub approve: Vec<Phase>,
+    /// Ask the handler for the actions it offers (phase `capabilities`)
+    /// and advertise them with `[[actions]]`.
+    pub capabilities: bool,
+}
+
+impl Default for HandlerOptions {
+    fn default() -> Self {
+        Self {
+            mode: HandlerMode::OneShot,
+            timeout: 300,
+            max_output: 32 * 1024 * 1024,
+            cwd: None,
+            pass_env: vec!["PATH".to_string(), "HOME".to_string(), "LANG".to_string()],
+            env: HashMap::new(),
+            cpu_seconds: None,
+            memory_bytes: None,
+            open_files: None,
+            approve: Vec::new(),
+            capabilities: false,
+        }
+    }
+}
+
+impl HandlerOptions {
+    fn rlimits(&self) -> Vec<(Resource, u64)> {
+        [
+            (Resource::RLIMIT_CPU, self.cpu_seconds),
+            (Resource::RLIMIT_AS, self.memory_bytes),
+            (Resource::RLIMIT_NOFILE, self.open_files),
+        ]
+        .into_iter()
+        .filter_map(|(resource, limit)| limit.map(|l| (resource, l)))
+        .collect()
+    }
+}
+
+/// Handlers built into the daemon, usable as routes.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Builtin {
+    /// Accepts everything and appends each executed request to a JSON-lines
+    /// file, e.g. for tips and document shares.
+    Inbox,
+    /// Declines everything as `capability_mismatch`.
+    Decline,
+}
+
+impl Builtin {
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Inbox => "inbox",
+            Builtin::Decline => "decline",
+        }
+    }
+}
+
+/// A configured handler: an external program in either mode, or a builtin.
+pub enum Handler {
+    OneShot { path: String, options: HandlerOptions },
+    Worker(Worker),
+    Builtin { kind: Builtin, inbox: PathBuf },
+}
+
+impl Handler {
+    pub fn new(path: &str, options: &HandlerOptions) -> Self {
+        match options.mode {
+            HandlerMode::OneShot => Handler::OneShot { path: path.to_string(), options: options.clone() },
+            HandlerMode::Worker => Handler::Worker(Worker::new(path, options)),
+        }
+    }
+
+    fn options(&self) -> Option<&HandlerOptions> {
+        match self {
+            Handler::OneShot { options, .. } => Some(options),
+            Handler::Worker(worker) => Some(worker.options()),
+            Handler::Builtin { .. } => None,
+        }
+    }
+
+    /// Whether calls for `phase` wait for an operator's approval first.
+    pub fn needs_approval(&self, phase: Phase) -> bool {
+        self.options().is_some_and(|o| o.approve.contains(&phase))
+    }
+
+    /// Whether the handler declares the actions it offers.
+    pub fn declares_capabilities(&self) -> bool {
+        self.options().is_some_and(|o| o.capabilities)
+    }
+
+    /// Passes `request` to the handler and returns its JSON response, or
+    /// null if a one-shot handler printed nothing. Progress events go to
+    /// `progress` when given and are dropped otherwise.
+    pub async fn call(&self, request: &serde_json::Value, progress: Option<&Progress>) -> Result<serde_json::Value> {
+        match self {
+            Handler::OneShot { path, options } => {
+                let output = run(path, options, &serde_json::to_vec(request)?, progress).await?;
+                if output.iter().all(u8::is_ascii_whitespace) {
+                    return Ok(serde_json::Value::Null);
+                }
+                serde_json::from_slice(&output).map_err(|e| task_failed(format!("Invalid handler response: {}", e)))
+            }
+            Handler::Worker(worker) => worker.call(request, progress).await,
+            Handler::Builtin { kind, inbox } => call_builtin(*kind, inbox, request).await,
+        }
+    }
+}
+
+impl std::fmt::Display for Handler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Handler::OneShot { path, .. } => write!(f, "command {}", path),
+            Handler::Worker(worker) => write!(f, "worker {}", worker.path()),
+            Handler::Builtin { kind: Builtin::Inbox, inbox } => write!(f, "builtin inbox ({})", inbox.display()),
+            Handler::Builtin { kind, .. } => write!(f, "builtin {}", kind.name()),
+        }
+    }
+}
+
+async fn call_builtin(kind: Builtin, inbox: &Path, request: &serde_json::Value) -> Result<serde_json::Value> {
+    let phase = request.get("phase").and_then(|v| v.as_str()).unwrap_or_default();
+    let response = match (kind, phase) {
+        (Builtin::Decline, "knock" | "evaluate") => {
+            serde_json::json!({"accept": false, "reason": "capability_mismatch"})
+        }
+        (Builtin::Inbox, "evaluate") => serde_json::json!({"accept": true, "estimated_time": 1}),
+        (Builtin::Inbox, "execute") => {
+            let mut line = serde_json::to_vec(request)?;
+            line.push(b'\n');
+            let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(inbox).await?;
+            file.write_all(&line).await?;
+            serde_json::json!({"res": {"stored": true}})
+        }
+        _ => serde_json::Value::Null,
+    };
+    Ok(response)
+}
+
+/// The handler command with our environment, directory and rlimits applied.
+pub fn command(path: &str, options: &HandlerOptions) -> Command {
+    let mut command = Command::new(path);
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .env_clear();
+    for name in &options.pass_env {
+        if let Some(value) = std::env::var_os(name) {
+            command.env(name, value);
+        }
+    }
+    command.envs(&options.env);
+    if let Some(cwd) = &options.cwd {
+        command.current_dir(shellexpand::tilde(cwd).as_ref());
+    }
+
+    let rlimits = options.rlimits();
+    if !rlimits.is_empty() {
+        // SAFETY: setrlimit is async-signal-safe and the closure allocates
+        // nothing.
+        unsafe {
+            command.pre_exec(move || {
+                for (resource, limit) in &rlimits {
+                    setrlimit(*resource, *limit, *limit)?;
+                }
+                Ok(())
+            });
+        }
+    }
+    command
+}
+
+/// Runs the handler at `path` once with `input` on stdin and returns its
+/// stdout, less any progress lines (see `progress_event`). Failures carry a
+/// `ProtocolError`: `timeout` when the run is killed for taking too long,
+/// `task_failed` for anything else.
+pub async fn run(path: &str, options: &HandlerOptions, input: &[u8], progress: Option<&Progress>) -> Result<Vec<u8>> {
+    let mut child = command(path, options)
+        .spawn()
+        .map_err(|e| task_failed(format!("Cannot start handler {}: {}", path, e)))?;
+    let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("Failed to open stdin"))?;
+    let stdout = child.stdout.take().ok_or_else(|| anyhow!("Failed to open stdout"))?;
+    let stderr = child.stderr.take().ok_or_else(|| anyhow!("Failed to open stderr"))?;
+
+    let write_input = async move {
+        // A handler that exits without reading its input is not an error.
+        match stdin.write_all(input).await {
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e.into()),
+            _ => Ok(()),
+        }
+    };
+    let exchange = async {
+        let (_, stdout, stderr) = tokio::try_join!(
+            write_input,
+            read_output(stdout, options.max_output, progress),
+            read_capped(stderr, options.max_output),
+        )?;
+        let status = child.wait().await?;
+        Ok::<_, anyhow::Error>((status, stdout, stderr))
+    };
+
+    let limit = Duration::from_secs(options.timeout);
+    let outcome = tokio::time::timeout(limit, exchange).await;
+    let (status, stdout, stderr) = match outcome {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => {
+            let _ = child.kill().await;
+            return Err(task_failed(e.to_string()));
+        }
+        Err(_) => {
+            let _ = child.kill().await;
+            return Err(timed_out(options.timeout));
+        }
+    };
+
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        return Err(crashed(format!("Handler {}: {}", describe_exit(status), stderr.trim())));
+    }
+    Ok(stdout)
+}
+
+async fn read_capped<R>(reader: R, max: usize) -> Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buffer = Vec::new();
+    reader.take(max as u64 + 1).read_to_end(&mut buffer).await?;
+    if buffer.len() > max {
+        return Err(anyhow!("Handler output exceeded {} bytes", max));
+    }
+    Ok(buffer)
+}
+
+/// Reads a one-shot handler's stdout, passing progress lines on as they
+/// arrive and keeping the rest.
+async fn read_output<R>(reader: R, max: usize, progress: Option<&Progress>) -> Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut reader = BufReader::new(reader.take(max as u64 + 1));
+    let mut output = Vec::new();
+    let mut line = Vec::new();
+    let mut total = 0;
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line).await?;
+        if read == 0 {
+            break;
+        }
+        total += read;
+        match progress_event(&line) {
+            Some(event) => {
+                if let Some(progress) = progress {
+                    let _ = progress.send(event);
+                }
+            }
+            None => output.extend_from_slice(&line),
+        }
+    }
+    if total > max {
+        return Err(anyhow!("Handler output exceeded {} bytes", max));
+    }
+    Ok(output)
+}
+
+/// A progress line is a JSON object with a `wrap` object, e.g.
+/// `{"wrap": {"prog": 60, "stat": "analyzing"}}`.
+fn progress_event(line: &[u8]) -> Option<serde_json::Value> {
+    let mut value: serde_json::Value = serde_json::from_slice(line).ok()?;
+    match value.get_mut("wrap")?.take() {
+        event @ serde_json::Value::Object(_) => Some(event),
+        _ => None,
+    }
+}
+
+pub fn describe_exit(status: ExitStatus) -> String {
+    use std::os::unix::process::ExitStatusExt;
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("exited with status {}", code),
+        (None, Some(signal)) => format!("was killed by signal {}", signal),
+        _ => "failed".to_string(),
+    }
+}
+
+pub fn task_failed(msg: String) -> anyhow::Error {
+    ProtocolError::new(ErrorCode::TaskFailed, msg).into()
+}
+
+/// A handler that died or could not be reached. Unlike an `err` it
+/// reported itself, the task may succeed if tried again.
+pub fn crashed(msg: String) -> anyhow::Error {
+    ProtocolError::new(ErrorCode::TaskFailed, msg).recoverable().into()
+}
+
+pub fn timed_out(seconds: u64) -> anyhow::Error {
+    ProtocolError::new(ErrorCode::Timeout, format!("Handler did not finish within {} seconds", seconds))
+        .recoverable()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempFile;
+
+    fn code(err: &anyhow::Error) -> ErrorCode {
+        ProtocolError::from_anyhow(err, ErrorCode::InternalError).code
+    }
+
+    fn recoverable(err: &anyhow::Error) -> bool {
+        ProtocolError::from_anyhow(err, ErrorCode::InternalError).recov
+    }
+
+    #[tokio::test]
+    async fn test_echoes_output() {
+        let output = run("/bin/cat", &HandlerOptions::default(), b"{\"accept\":true}", None).await.unwrap();
+        assert_eq!(output, b"{\"accept\":true}");
+    }
+
+    #[tokio::test]
+    async fn test_progress_lines_split_from_result() {
+        let (progress, mut events) = mpsc::unbounded_channel();
+        let input = b"{\"wrap\": {\"prog\": 50}}\n{\"accept\":true}\n";
+        let output = run("/bin/cat", &HandlerOptions::default(), input, Some(&progress)).await.unwrap();
+        assert_eq!(output, b"{\"accept\":true}\n");
+        assert_eq!(events.recv().await.unwrap(), serde_json::json!({"prog": 50}));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_kills_handler() {
+        let script = TempFile::new("handler", "sleep");
+        std::fs::write(&script, "#!/bin/sh\nsleep 5\n").unwrap();
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+
+        let options = HandlerOptions { timeout: 1, ..HandlerOptions::default() };
+        let started = std::time::Instant::now();
+        let err = run(script.to_str(), &options, b"", None).await.unwrap_err();
+        assert_eq!(code(&err), ErrorCode::Timeout);
+        assert!(recoverable(&err));
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn test_failure_and_output_cap() {
+        let err = run("/bin/false", &HandlerOptions::default(), b"", None).await.unwrap_err();
+        assert_eq!(code(&err), ErrorCode::TaskFailed);
+        assert!(recoverable(&err));
+
+        let options = HandlerOptions { max_output: 4, ..HandlerOptions::default() };
+        let err = run("/bin/cat", &options, b"too long", None).await.unwrap_err();
+        assert_eq!(code(&err), ErrorCode::TaskFailed);
+    }
+
+    #[tokio::test]
+    async fn test_environment_is_scrubbed() {
+        std::env::set_var("WISHP_TEST_SECRET", "leak");
+        let mut options = HandlerOptions::default();
+        options.env.insert("WISHP_GIVEN".to_string(), "yes".to_string());
+
+        let output = run("/usr/bin/env", &options, b"", None).await.unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("WISHP_GIVEN=yes"));
+        assert!(!output.contains("WISHP_TEST_SECRET"));
+    }
+}
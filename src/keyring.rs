@@ -1,13 +1,54 @@
+use crate::protocol::Endpoint;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::PathBuf;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+
+const KEYRING_VERSION: u8 = 2;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct KeyringEntry {
     pub agent_id: String,
     pub public_key: [u8; 32],
     pub added_at: u64,
+    /// Where the peer can be reached, tried in order.
+    #[serde(default)]
+    pub endpoints: Vec<Endpoint>,
+    /// Pinned SHA-256 fingerprint (hex) of the peer's TLS certificate.
+    #[serde(default)]
+    pub tls_fingerprint: Option<String>,
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+/// On-disk layout since version 2. Entries are written with field names so
+/// new fields can be added without another migration.
+#[derive(Serialize, Deserialize)]
+struct KeyringFile {
+    ver: u8,
+    entries: HashMap<String, KeyringEntry>,
+}
+
+/// Entry layout written before the address book existed: a bare
+/// `agent_id -> entry` map with entries encoded as msgpack arrays.
+#[derive(Deserialize)]
+struct LegacyEntry {
+    agent_id: String,
+    public_key: [u8; 32],
+    added_at: u64,
+}
+
+impl From<LegacyEntry> for KeyringEntry {
+    fn from(legacy: LegacyEntry) -> Self {
+        Self {
+            agent_id: legacy.agent_id,
+            public_key: legacy.public_key,
+            added_at: legacy.added_at,
+            endpoints: Vec::new(),
+            tls_fingerprint: None,
+            labels: Vec::new(),
+        }
+    }
 }
 
 pub struct Keyring {
@@ -19,7 +60,8 @@ impl Keyring {
     pub fn load(path: PathBuf) -> Result<Self> {
         let entries = if path.exists() {
             let data = std::fs::read(&path)?;
-            rmp_serde::from_slice(&data).unwrap_or_default()
+            decode_entries(&data)
+                .map_err(|e| anyhow!("Cannot read keyring {}: {}", path.display(), e))?
         } else {
             HashMap::new()
         };
@@ -36,21 +78,131 @@ impl Keyring {
             agent_id,
             public_key,
             added_at: timestamp,
+            endpoints: Vec::new(),
+            tls_fingerprint: None,
+            labels: Vec::new(),
         });
         self.save()
     }
 
+    /// Applies `f` to an existing entry and writes the keyring back.
+    pub fn edit<F>(&mut self, agent_id: &str, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut KeyringEntry),
+    {
+        let entry = self
+            .entries
+            .get_mut(agent_id)
+            .ok_or_else(|| anyhow!("Unknown peer: {}", agent_id))?;
+        f(entry);
+        self.save()
+    }
+
     pub fn get(&self, agent_id: &str) -> Option<&[u8; 32]> {
         self.entries.get(agent_id).map(|e| &e.public_key)
     }
 
+    pub fn get_entry(&self, agent_id: &str) -> Option<&KeyringEntry> {
+        self.entries.get(agent_id)
+    }
+
     pub fn list(&self) -> Vec<&KeyringEntry> {
         self.entries.values().collect()
     }
 
     fn save(&self) -> Result<()> {
-        let data = rmp_serde::to_vec(&self.entries)?;
+        let file = KeyringFile {
+            ver: KEYRING_VERSION,
+            entries: self.entries.clone(),
+        };
+        let data = rmp_serde::to_vec_named(&file)?;
         std::fs::write(&self.path, data)?;
         Ok(())
     }
 }
+
+fn decode_entries(data: &[u8]) -> Result<HashMap<String, KeyringEntry>> {
+    if let Ok(file) = rmp_serde::from_slice::<KeyringFile>(data) {
+        if file.ver > KEYRING_VERSION {
+            return Err(anyhow!("Unsupported keyring version {}", file.ver));
+        }
+        return Ok(file.entries);
+    }
+
+    let legacy: HashMap<String, LegacyEntry> = rmp_serde::from_slice(data)
+        .map_err(|e| anyhow!("Unrecognized keyring format: {}", e))?;
+    Ok(legacy.into_iter().map(|(id, entry)| (id, entry.into())).collect())
+}
+
+/// Normalizes a certificate fingerprint to lowercase hex, accepting an
+/// optional `sha256:` prefix and colon separators.
+pub fn normalize_fingerprint(fingerprint: &str) -> Result<String> {
+    let hex_part = fingerprint.strip_prefix("sha256:").unwrap_or(fingerprint);
+    let normalized: String = hex_part
+        .chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_ascii_lowercase();
+
+    if normalized.len() != 64 || !normalized.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("Invalid SHA-256 fingerprint: {}", fingerprint));
+    }
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempFile;
+
+    #[derive(Serialize)]
+    struct OldEntry {
+        agent_id: String,
+        public_key: [u8; 32],
+        added_at: u64,
+    }
+
+    #[test]
+    fn test_migrates_legacy_keyring() {
+        let path = TempFile::new("keyring", "legacy");
+        let mut old = HashMap::new();
+        old.insert("churi-7b9e4d2a".to_string(), OldEntry {
+            agent_id: "churi-7b9e4d2a".to_string(),
+            public_key: [7u8; 32],
+            added_at: 1707397200,
+        });
+        std::fs::write(&path, rmp_serde::to_vec(&old).unwrap()).unwrap();
+
+        let mut keyring = Keyring::load(path.to_path_buf()).unwrap();
+        let entry = keyring.get_entry("churi-7b9e4d2a").unwrap();
+        assert_eq!(entry.public_key, [7u8; 32]);
+        assert_eq!(entry.added_at, 1707397200);
+        assert!(entry.endpoints.is_empty());
+
+        keyring.edit("churi-7b9e4d2a", |e| {
+            e.endpoints.push(Endpoint::Direct { host: "192.168.1.100".to_string(), port: 7779 });
+            e.labels.push("trusted".to_string());
+        }).unwrap();
+
+        let reloaded = Keyring::load(path.to_path_buf()).unwrap();
+        let entry = reloaded.get_entry("churi-7b9e4d2a").unwrap();
+        assert_eq!(entry.endpoints.len(), 1);
+        assert_eq!(entry.labels, vec!["trusted".to_string()]);
+    }
+
+    #[test]
+    fn test_rejects_corrupt_keyring() {
+        let path = TempFile::new("keyring", "corrupt");
+        std::fs::write(&path, b"not msgpack").unwrap();
+        assert!(Keyring::load(path.to_path_buf()).is_err());
+    }
+
+    #[test]
+    fn test_normalize_fingerprint() {
+        let hex = "AB".repeat(32);
+        let colons = vec!["ab"; 32].join(":");
+        assert_eq!(normalize_fingerprint(&format!("sha256:{}", hex)).unwrap(), "ab".repeat(32));
+        assert_eq!(normalize_fingerprint(&colons).unwrap(), "ab".repeat(32));
+        assert!(normalize_fingerprint("abcd").is_err());
+    }
+}
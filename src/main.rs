@@ -1,12 +1,34 @@
+mod approval;
+mod blocklist;
+mod capabilities;
 mod client;
+mod control;
+mod conversations;
 mod crypto;
 mod daemon;
+mod envelope;
+mod handler;
 mod keyring;
+mod outbox;
+mod payload;
+mod policy;
 mod protocol;
+mod ratelimit;
+mod replay;
+mod router;
+mod scheduler;
+mod schema;
+mod session;
+#[cfg(test)]
+mod test_util;
+mod worker;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use daemon::{Config, AgentConfig, NetworkConfig, OpenClawConfig, KeysConfig};
+use daemon::{Config, AgentConfig, NetworkConfig, OpenClawConfig, KeysConfig, TimeoutConfig, BlocklistConfig, LimitsConfig, PolicyConfig, ShutdownConfig, DeferConfig};
+use handler::HandlerOptions;
+use payload::StagePayload;
+use protocol::Endpoint;
 use std::collections::HashMap;
 use std::io::Read;
 
@@ -22,15 +44,151 @@ struct Cli {
 enum Commands {
     Daemon,
     Send {
-        agent_id: String,
+        /// wish://agent@host:port/, agent@host, or an agent ID from the keyring
+        target: String,
+        /// How to answer counter-proposals: take the first option, or prompt
+        #[arg(long, value_enum, default_value_t = SelectMode::First, conflicts_with = "select_script")]
+        select: SelectMode,
+        /// Program that picks counter-proposal options (JSON on stdin/stdout)
+        #[arg(long)]
+        select_script: Option<String>,
+        /// Queue in the daemon's outbox and wait for the result (the default
+        /// when the daemon is running)
+        #[arg(long, conflicts_with = "no_wait")]
+        wait: bool,
+        /// Queue in the daemon's outbox and print the job ID
+        #[arg(long)]
+        no_wait: bool,
+        /// Send even if the WISH does not match the peer's cached
+        /// capabilities
+        #[arg(long)]
+        no_check: bool,
+    },
+    /// Fetch and show the actions a peer offers
+    Capabilities {
+        /// wish://agent@host:port/, agent@host, or an agent ID from the keyring
+        target: String,
+        /// Print the list as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the daemon's outbound wishes, or show one
+    Outbox {
+        id: Option<String>,
+        /// Withdraw the job, stopping its task if the peer is running it
+        #[arg(long, requires = "id")]
+        cancel: bool,
+    },
+    /// Print the GIFT delivered later for a ticket
+    Result {
+        ticket: String,
     },
     Keygen,
     AddPeer {
         agent_id: String,
         public_key: String,
+        /// host:port, rdv:server[:port] or unix:/path (repeatable, tried in order)
+        #[arg(long = "endpoint")]
+        endpoints: Vec<String>,
+        /// Pinned SHA-256 fingerprint of the peer's TLS certificate
+        #[arg(long)]
+        tls_fingerprint: Option<String>,
+        #[arg(long = "label")]
+        labels: Vec<String>,
+    },
+    EditPeer {
+        agent_id: String,
+        #[arg(long = "add-endpoint")]
+        add_endpoints: Vec<String>,
+        #[arg(long = "remove-endpoint")]
+        remove_endpoints: Vec<String>,
+        #[arg(long)]
+        clear_endpoints: bool,
+        #[arg(long, conflicts_with = "clear_tls_fingerprint")]
+        tls_fingerprint: Option<String>,
+        #[arg(long)]
+        clear_tls_fingerprint: bool,
+        #[arg(long = "add-label")]
+        add_labels: Vec<String>,
+        #[arg(long = "remove-label")]
+        remove_labels: Vec<String>,
     },
     ListPeers,
-    Gencert,
+    Gencert {
+        /// Host names or IP addresses to include in the certificate
+        #[arg(long = "san", default_value = "localhost")]
+        names: Vec<String>,
+    },
+    Block {
+        agent_id: String,
+        /// spam, malformed_messages, size_violations, rate_limit_violations,
+        /// suspicious_behavior or manual_block
+        #[arg(long, default_value = "manual_block")]
+        reason: blocklist::BlockReason,
+    },
+    Unblock {
+        agent_id: String,
+    },
+    Blocklist,
+    /// Print the JSON Schema of handler requests and responses
+    HandlerSchema,
+    /// Show which handler a KNOCK or WISH message (JSON file) would reach
+    RouteTest {
+        message: std::path::PathBuf,
+        /// KNOCK category to assume for a WISH
+        #[arg(long, value_parser = payload::parse_code::<payload::Category>)]
+        category: Option<payload::Category>,
+    },
+    /// List requests waiting for approval in the running daemon
+    Pending,
+    /// Let a pending request through to the handler
+    Approve {
+        id: String,
+    },
+    /// Decline a pending request
+    Deny {
+        id: String,
+        /// Decline reason name (default policy_violation)
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Make the running daemon re-read its config, policy and keyring
+    Reload,
+    /// Show rate limit usage per peer
+    Limits,
+    /// List the running daemon's active conversations
+    Conversations,
+    /// End an active conversation with an ERROR to the requester
+    Cancel {
+        id: String,
+    },
+    /// Stop the running daemon once its active conversations end
+    Shutdown,
+    /// Inspect the local policy
+    Policy {
+        #[command(subcommand)]
+        command: PolicyCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum PolicyCommand {
+    /// Show what the policy decides for a KNOCK or WISH message (JSON file)
+    Check {
+        message: std::path::PathBuf,
+        /// KNOCK category to assume for a WISH
+        #[arg(long, value_parser = payload::parse_code::<payload::Category>)]
+        category: Option<payload::Category>,
+        /// KNOCK priority to assume for a WISH, 1 (low) to 4 (urgent)
+        #[arg(long, value_parser = payload::parse_code::<payload::Priority>)]
+        priority: Option<payload::Priority>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum SelectMode {
+    First,
+    Prompt,
 }
 
 #[tokio::main]
@@ -43,32 +201,184 @@ async fn main() -> Result<()> {
         Commands::Daemon => {
             daemon::start_server(config).await?;
         }
-        Commands::Send { agent_id } => {
+        Commands::Send { target, select, select_script, wait, no_wait, no_check } => {
             let mut buffer = String::new();
             std::io::stdin().read_to_string(&mut buffer)?;
             let payload: HashMap<String, serde_json::Value> = serde_json::from_str(&buffer)?;
+            client::check_input(&payload)?;
+            if !no_check {
+                check_capabilities(&config, &target, &payload)?;
+            }
+
+            // The daemon cannot prompt, so interactive selection always
+            // sends from here.
+            let queued = if select == SelectMode::Prompt {
+                if wait || no_wait {
+                    let flag = if wait { "--wait" } else { "--no-wait" };
+                    return Err(anyhow::anyhow!("--select prompt cannot be used with {}", flag));
+                }
+                None
+            } else {
+                let select_script = match select_script.clone() {
+                    Some(path) if path.contains('/') => Some(std::fs::canonicalize(&path)?.display().to_string()),
+                    other => other,
+                };
+                let request = control::Request::Send { target: target.clone(), payload: payload.clone(), select_script };
+                control::send_if_running(&config.control.path(), &request).await
+            };
 
-            match client::send_message(&agent_id, payload, &config).await {
-                Ok(response) => {
-                    println!("{}", serde_json::to_string_pretty(&response.payload)?);
+            match queued {
+                Some(job) => {
+                    let job: outbox::Job = serde_json::from_value(job?)?;
+                    if no_wait {
+                        println!("{}", job.id);
+                    } else {
+                        eprintln!("Queued as {}", job.id);
+                        let job = wait_for_job(&config, job).await?;
+                        print_job_result(&job)?;
+                    }
+                }
+                None if wait || no_wait => {
+                    let flag = if wait { "--wait" } else { "--no-wait" };
+                    return Err(anyhow::anyhow!("{} needs a running daemon to deliver the wish", flag));
                 }
-                Err(e) => {
-                    eprintln!("Error sending message: {}", e);
-                    std::process::exit(1);
+                None => {
+                    let destination = client::Destination::resolve(&target, &config)?;
+                    let selector: Box<dyn client::OptionSelector> = match (select_script, select) {
+                        (Some(path), _) => Box::new(client::ScriptSelector { path }),
+                        (None, SelectMode::First) => Box::new(client::FirstOption),
+                        (None, SelectMode::Prompt) => Box::new(client::PromptSelector),
+                    };
+
+                    match client::send_message(&destination, payload, &config, selector.as_ref(), interrupted()).await {
+                        Ok(outcome) => {
+                            println!("{}", serde_json::to_string_pretty(&outcome.output())?);
+                            if outcome.response.parse::<payload::GiftPayload>().is_ok_and(|gift| gift.cncl) {
+                                eprintln!("Cancelled");
+                                std::process::exit(1);
+                            }
+                        }
+                        Err(e) => {
+                            if let Some(protocol::PeerError(error)) = e.downcast_ref() {
+                                println!("{}", serde_json::to_string_pretty(&error.to_payload())?);
+                            }
+                            eprintln!("Error sending message: {}", e);
+                            std::process::exit(1);
+                        }
+                    }
                 }
             }
         }
+        Commands::Capabilities { target, json } => {
+            handle_capabilities(&config, &target, json).await?;
+        }
+        Commands::Outbox { id: Some(id), cancel: true } => {
+            let job: outbox::Job = serde_json::from_value(control::send(&config.control.path(), &control::Request::CancelJob { id }).await?)?;
+            println!("Cancelling {} ({})", job.id, job.state.name());
+        }
+        Commands::Outbox { id, .. } => {
+            handle_outbox(&config, id).await?;
+        }
+        Commands::Result { ticket } => {
+            handle_result(&config, &ticket).await?;
+        }
         Commands::Keygen => {
             handle_keygen()?;
         }
-        Commands::AddPeer { agent_id, public_key } => {
-            handle_add_peer(agent_id, public_key)?;
+        Commands::AddPeer { agent_id, public_key, endpoints, tls_fingerprint, labels } => {
+            handle_add_peer(&config, agent_id, public_key, endpoints, tls_fingerprint, labels)?;
+            notify_keyring_changed(&config).await;
+        }
+        Commands::EditPeer {
+            agent_id,
+            add_endpoints,
+            remove_endpoints,
+            clear_endpoints,
+            tls_fingerprint,
+            clear_tls_fingerprint,
+            add_labels,
+            remove_labels,
+        } => {
+            let edits = PeerEdits {
+                add_endpoints,
+                remove_endpoints,
+                clear_endpoints,
+                tls_fingerprint,
+                clear_tls_fingerprint,
+                add_labels,
+                remove_labels,
+            };
+            handle_edit_peer(&config, &agent_id, edits)?;
+            notify_keyring_changed(&config).await;
         }
         Commands::ListPeers => {
-            handle_list_peers()?;
+            handle_list_peers(&config)?;
+        }
+        Commands::Gencert { names } => {
+            handle_gencert(names)?;
+        }
+        Commands::Block { agent_id, reason } => {
+            let request = control::Request::Block { id: agent_id.clone(), reason };
+            match control::send_if_running(&config.control.path(), &request).await {
+                Some(result) => {
+                    result?;
+                    println!("✓ Blocked: {} ({})", agent_id, reason.name());
+                }
+                None => handle_block(&config, &agent_id, reason)?,
+            }
+        }
+        Commands::Unblock { agent_id } => {
+            let request = control::Request::Unblock { id: agent_id.clone() };
+            let unblocked = match control::send_if_running(&config.control.path(), &request).await {
+                Some(result) => result?.as_bool().unwrap_or(false),
+                None => open_blocklist(&config)?.unblock(&agent_id)?,
+            };
+            if unblocked {
+                println!("✓ Unblocked: {}", agent_id);
+            } else {
+                println!("{} is not blocked.", agent_id);
+            }
+        }
+        Commands::Blocklist => {
+            handle_list_blocklist(&config).await?;
+        }
+        Commands::HandlerSchema => {
+            println!("{}", serde_json::to_string_pretty(&envelope::schema())?);
         }
-        Commands::Gencert => {
-            handle_gencert()?;
+        Commands::RouteTest { message, category } => {
+            handle_route_test(&config, &message, category)?;
+        }
+        Commands::Pending => {
+            handle_pending(&config).await?;
+        }
+        Commands::Approve { id } => {
+            control::send(&config.control.path(), &control::Request::Approve { id: id.clone() }).await?;
+            println!("✓ Approved: {}", id);
+        }
+        Commands::Deny { id, reason } => {
+            control::send(&config.control.path(), &control::Request::Deny { id: id.clone(), reason }).await?;
+            println!("✓ Denied: {}", id);
+        }
+        Commands::Reload => {
+            control::send(&config.control.path(), &control::Request::Reload).await?;
+            println!("✓ Daemon reloaded its config and keyring");
+        }
+        Commands::Limits => {
+            handle_limits(&config).await?;
+        }
+        Commands::Conversations => {
+            handle_conversations(&config).await?;
+        }
+        Commands::Cancel { id } => {
+            control::send(&config.control.path(), &control::Request::Cancel { id: id.clone() }).await?;
+            println!("✓ Cancelled: {}", id);
+        }
+        Commands::Shutdown => {
+            control::send(&config.control.path(), &control::Request::Shutdown).await?;
+            println!("✓ Daemon is shutting down");
+        }
+        Commands::Policy { command: PolicyCommand::Check { message, category, priority } } => {
+            handle_policy_check(&config, &message, category, priority)?;
         }
     }
 
@@ -76,11 +386,9 @@ async fn main() -> Result<()> {
 }
 
 fn load_config() -> Result<Config> {
-    let config_path = shellexpand::tilde("~/.wish-protocol/config.toml").into_owned();
-    if std::path::Path::new(&config_path).exists() {
-        let content = std::fs::read_to_string(config_path)?;
-        let config: Config = toml::from_str(&content)?;
-        return Ok(config);
+    let config_path = std::path::PathBuf::from(shellexpand::tilde(daemon::CONFIG_PATH).into_owned());
+    if config_path.exists() {
+        return Config::load(&config_path);
     }
 
     Ok(Config {
@@ -92,6 +400,7 @@ fn load_config() -> Result<Config> {
         },
         openclaw: OpenClawConfig {
             path: "./mock_openclaw.sh".to_string(),
+            run: HandlerOptions::default(),
         },
         keys: KeysConfig {
             private_key_path: "~/.wish-protocol/keys/private.key".to_string(),
@@ -100,6 +409,18 @@ fn load_config() -> Result<Config> {
             cert_path: "~/.wish-protocol/cert.pem".to_string(),
             key_path: "~/.wish-protocol/key.pem".to_string(),
         },
+        timeouts: TimeoutConfig::default(),
+        blocklist: BlocklistConfig::default(),
+        limits: LimitsConfig::default(),
+        handlers: Vec::new(),
+        policy: PolicyConfig::default(),
+        approval: approval::ApprovalConfig::default(),
+        control: control::ControlConfig::default(),
+        shutdown: ShutdownConfig::default(),
+        outbox: outbox::OutboxConfig::default(),
+        defer: DeferConfig::default(),
+        capacity: scheduler::CapacityConfig::default(),
+        actions: Vec::new(),
     })
 }
 
@@ -142,10 +463,33 @@ fn handle_keygen() -> Result<()> {
     Ok(())
 }
 
-fn handle_add_peer(agent_id: String, public_key: String) -> Result<()> {
+fn open_keyring(config: &Config) -> Result<keyring::Keyring> {
+    let keyring_path = shellexpand::tilde(&config.keys.keyring_path).into_owned();
+    keyring::Keyring::load(std::path::PathBuf::from(keyring_path))
+}
+
+fn open_blocklist(config: &Config) -> Result<blocklist::Blocklist> {
+    let blocklist_path = shellexpand::tilde(&config.blocklist.path).into_owned();
+    blocklist::Blocklist::load(std::path::PathBuf::from(blocklist_path))
+}
+
+fn parse_endpoints(endpoints: &[String]) -> Result<Vec<Endpoint>> {
+    endpoints.iter().map(|e| Endpoint::parse(e)).collect()
+}
+
+fn handle_add_peer(
+    config: &Config,
+    agent_id: String,
+    public_key: String,
+    endpoints: Vec<String>,
+    tls_fingerprint: Option<String>,
+    labels: Vec<String>,
+) -> Result<()> {
     use hex;
     use base64::{Engine as _, engine::general_purpose};
 
+    protocol::validate_agent_id(&agent_id)?;
+
     let key_bytes = if public_key.len() == 64 {
         hex::decode(&public_key)?
     } else {
@@ -159,24 +503,79 @@ fn handle_add_peer(agent_id: String, public_key: String) -> Result<()> {
     let mut key_array = [0u8; 32];
     key_array.copy_from_slice(&key_bytes);
 
-    let keyring_path = dirs::home_dir()
-        .ok_or_else(|| anyhow::anyhow!("Cannot determine home directory"))?
-        .join(".wish-protocol/keyring.msgpack");
+    let endpoints = parse_endpoints(&endpoints)?;
+    let tls_fingerprint = tls_fingerprint
+        .map(|f| keyring::normalize_fingerprint(&f))
+        .transpose()?;
 
-    let mut keyring = keyring::Keyring::load(keyring_path)?;
+    let mut keyring = open_keyring(config)?;
     keyring.add(agent_id.clone(), key_array)?;
 
+    if !endpoints.is_empty() || tls_fingerprint.is_some() || !labels.is_empty() {
+        keyring.edit(&agent_id, |entry| {
+            entry.endpoints = endpoints;
+            entry.tls_fingerprint = tls_fingerprint;
+            entry.labels = labels;
+        })?;
+    }
+
     println!("✓ Added peer: {}", agent_id);
 
     Ok(())
 }
 
-fn handle_list_peers() -> Result<()> {
-    let keyring_path = dirs::home_dir()
-        .ok_or_else(|| anyhow::anyhow!("Cannot determine home directory"))?
-        .join(".wish-protocol/keyring.msgpack");
+struct PeerEdits {
+    add_endpoints: Vec<String>,
+    remove_endpoints: Vec<String>,
+    clear_endpoints: bool,
+    tls_fingerprint: Option<String>,
+    clear_tls_fingerprint: bool,
+    add_labels: Vec<String>,
+    remove_labels: Vec<String>,
+}
+
+fn handle_edit_peer(config: &Config, agent_id: &str, edits: PeerEdits) -> Result<()> {
+    let add_endpoints = parse_endpoints(&edits.add_endpoints)?;
+    let remove_endpoints = parse_endpoints(&edits.remove_endpoints)?;
+    let tls_fingerprint = edits
+        .tls_fingerprint
+        .map(|f| keyring::normalize_fingerprint(&f))
+        .transpose()?;
 
-    let keyring = keyring::Keyring::load(keyring_path)?;
+    let mut keyring = open_keyring(config)?;
+    keyring.edit(agent_id, |entry| {
+        if edits.clear_endpoints {
+            entry.endpoints.clear();
+        }
+        entry.endpoints.retain(|e| !remove_endpoints.contains(e));
+        for endpoint in add_endpoints {
+            if !entry.endpoints.contains(&endpoint) {
+                entry.endpoints.push(endpoint);
+            }
+        }
+
+        if edits.clear_tls_fingerprint {
+            entry.tls_fingerprint = None;
+        }
+        if tls_fingerprint.is_some() {
+            entry.tls_fingerprint = tls_fingerprint;
+        }
+
+        entry.labels.retain(|l| !edits.remove_labels.contains(l));
+        for label in edits.add_labels {
+            if !entry.labels.contains(&label) {
+                entry.labels.push(label);
+            }
+        }
+    })?;
+
+    println!("✓ Updated peer: {}", agent_id);
+
+    Ok(())
+}
+
+fn handle_list_peers(config: &Config) -> Result<()> {
+    let keyring = open_keyring(config)?;
     let entries = keyring.list();
 
     if entries.is_empty() {
@@ -187,23 +586,485 @@ fn handle_list_peers() -> Result<()> {
         for entry in entries {
             use chrono::{DateTime, Utc};
             let dt = DateTime::<Utc>::from_timestamp(entry.added_at as i64, 0)
-                .unwrap_or_else(|| Utc::now());
+                .unwrap_or_else(Utc::now);
             println!("  {} (added: {})", entry.agent_id, dt.format("%Y-%m-%d %H:%M:%S"));
+            for endpoint in &entry.endpoints {
+                println!("      endpoint: {}", endpoint);
+            }
+            if let Some(fingerprint) = &entry.tls_fingerprint {
+                println!("      tls: sha256:{}", fingerprint);
+            }
+            if !entry.labels.is_empty() {
+                println!("      labels: {}", entry.labels.join(", "));
+            }
         }
     }
 
     Ok(())
 }
 
-fn handle_gencert() -> Result<()> {
+fn handle_block(config: &Config, agent_id: &str, reason: blocklist::BlockReason) -> Result<()> {
+    protocol::validate_agent_id(agent_id)?;
+
+    let fp = open_keyring(config)?.get(agent_id).map(crypto::key_fingerprint);
+    open_blocklist(config)?.block(agent_id, reason, fp)?;
+
+    println!("✓ Blocked: {} ({})", agent_id, reason.name());
+
+    Ok(())
+}
+
+/// Reads a KNOCK or WISH for the dry-run commands. Only `from` and
+/// `payload` are needed; a handler request is accepted too, since it carries
+/// the message in `msg`.
+fn read_test_message(config: &Config, path: &std::path::Path) -> Result<protocol::Message> {
+    let mut message: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    if let Some(inner) = message.get_mut("msg") {
+        message = inner.take();
+    }
+    let from = message
+        .get("from")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow::anyhow!("Message has no from"))?;
+    let payload: HashMap<String, serde_json::Value> =
+        serde_json::from_value(message.get("payload").cloned().unwrap_or_default())?;
+    let stage = match message.get("stage").and_then(|v| v.as_u64()) {
+        Some(stage) => stage as u8,
+        None if payload.contains_key("task") => protocol::Stage::Wish.to_u8(),
+        None => protocol::Stage::Knock.to_u8(),
+    };
+
+    Ok(protocol::Message {
+        stage,
+        counter: 1,
+        timestamp: protocol::current_timestamp(),
+        from: from.to_string(),
+        to: config.agent.id.clone(),
+        payload,
+    })
+}
+
+fn peer_labels(config: &Config, agent_id: &str) -> Result<Vec<String>> {
+    Ok(open_keyring(config)?
+        .get_entry(agent_id)
+        .map(|e| e.labels.clone())
+        .unwrap_or_default())
+}
+
+/// The WISH payload of a test message, or None for a KNOCK.
+fn test_wish(message: &protocol::Message) -> Result<Option<payload::WishPayload>> {
+    (message.stage == protocol::Stage::Wish.to_u8())
+        .then(|| message.parse())
+        .transpose()
+}
+
+/// A code field of a test message, such as the KNOCK `c` or `pri`.
+fn test_code<T: serde::de::DeserializeOwned>(message: &protocol::Message, key: &str) -> Option<T> {
+    serde_json::from_value(message.payload.get(key)?.clone()).ok()
+}
+
+fn handle_route_test(
+    config: &Config,
+    path: &std::path::Path,
+    category: Option<payload::Category>,
+) -> Result<()> {
+    let message = read_test_message(config, path)?;
+    let labels = peer_labels(config, &message.from)?;
+    let wish = test_wish(&message)?;
+    let target = router::Target {
+        category: category.or_else(|| test_code(&message, "c")),
+        action: wish.as_ref().and_then(payload::WishPayload::action),
+        peer: &message.from,
+        labels: &labels,
+    };
+
+    let default = handler::Handler::new(&config.openclaw.path, &config.openclaw.run);
+    let router = router::Router::new(&config.handlers, default)?;
+    let (name, handler) = router.route(&target);
+    println!("Route: {}", name);
+    println!("Handler: {}", handler);
+
+    Ok(())
+}
+
+fn handle_policy_check(
+    config: &Config,
+    path: &std::path::Path,
+    category: Option<payload::Category>,
+    priority: Option<payload::Priority>,
+) -> Result<()> {
+    let message = read_test_message(config, path)?;
+    let labels = peer_labels(config, &message.from)?;
+    let wish = test_wish(&message)?;
+    let subject = policy::Subject {
+        peer: &message.from,
+        labels: &labels,
+        category: category.or_else(|| test_code(&message, "c")),
+        priority: priority.or_else(|| test_code(&message, "pri")),
+        offer: wish.as_ref().and_then(payload::WishPayload::offer_type),
+        size: wish.is_some().then(|| protocol::encode_message(&message).map(|b| b.len())).transpose()?,
+        time: chrono::Local::now().time(),
+    };
+
+    let verdict = config.policy.load()?.evaluate(&subject);
+    println!("Rule: {}", verdict.rule);
+    println!("Decision: {}", verdict.decision.name());
+    if let Some(reason) = &verdict.reason {
+        println!("Reason: {}", reason);
+    }
+    if let Some(msg) = &verdict.msg {
+        println!("Message: {}", msg);
+    }
+
+    Ok(())
+}
+
+async fn handle_pending(config: &Config) -> Result<()> {
+    use chrono::{DateTime, Utc};
+
+    let data = control::send(&config.control.path(), &control::Request::Pending).await?;
+    let items: Vec<approval::PendingItem> = serde_json::from_value(data)?;
+
+    if items.is_empty() {
+        println!("Nothing awaiting approval.");
+        return Ok(());
+    }
+
+    println!("Awaiting approval:");
+    for item in items {
+        let queued = DateTime::<Utc>::from_timestamp(item.queued as i64, 0)
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        println!("  {}  {} from {} (queued {})", item.id, item.phase.name(), item.peer, queued);
+        if let Some(preview) = &item.preview {
+            println!("      {}", preview);
+        }
+        if let Some(offer) = &item.offer {
+            println!("      offer: {}", offer);
+        }
+    }
+
+    Ok(())
+}
+
+/// Asks the running daemon, if any, to pick up a keyring edit.
+async fn notify_keyring_changed(config: &Config) {
+    let request = control::Request::ReloadKeyring;
+    if let Some(Err(e)) = control::send_if_running(&config.control.path(), &request).await {
+        eprintln!("Warning: The daemon did not reload the keyring: {}", e);
+    }
+}
+
+async fn handle_list_blocklist(config: &Config) -> Result<()> {
+    use chrono::{DateTime, Utc};
+
+    let entries: Vec<blocklist::BlocklistEntry> =
+        match control::send_if_running(&config.control.path(), &control::Request::Blocklist).await {
+            Some(data) => serde_json::from_value(data?)?,
+            None => open_blocklist(config)?.list().into_iter().cloned().collect(),
+        };
+
+    if entries.is_empty() {
+        println!("No blocked agents.");
+        return Ok(());
+    }
+
+    println!("Blocked agents:");
+    for entry in entries {
+        let dt = DateTime::<Utc>::from_timestamp(entry.at as i64, 0).unwrap_or_else(Utc::now);
+        let by = match entry.by {
+            blocklist::BlockedBy::Manual => "manual",
+            blocklist::BlockedBy::Automatic => "automatic",
+        };
+        println!(
+            "  {} ({}, {}, blocked: {}, violations: {})",
+            entry.id,
+            entry.r.name(),
+            by,
+            dt.format("%Y-%m-%d %H:%M:%S"),
+            entry.c
+        );
+    }
+
+    Ok(())
+}
+
+/// Resolves on Ctrl-C. A second Ctrl-C exits without waiting for what the
+/// first one started.
+async fn interrupted() {
+    let _ = tokio::signal::ctrl_c().await;
+    tokio::spawn(async {
+        let _ = tokio::signal::ctrl_c().await;
+        std::process::exit(130);
+    });
+}
+
+/// Polls the daemon until outbox job `job` is finished, reporting retries.
+async fn wait_for_job(config: &Config, mut job: outbox::Job) -> Result<outbox::Job> {
+    let request = control::Request::Job { id: job.id.clone() };
+    let mut attempts = 0;
+    let mut awaiting = false;
+    let cancel = interrupted();
+    tokio::pin!(cancel);
+    let mut cancelling = false;
+    while !job.state.is_done() {
+        tokio::select! {
+            _ = tokio::time::sleep(std::time::Duration::from_secs(1)) => {}
+            _ = &mut cancel, if !cancelling => {
+                cancelling = true;
+                eprintln!("Cancelling {}", job.id);
+                let request = control::Request::CancelJob { id: job.id.clone() };
+                if let Err(e) = control::send(&config.control.path(), &request).await {
+                    eprintln!("Could not cancel {}: {}", job.id, e);
+                }
+            }
+        }
+        job = serde_json::from_value(control::send(&config.control.path(), &request).await?)?;
+        if job.state == outbox::JobState::Awaiting && !awaiting {
+            awaiting = true;
+            eprintln!(
+                "Granted with ticket {}; waiting for the GIFT",
+                job.ticket.as_deref().unwrap_or("?")
+            );
+        }
+        if job.state == outbox::JobState::Queued && job.attempts > attempts {
+            attempts = job.attempts;
+            let wait = job.next_attempt.saturating_sub(protocol::current_timestamp() as u64);
+            eprintln!(
+                "Attempt {} failed: {}; retrying in {}s",
+                attempts,
+                job.error.as_deref().unwrap_or("unknown error"),
+                wait
+            );
+        }
+    }
+    Ok(job)
+}
+
+/// Prints a finished job like a direct `wishp send` would.
+fn print_job_result(job: &outbox::Job) -> Result<()> {
+    if let Some(result) = &job.result {
+        println!("{}", serde_json::to_string_pretty(result)?);
+    }
+    match job.state {
+        outbox::JobState::Failed => {
+            eprintln!("Error sending message: {}", job.error.as_deref().unwrap_or("unknown error"));
+            std::process::exit(1);
+        }
+        outbox::JobState::Cancelled => {
+            eprintln!("Cancelled");
+            std::process::exit(1);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+async fn handle_result(config: &Config, ticket: &str) -> Result<()> {
+    let request = control::Request::Ticket { id: ticket.to_string() };
+    let job: outbox::Job = match control::send_if_running(&config.control.path(), &request).await {
+        Some(data) => serde_json::from_value(data?)?,
+        None => outbox::Outbox::load(config.outbox.path())?.by_ticket(ticket)?,
+    };
+
+    match job.state {
+        outbox::JobState::Delivered | outbox::JobState::Failed | outbox::JobState::Cancelled => print_job_result(&job),
+        state => {
+            eprintln!("Ticket {} is {}; no GIFT yet.", ticket, state.name());
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Refuses a WISH that the peer's cached capability list rules out.
+fn check_capabilities(config: &Config, target: &str, input: &HashMap<String, serde_json::Value>) -> Result<()> {
+    let agent_id = client::Destination::resolve(target, config)?.agent_id;
+    let Some(cached) = capabilities::Capabilities::load_cached(&agent_id)? else {
+        return Ok(());
+    };
+    cached.check(&payload::WishPayload::from_map(input)?).map_err(|e| {
+        anyhow::anyhow!(
+            "WISH does not match the capabilities of {}: {}\n(refresh with `wishp capabilities {}`, or send with --no-check)",
+            agent_id,
+            e,
+            target
+        )
+    })
+}
+
+async fn handle_capabilities(config: &Config, target: &str, json: bool) -> Result<()> {
+    let destination = client::Destination::resolve(target, config)?;
+    let fetched = client::fetch_capabilities(&destination, config).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&fetched)?);
+        return Ok(());
+    }
+
+    if fetched.actions.is_empty() {
+        println!("{} advertises no actions.", destination.agent_id);
+        return Ok(());
+    }
+    println!("Capabilities of {} (digest {}):", destination.agent_id, fetched.h);
+    for capability in &fetched.actions {
+        match &capability.desc {
+            Some(desc) => println!("  {}  {}", capability.act, desc),
+            None => println!("  {}", capability.act),
+        }
+        if let Some(par) = &capability.par {
+            let required: Vec<&str> = par["required"].as_array().into_iter().flatten().filter_map(|r| r.as_str()).collect();
+            let params: Vec<String> = match par["properties"].as_object() {
+                Some(properties) => properties
+                    .iter()
+                    .map(|(name, field)| {
+                        let kind = field["type"].as_str().unwrap_or("any");
+                        let required = if required.contains(&name.as_str()) { ", required" } else { "" };
+                        format!("{} ({}{})", name, kind, required)
+                    })
+                    .collect(),
+                None => vec![par.to_string()],
+            };
+            println!("      params: {}", params.join(", "));
+        }
+        let mut limits = Vec::new();
+        if let Some(max) = capability.max_size {
+            limits.push(format!("up to {} bytes", max));
+        }
+        if let Some(cost) = capability.cost {
+            limits.push(format!("cost {}", cost));
+        }
+        if let Some(time) = capability.time {
+            limits.push(format!("about {}s", time));
+        }
+        if !limits.is_empty() {
+            println!("      {}", limits.join(", "));
+        }
+    }
+    Ok(())
+}
+
+async fn handle_outbox(config: &Config, id: Option<String>) -> Result<()> {
+    use chrono::{DateTime, Utc};
+
+    let jobs: Vec<outbox::Job> = match control::send_if_running(&config.control.path(), &control::Request::Outbox).await {
+        Some(data) => serde_json::from_value(data?)?,
+        None => outbox::Outbox::load(config.outbox.path())?.list(),
+    };
+
+    if let Some(id) = id {
+        let job = jobs
+            .into_iter()
+            .find(|j| j.id == id)
+            .ok_or_else(|| anyhow::anyhow!("No outbox job {}", id))?;
+        println!("{}", serde_json::to_string_pretty(&job)?);
+        return Ok(());
+    }
+
+    if jobs.is_empty() {
+        println!("Outbox is empty.");
+        return Ok(());
+    }
+
+    let time = |t: u64| {
+        DateTime::<Utc>::from_timestamp(t as i64, 0)
+            .unwrap_or_else(Utc::now)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    };
+    println!("Outbox:");
+    for job in jobs {
+        println!(
+            "  {}  {}, {}, {} attempts, queued {}",
+            job.id,
+            job.target,
+            job.state.name(),
+            job.attempts,
+            time(job.created)
+        );
+        if job.state == outbox::JobState::Queued && job.attempts > 0 {
+            println!("      next attempt {}", time(job.next_attempt));
+        }
+        if let (Some(ticket), Some(expires)) = (&job.ticket, job.expires) {
+            println!("      ticket {}, expires {}", ticket, time(expires));
+        }
+        if let Some(error) = &job.error {
+            println!("      {}", error);
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_limits(config: &Config) -> Result<()> {
+    use ratelimit::format_bytes;
+
+    let usage: Vec<ratelimit::Usage> =
+        match control::send_if_running(&config.control.path(), &control::Request::RateLimits).await {
+            Some(data) => serde_json::from_value(data?)?,
+            None => {
+                let path = shellexpand::tilde(&config.limits.state_path).into_owned();
+                ratelimit::RateLimiter::load(std::path::PathBuf::from(path))?.usage()
+            }
+        };
+
+    if usage.is_empty() {
+        println!("No rate limit usage.");
+        return Ok(());
+    }
+
+    println!("Rate limit usage:");
+    for u in usage {
+        let limits = config.limits.for_peer(&u.peer);
+        println!("  {}", u.peer);
+        println!("      knocks: {}/{} this hour", u.knocks_hour, limits.knocks_per_hour);
+        println!("      messages: {}/{} today", u.messages_day, limits.messages_per_day);
+        println!(
+            "      bytes: {} of {} this hour, {} of {} today",
+            format_bytes(u.bytes_hour),
+            format_bytes(limits.bytes_per_hour),
+            format_bytes(u.bytes_day),
+            format_bytes(limits.bytes_per_day)
+        );
+    }
+
+    Ok(())
+}
+
+async fn handle_conversations(config: &Config) -> Result<()> {
+    use chrono::{DateTime, Utc};
+
+    let data = control::send(&config.control.path(), &control::Request::Conversations).await?;
+    let conversations: Vec<conversations::ConversationInfo> = serde_json::from_value(data)?;
+
+    if conversations.is_empty() {
+        println!("No active conversations.");
+        return Ok(());
+    }
+
+    println!("Active conversations:");
+    for c in conversations {
+        let started = DateTime::<Utc>::from_timestamp(c.started as i64, 0).unwrap_or_else(Utc::now);
+        println!(
+            "  {}  {} ({}), {}, started {}",
+            c.id,
+            c.peer,
+            c.addr,
+            c.stage,
+            started.format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+
+    Ok(())
+}
+
+fn handle_gencert(subject_alt_names: Vec<String>) -> Result<()> {
     use rcgen::generate_simple_self_signed;
 
-    println!("Generating self-signed certificate...");
+    println!("Generating self-signed certificate for {}...", subject_alt_names.join(", "));
 
-    let subject_alt_names = vec!["localhost".to_string()];
     let certified_key = generate_simple_self_signed(subject_alt_names)
         .map_err(|e| anyhow::anyhow!("Failed to generate certificate: {}", e))?;
 
+    let fingerprint = crypto::cert_fingerprint(certified_key.cert.der());
     let cert_pem = certified_key.cert.pem();
     let key_pem = certified_key.key_pair.serialize_pem();
 
@@ -217,6 +1078,9 @@ fn handle_gencert() -> Result<()> {
 
     println!("✓ Certificate saved to ~/.wish-protocol/cert.pem");
     println!("✓ Private key saved to ~/.wish-protocol/key.pem");
+    println!();
+    println!("Certificate fingerprint: sha256:{}", fingerprint);
+    println!("Peers can pin it with: wishp edit-peer <your-agent-id> --tls-fingerprint <fingerprint>");
 
     Ok(())
 }
@@ -0,0 +1,625 @@
+use crate::client::{self, Destination, SendOutcome};
+use crate::daemon::Config;
+use crate::payload::{GiftPayload, GrantPayload, Reason, StagePayload, Status, WelcomePayload};
+use crate::protocol::{self, PeerError, RejectReason, Stage};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{watch, Notify};
+
+/// First and longest wait between delivery attempts (spec §13.6).
+const BASE_BACKOFF: u64 = 60;
+const MAX_BACKOFF: u64 = 3600;
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct OutboxConfig {
+    pub dir: String,
+    /// Attempts after which a wish that never got through is failed.
+    pub max_attempts: u32,
+    /// Seconds finished jobs are kept for `wishp outbox`.
+    pub keep: u64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            dir: "~/.wish-protocol/outbox".to_string(),
+            max_attempts: 10,
+            keep: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl OutboxConfig {
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(shellexpand::tilde(&self.dir).into_owned())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    #[default]
+    Wish,
+    /// The GIFT of a deferred task, going back to the requester.
+    Gift,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    /// Waiting for the next attempt.
+    Queued,
+    Sending,
+    /// Granted with a ticket; the peer delivers the GIFT later.
+    Awaiting,
+    /// The peer sent a GIFT.
+    Delivered,
+    /// The peer declined for a reason retrying will not fix.
+    Declined,
+    Failed,
+    /// Withdrawn by `wishp send` or `wishp outbox --cancel`.
+    Cancelled,
+}
+
+impl JobState {
+    pub fn name(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Sending => "sending",
+            JobState::Awaiting => "awaiting",
+            JobState::Delivered => "delivered",
+            JobState::Declined => "declined",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn is_done(self) -> bool {
+        matches!(self, JobState::Delivered | JobState::Declined | JobState::Failed | JobState::Cancelled)
+    }
+}
+
+/// An outbound wish, stored as `<dir>/<id>.json`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Job {
+    pub id: String,
+    #[serde(default)]
+    pub kind: JobKind,
+    /// As given to `wishp send`.
+    pub target: String,
+    /// Agent ID of the target.
+    #[serde(default)]
+    pub peer: String,
+    /// The WISH payload, from which the KNOCK takes `c`, `pri` and `prev`,
+    /// or the GIFT payload.
+    pub payload: HashMap<String, serde_json::Value>,
+    /// Program that picks counter-proposal options; the first option is
+    /// taken without one.
+    pub select_script: Option<String>,
+    pub state: JobState,
+    pub attempts: u32,
+    /// Unix times.
+    pub created: u64,
+    pub next_attempt: u64,
+    /// Why the last attempt failed.
+    pub error: Option<String>,
+    /// What `wishp send` prints: the GIFT or decline payload, or the ERROR.
+    pub result: Option<serde_json::Value>,
+    /// Ticket of a deferred GIFT: issued by the peer's GRANT for a wish, or
+    /// answered by a GIFT job.
+    #[serde(default)]
+    pub ticket: Option<String>,
+    /// Unix time the ticket expires.
+    #[serde(default)]
+    pub expires: Option<u64>,
+}
+
+/// How a delivery attempt ended.
+#[derive(Debug, PartialEq)]
+enum Attempt {
+    Done(JobState, Option<serde_json::Value>),
+    /// Granted with a ticket for a later GIFT.
+    Deferred { ticket: String, expires: Option<u64> },
+    /// Try again, after `retry` seconds if the peer said so.
+    Retry { retry: Option<u64>, error: String },
+    Failed { error: String, result: Option<serde_json::Value> },
+}
+
+/// Outbound wishes the daemon delivers and retries on behalf of `wishp send`.
+pub struct Outbox {
+    dir: PathBuf,
+    jobs: Mutex<HashMap<String, Job>>,
+    wake: Notify,
+    /// Cancel requests for the jobs being sent.
+    sending: Mutex<HashMap<String, watch::Sender<bool>>>,
+}
+
+impl Outbox {
+    /// Reads the jobs in `dir`. Jobs that were being sent when the daemon
+    /// stopped are queued again.
+    pub fn load(dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut jobs = HashMap::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let job: Result<Job> = std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| Ok(serde_json::from_slice(&bytes)?));
+            match job {
+                Ok(mut job) => {
+                    if job.state == JobState::Sending {
+                        job.state = JobState::Queued;
+                    }
+                    jobs.insert(job.id.clone(), job);
+                }
+                Err(e) => eprintln!("Warning: Skipping outbox entry {}: {}", path.display(), e),
+            }
+        }
+        Ok(Self { dir, jobs: Mutex::new(jobs), wake: Notify::new(), sending: Mutex::new(HashMap::new()) })
+    }
+
+    /// Queues a wish to `peer` for delivery as soon as possible.
+    pub fn enqueue(
+        &self,
+        target: String,
+        peer: String,
+        payload: HashMap<String, serde_json::Value>,
+        select_script: Option<String>,
+    ) -> Result<Job> {
+        self.push(Job { select_script, ..Job::new(target, peer, payload) })
+    }
+
+    /// Queues the GIFT of a deferred task; `gift` carries the ticket.
+    pub fn enqueue_gift(&self, peer: &str, gift: &GiftPayload, expires: u64) -> Result<Job> {
+        self.push(Job {
+            kind: JobKind::Gift,
+            ticket: gift.tkt.clone(),
+            expires: Some(expires),
+            ..Job::new(peer.to_string(), peer.to_string(), gift.to_map())
+        })
+    }
+
+    fn push(&self, job: Job) -> Result<Job> {
+        self.save(&job)?;
+        self.jobs.lock().unwrap().insert(job.id.clone(), job.clone());
+        self.wake.notify_one();
+        Ok(job)
+    }
+
+    pub fn list(&self) -> Vec<Job> {
+        let mut jobs: Vec<_> = self.jobs.lock().unwrap().values().cloned().collect();
+        jobs.sort_by_key(|j| j.created);
+        jobs
+    }
+
+    /// Whether `peer` may deliver the GIFT for `ticket`: one of our wishes
+    /// to that peer was granted with it and it has not expired.
+    pub fn expects_gift(&self, ticket: &str, peer: &str) -> bool {
+        awaiting(&mut self.jobs.lock().unwrap(), ticket, peer).is_some()
+    }
+
+    /// Stores the GIFT `peer` delivered for `ticket`.
+    pub fn receive_gift(&self, ticket: &str, peer: &str, gift: serde_json::Value) -> Result<()> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = awaiting(&mut jobs, ticket, peer)
+            .ok_or_else(|| anyhow!("No deferred GIFT expected from {} for ticket {}", peer, ticket))?;
+        job.state = JobState::Delivered;
+        job.result = Some(gift);
+        eprintln!("Outbox: {} to {} delivered by ticket {}", job.id, job.target, ticket);
+        self.save(job)
+    }
+
+    /// The wish that was granted with `ticket`.
+    pub fn by_ticket(&self, ticket: &str) -> Result<Job> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .find(|j| j.kind == JobKind::Wish && j.ticket.as_deref() == Some(ticket))
+            .cloned()
+            .ok_or_else(|| anyhow!("No outbox job for ticket {}", ticket))
+    }
+
+    pub fn get(&self, id: &str) -> Result<Job> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| anyhow!("No outbox job {}", id))
+    }
+
+    /// Withdraws job `id`. One being sent asks the peer to stop the task and
+    /// ends with its cancelled GIFT; one granted with a ticket no longer
+    /// takes the GIFT.
+    pub fn cancel(&self, id: &str) -> Result<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get_mut(id).ok_or_else(|| anyhow!("No outbox job {}", id))?;
+        match job.state {
+            JobState::Sending => {
+                self.sending
+                    .lock()
+                    .unwrap()
+                    .entry(job.id.clone())
+                    .or_insert_with(|| watch::channel(false).0)
+                    .send_replace(true);
+            }
+            JobState::Queued | JobState::Awaiting => {
+                job.state = JobState::Cancelled;
+                eprintln!("Outbox: {} to {} cancelled", job.id, job.target);
+                self.save(job)?;
+            }
+            state => return Err(anyhow!("Job {} is already {}", id, state.name())),
+        }
+        Ok(job.clone())
+    }
+
+    /// Marks the jobs due at `now` as being sent and returns them.
+    pub fn take_due(&self, now: u64) -> Vec<Job> {
+        let mut due = Vec::new();
+        for job in self.jobs.lock().unwrap().values_mut() {
+            if job.state == JobState::Queued && job.next_attempt <= now {
+                job.state = JobState::Sending;
+                due.push(job.clone());
+            }
+        }
+        due
+    }
+
+    /// Unix time of the next queued attempt.
+    pub fn next_due(&self) -> Option<u64> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.values().filter(|j| j.state == JobState::Queued).map(|j| j.next_attempt).min()
+    }
+
+    /// Resolves when a job is queued.
+    pub async fn woken(&self) {
+        self.wake.notified().await
+    }
+
+    /// Fails jobs whose ticket ran out and forgets finished jobs older than
+    /// `keep` seconds.
+    pub fn expire(&self, keep: u64) {
+        let now = now();
+        let mut jobs = self.jobs.lock().unwrap();
+        for job in jobs.values_mut() {
+            let waiting = matches!(job.state, JobState::Queued | JobState::Awaiting);
+            if waiting && job.expires.is_some_and(|e| e <= now) {
+                job.state = JobState::Failed;
+                job.error = Some(format!("Ticket {} expired", job.ticket.as_deref().unwrap_or("?")));
+                if let Err(e) = self.save(job) {
+                    eprintln!("Warning: Could not save outbox job {}: {}", job.id, e);
+                }
+            }
+        }
+
+        let cutoff = now.saturating_sub(keep);
+        jobs.retain(|id, job| {
+            if !job.state.is_done() || job.created > cutoff {
+                return true;
+            }
+            let _ = std::fs::remove_file(self.dir.join(format!("{}.json", id)));
+            false
+        });
+    }
+
+    /// Makes one delivery attempt for `job` and records how it went.
+    pub async fn deliver(&self, mut job: Job, config: &Config) {
+        let withdrawn = self
+            .sending
+            .lock()
+            .unwrap()
+            .entry(job.id.clone())
+            .or_insert_with(|| watch::channel(false).0)
+            .subscribe();
+        let mut cancel = withdrawn.clone();
+        let cancel = async move {
+            let _ = cancel.wait_for(|&cancelled| cancelled).await;
+        };
+
+        let attempt = match Destination::resolve(&job.target, config) {
+            Ok(destination) => match job.kind {
+                JobKind::Wish => {
+                    let selector: Box<dyn client::OptionSelector> = match &job.select_script {
+                        Some(path) => Box::new(client::ScriptSelector { path: path.clone() }),
+                        None => Box::new(client::FirstOption),
+                    };
+                    // The daemon is here to take a deferred GIFT.
+                    let mut payload = job.payload.clone();
+                    payload.insert("dfr".to_string(), serde_json::json!(true));
+                    classify(client::send_message(&destination, payload, config, selector.as_ref(), cancel).await)
+                }
+                JobKind::Gift => match GiftPayload::from_map(&job.payload) {
+                    Ok(gift) => classify_gift(client::deliver_gift(&destination, &gift, config).await),
+                    Err(e) => Attempt::Failed { error: e.to_string(), result: None },
+                },
+            },
+            Err(e) => Attempt::Retry { retry: None, error: e.to_string() },
+        };
+
+        // Under the lock, so `cancel` sees either the job being sent or its
+        // new state.
+        let mut jobs = self.jobs.lock().unwrap();
+        self.sending.lock().unwrap().remove(&job.id);
+        let attempt = match attempt {
+            Attempt::Done(..) => attempt,
+            _ if *withdrawn.borrow() => Attempt::Done(JobState::Cancelled, None),
+            attempt => attempt,
+        };
+
+        job.attempts += 1;
+        match attempt {
+            Attempt::Done(state, result) => {
+                job.state = state;
+                job.error = None;
+                job.result = result;
+            }
+            Attempt::Deferred { ticket, expires } => {
+                eprintln!("Outbox: {} to {} granted with ticket {}", job.id, job.target, ticket);
+                job.state = JobState::Awaiting;
+                job.error = None;
+                job.ticket = Some(ticket);
+                job.expires = expires;
+            }
+            Attempt::Retry { error, .. } if job.attempts >= config.outbox.max_attempts => {
+                job.state = JobState::Failed;
+                job.error = Some(format!("Gave up after {} attempts: {}", job.attempts, error));
+            }
+            Attempt::Retry { retry, error } => {
+                let wait = retry.unwrap_or_else(|| backoff(job.attempts - 1));
+                eprintln!("Outbox: {} to {} will retry in {}s: {}", job.id, job.target, wait, error);
+                job.state = JobState::Queued;
+                job.next_attempt = now().saturating_add(wait);
+                job.error = Some(error);
+            }
+            Attempt::Failed { error, result } => {
+                job.state = JobState::Failed;
+                job.error = Some(error);
+                job.result = result;
+            }
+        }
+        if job.state.is_done() {
+            eprintln!("Outbox: {} to {} {}", job.id, job.target, job.state.name());
+        }
+
+        if let Err(e) = self.save(&job) {
+            eprintln!("Warning: Could not save outbox job {}: {}", job.id, e);
+        }
+        jobs.insert(job.id.clone(), job);
+    }
+
+    fn save(&self, job: &Job) -> Result<()> {
+        let path = self.dir.join(format!("{}.json", job.id));
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, serde_json::to_vec_pretty(job)?)?;
+        std::fs::rename(&temp, &path)?;
+        Ok(())
+    }
+}
+
+/// The unexpired wish to `peer` that was granted with `ticket`.
+fn awaiting<'a>(jobs: &'a mut HashMap<String, Job>, ticket: &str, peer: &str) -> Option<&'a mut Job> {
+    let now = now();
+    jobs.values_mut().find(|j| {
+        j.kind == JobKind::Wish
+            && j.state == JobState::Awaiting
+            && j.ticket.as_deref() == Some(ticket)
+            && j.peer == peer
+            && j.expires.unwrap_or(u64::MAX) > now
+    })
+}
+
+/// Seconds to wait after `rejections` failed attempts (spec §13.6).
+pub fn backoff(rejections: u32) -> u64 {
+    BASE_BACKOFF.saturating_mul(1u64.checked_shl(rejections).unwrap_or(u64::MAX)).min(MAX_BACKOFF)
+}
+
+/// Sorts an attempt's outcome into done, worth retrying, or failed. Declines
+/// for being busy or rate limited, recoverable ERRORs and connection
+/// failures are retried; other declines and ERRORs are final.
+fn classify(sent: Result<SendOutcome>) -> Attempt {
+    let outcome = match sent {
+        Ok(outcome) => outcome,
+        Err(e) => return classify_error(e),
+    };
+
+    let response = &outcome.response;
+    if let Ok(gift) = response.parse::<GiftPayload>() {
+        let state = if gift.cncl { JobState::Cancelled } else { JobState::Delivered };
+        return Attempt::Done(state, Some(outcome.output()));
+    }
+    if let Ok(GrantPayload { tkt: Some(ticket), exp, .. }) = response.parse() {
+        return Attempt::Deferred { ticket, expires: exp };
+    }
+    if let Some(retry) = temporary_decline(response) {
+        return retry;
+    }
+    Attempt::Done(JobState::Declined, Some(outcome.output()))
+}
+
+/// Like `classify`, for a deferred GIFT: `declined` is the requester's
+/// WELCOME if it would not take the GIFT.
+fn classify_gift(sent: Result<Option<protocol::Message>>) -> Attempt {
+    match sent {
+        Ok(None) => Attempt::Done(JobState::Delivered, None),
+        Ok(Some(declined)) => temporary_decline(&declined).unwrap_or_else(|| Attempt::Failed {
+            error: "The requester declined the GIFT".to_string(),
+            result: Some(serde_json::json!(declined.payload)),
+        }),
+        Err(e) => classify_error(e),
+    }
+}
+
+fn classify_error(e: anyhow::Error) -> Attempt {
+    match e.downcast_ref::<PeerError>() {
+        Some(PeerError(error)) if error.recov => Attempt::Retry {
+            retry: error.det.as_ref().and_then(|d| d.get("retry")).and_then(|v| v.as_u64()),
+            error: e.to_string(),
+        },
+        Some(PeerError(error)) => Attempt::Failed {
+            error: e.to_string(),
+            result: Some(serde_json::json!(error.to_payload())),
+        },
+        None => Attempt::Retry { retry: None, error: e.to_string() },
+    }
+}
+
+/// A retry for a WELCOME or GRANT that declined for being busy or rate
+/// limited, or that gave a `retry` hint.
+fn temporary_decline(response: &protocol::Message) -> Option<Attempt> {
+    let (st, r, retry, msg) = match Stage::from_u8(response.stage).ok()? {
+        Stage::Welcome => {
+            let welcome: WelcomePayload = response.parse().ok()?;
+            (welcome.st, welcome.r, welcome.retry, welcome.msg)
+        }
+        Stage::Grant => {
+            let grant: GrantPayload = response.parse().ok()?;
+            (grant.st, grant.r, grant.retry, grant.msg)
+        }
+        _ => return None,
+    };
+    let temporary = [RejectReason::Busy, RejectReason::RateLimited].map(Reason::Code);
+    if retry.is_some() || st == Status::Busy || r.is_some_and(|r| temporary.contains(&r)) {
+        let msg = msg.as_deref().unwrap_or("busy");
+        return Some(Attempt::Retry { retry, error: format!("Declined: {}", msg) });
+    }
+    None
+}
+
+impl Job {
+    fn new(target: String, peer: String, payload: HashMap<String, serde_json::Value>) -> Self {
+        let now = now();
+        Self {
+            id: hex::encode(rand::random::<[u8; 8]>()),
+            kind: JobKind::Wish,
+            target,
+            peer,
+            payload,
+            select_script: None,
+            state: JobState::Queued,
+            attempts: 0,
+            created: now,
+            next_attempt: now,
+            error: None,
+            result: None,
+            ticket: None,
+            expires: None,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ErrorCode, Message, ProtocolError};
+
+    fn outcome(stage: Stage, payload: serde_json::Value) -> Result<SendOutcome> {
+        Ok(SendOutcome {
+            response: Message {
+                stage: stage.to_u8(),
+                counter: 2,
+                timestamp: 0,
+                from: "agent-b".to_string(),
+                to: "agent-a".to_string(),
+                payload: serde_json::from_value(payload).unwrap(),
+            },
+            agreed: None,
+        })
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0), 60);
+        assert_eq!(backoff(3), 480);
+        assert_eq!(backoff(6), 3600);
+        assert_eq!(backoff(100), 3600);
+    }
+
+    #[test]
+    fn test_classify() {
+        let gift = classify(outcome(Stage::Gift, serde_json::json!({"ok": true})));
+        assert!(matches!(gift, Attempt::Done(JobState::Delivered, _)));
+
+        let busy = classify(outcome(Stage::Welcome, serde_json::json!({"st": 2, "r": 1, "retry": 30, "eph_key": [], "auth": []})));
+        assert!(matches!(busy, Attempt::Retry { retry: Some(30), .. }));
+
+        let cancelled = classify(outcome(Stage::Gift, serde_json::json!({"ok": false, "cncl": true})));
+        assert!(matches!(cancelled, Attempt::Done(JobState::Cancelled, _)));
+
+        let declined = classify(outcome(Stage::Grant, serde_json::json!({"st": 2, "r": 4})));
+        assert!(matches!(declined, Attempt::Done(JobState::Declined, _)));
+
+        let error = ProtocolError::new(ErrorCode::InternalError, "Shutting down")
+            .with_details(serde_json::json!({"retry": 45}))
+            .recoverable();
+        let shutdown = classify(Err(PeerError(error).into()));
+        assert!(matches!(shutdown, Attempt::Retry { retry: Some(45), .. }));
+
+        let fatal = classify(Err(PeerError(ProtocolError::new(ErrorCode::InternalError, "No")).into()));
+        assert!(matches!(fatal, Attempt::Failed { result: Some(_), .. }));
+
+        assert!(matches!(classify(Err(anyhow!("Connection refused"))), Attempt::Retry { retry: None, .. }));
+    }
+
+    #[test]
+    fn test_ticket_keyed_to_peer_and_expiry() {
+        let dir = std::env::temp_dir().join(format!("wishp-outbox-tickets-{}", std::process::id()));
+        let outbox = Outbox::load(dir.clone()).unwrap();
+        let job = outbox.enqueue("agent-b".to_string(), "agent-b".to_string(), HashMap::new(), None).unwrap();
+
+        let granted = classify(outcome(Stage::Grant, serde_json::json!({"st": 1, "est_t": 900, "tkt": "t1", "exp": now() + 60})));
+        let Attempt::Deferred { ticket, expires } = granted else {
+            panic!("expected a deferral, got {:?}", granted);
+        };
+        {
+            let mut jobs = outbox.jobs.lock().unwrap();
+            let job = jobs.get_mut(&job.id).unwrap();
+            job.state = JobState::Awaiting;
+            job.ticket = Some(ticket);
+            job.expires = expires;
+        }
+
+        assert!(!outbox.expects_gift("t1", "agent-c"));
+        assert!(outbox.receive_gift("t1", "agent-c", serde_json::json!({"ok": true})).is_err());
+        assert!(outbox.expects_gift("t1", "agent-b"));
+        outbox.receive_gift("t1", "agent-b", serde_json::json!({"ok": true})).unwrap();
+        assert_eq!(outbox.by_ticket("t1").unwrap().state, JobState::Delivered);
+
+        let gift = GiftPayload { tkt: Some("t2".to_string()), ..GiftPayload::new(true, serde_json::Value::Null) };
+        let late = outbox.enqueue_gift("agent-b", &gift, now() - 1).unwrap();
+        outbox.expire(60);
+        assert_eq!(outbox.get(&late.id).unwrap().state, JobState::Failed);
+        assert!(outbox.take_due(now()).is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_cancel() {
+        let dir = std::env::temp_dir().join(format!("wishp-outbox-cancel-{}", std::process::id()));
+        let outbox = Outbox::load(dir.clone()).unwrap();
+        let queued = outbox.enqueue("agent-b".to_string(), "agent-b".to_string(), HashMap::new(), None).unwrap();
+        assert_eq!(outbox.cancel(&queued.id).unwrap().state, JobState::Cancelled);
+        assert!(outbox.cancel(&queued.id).is_err());
+
+        // A job being sent stays so until the peer answers the cancel.
+        let sending = outbox.enqueue("agent-b".to_string(), "agent-b".to_string(), HashMap::new(), None).unwrap();
+        assert_eq!(outbox.take_due(now()).len(), 1);
+        assert_eq!(outbox.cancel(&sending.id).unwrap().state, JobState::Sending);
+        assert!(*outbox.sending.lock().unwrap()[&sending.id].borrow());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,570 @@
+use crate::protocol::{CounterProposal, ErrorCode, Message, ProtocolError, RejectReason, Stage};
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// KNOCK `c` (spec §7.1).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum Category {
+    TaskRequest = 1,
+    InfoShare = 2,
+    Question = 3,
+    Tip = 4,
+    Barter = 5,
+    DocumentShare = 6,
+    KnowledgeTransfer = 7,
+}
+
+impl TryFrom<u8> for Category {
+    type Error = String;
+
+    fn try_from(code: u8) -> Result<Self, String> {
+        match code {
+            1 => Ok(Category::TaskRequest),
+            2 => Ok(Category::InfoShare),
+            3 => Ok(Category::Question),
+            4 => Ok(Category::Tip),
+            5 => Ok(Category::Barter),
+            6 => Ok(Category::DocumentShare),
+            7 => Ok(Category::KnowledgeTransfer),
+            _ => Err(format!("Invalid category: {}", code)),
+        }
+    }
+}
+
+impl From<Category> for u8 {
+    fn from(category: Category) -> u8 {
+        category as u8
+    }
+}
+
+/// Reads a `Category` or `Priority` from its code as text, as in config
+/// keys and command line flags.
+pub fn parse_code<T: TryFrom<u8, Error = String>>(text: &str) -> Result<T, String> {
+    let code: u8 = text.trim().parse().map_err(|_| format!("Invalid code: {}", text))?;
+    T::try_from(code)
+}
+
+/// KNOCK `pri`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum Priority {
+    Low = 1,
+    Normal = 2,
+    High = 3,
+    Urgent = 4,
+}
+
+impl TryFrom<u8> for Priority {
+    type Error = String;
+
+    fn try_from(code: u8) -> Result<Self, String> {
+        match code {
+            1 => Ok(Priority::Low),
+            2 => Ok(Priority::Normal),
+            3 => Ok(Priority::High),
+            4 => Ok(Priority::Urgent),
+            _ => Err(format!("Invalid priority: {}", code)),
+        }
+    }
+}
+
+impl From<Priority> for u8 {
+    fn from(priority: Priority) -> u8 {
+        priority as u8
+    }
+}
+
+/// WELCOME and GRANT `st`. WELCOME uses the first three, GRANT all but busy.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum Status {
+    Accept = 1,
+    Decline = 2,
+    Busy = 3,
+    Negotiate = 4,
+}
+
+impl TryFrom<u8> for Status {
+    type Error = String;
+
+    fn try_from(code: u8) -> Result<Self, String> {
+        match code {
+            1 => Ok(Status::Accept),
+            2 => Ok(Status::Decline),
+            3 => Ok(Status::Busy),
+            4 => Ok(Status::Negotiate),
+            _ => Err(format!("Invalid status: {}", code)),
+        }
+    }
+}
+
+impl From<Status> for u8 {
+    fn from(status: Status) -> u8 {
+        status as u8
+    }
+}
+
+/// THANK `ctx`: the spec's success, decline and error, and our cancel of a
+/// granted task before its GIFT.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum ThankContext {
+    Success = 1,
+    Decline = 2,
+    Error = 3,
+    Cancel = 4,
+}
+
+impl TryFrom<u8> for ThankContext {
+    type Error = String;
+
+    fn try_from(code: u8) -> Result<Self, String> {
+        match code {
+            1 => Ok(ThankContext::Success),
+            2 => Ok(ThankContext::Decline),
+            3 => Ok(ThankContext::Error),
+            4 => Ok(ThankContext::Cancel),
+            _ => Err(format!("Invalid THANK context: {}", code)),
+        }
+    }
+}
+
+impl From<ThankContext> for u8 {
+    fn from(context: ThankContext) -> u8 {
+        context as u8
+    }
+}
+
+/// A decline reason `r`: a spec §9.2 code, or a name the spec does not
+/// define, as handlers may give.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Reason {
+    Code(RejectReason),
+    Name(String),
+}
+
+impl Reason {
+    /// The reason for `name`: its code when the spec knows it.
+    pub fn named(name: &str) -> Self {
+        match RejectReason::from_name(name) {
+            Some(reason) => Reason::Code(reason),
+            None => Reason::Name(name.to_string()),
+        }
+    }
+}
+
+impl From<RejectReason> for Reason {
+    fn from(reason: RejectReason) -> Self {
+        Reason::Code(reason)
+    }
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reason::Code(reason) => write!(f, "{} ({})", reason.name(), reason.code()),
+            Reason::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+/// A typed stage payload. `Message.payload` keeps the wire form; these
+/// convert to and from it, and unknown fields are kept in `ext`.
+pub trait StagePayload: Serialize + DeserializeOwned {
+    const STAGE: Stage;
+
+    /// The payload as carried in `Message.payload`.
+    fn to_map(&self) -> HashMap<String, Value> {
+        match serde_json::to_value(self) {
+            Ok(Value::Object(map)) => map.into_iter().collect(),
+            _ => HashMap::new(),
+        }
+    }
+
+    /// Reads a payload, failing with `invalid_format` if it does not fit
+    /// the stage.
+    fn from_map(payload: &HashMap<String, Value>) -> Result<Self> {
+        let object = Value::Object(payload.clone().into_iter().collect());
+        serde_json::from_value(object).map_err(|e| {
+            ProtocolError::new(ErrorCode::InvalidFormat, format!("Invalid {} payload: {}", Self::STAGE.name(), e)).into()
+        })
+    }
+}
+
+/// Checks that a decoded message's payload is well-formed for its stage.
+pub fn validate(message: &Message) -> Result<()> {
+    let stage = Stage::from_u8(message.stage)
+        .map_err(|e| ProtocolError::new(ErrorCode::InvalidFormat, e.to_string()))?;
+    let payload = &message.payload;
+    match stage {
+        Stage::Knock => KnockPayload::from_map(payload).map(drop),
+        Stage::Welcome => WelcomePayload::from_map(payload).map(drop),
+        Stage::Wish => WishPayload::from_map(payload).map(drop),
+        Stage::Grant => GrantPayload::from_map(payload).map(drop),
+        Stage::Wrap => WrapPayload::from_map(payload).map(drop),
+        Stage::Gift => GiftPayload::from_map(payload).map(drop),
+        Stage::Thank => ThankPayload::from_map(payload).map(drop),
+        Stage::Error => ErrorPayload::from_map(payload).map(drop),
+    }
+}
+
+/// KNOCK (spec §7.1). Sent in the clear, so it carries the key exchange.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KnockPayload {
+    pub c: Category,
+    pub pri: Priority,
+    /// Preview, at most 200 characters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offer: Option<Value>,
+    /// Ticket of a deferred task whose GIFT this conversation delivers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tkt: Option<String>,
+    /// Ephemeral X25519 public key and the KNOCK authenticator, filled in
+    /// by the handshake.
+    pub eph_key: Vec<u8>,
+    pub auth: Vec<u8>,
+    #[serde(flatten)]
+    pub ext: HashMap<String, Value>,
+}
+
+impl KnockPayload {
+    pub fn new(c: Category, pri: Priority) -> Self {
+        Self {
+            c,
+            pri,
+            prev: None,
+            offer: None,
+            tkt: None,
+            eph_key: Vec::new(),
+            auth: Vec::new(),
+            ext: HashMap::new(),
+        }
+    }
+}
+
+impl StagePayload for KnockPayload {
+    const STAGE: Stage = Stage::Knock;
+}
+
+/// The WELCOME `cap` field: digest and number of the advertised actions.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CapabilityDigest {
+    pub h: String,
+    pub n: usize,
+}
+
+/// WELCOME (spec §7.2), with the responder's half of the key exchange.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WelcomePayload {
+    pub st: Status,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r: Option<Reason>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg: Option<String>,
+    /// Seconds until trying again is worthwhile, with a decline or busy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cap: Option<CapabilityDigest>,
+    pub eph_key: Vec<u8>,
+    pub auth: Vec<u8>,
+    #[serde(flatten)]
+    pub ext: HashMap<String, Value>,
+}
+
+impl WelcomePayload {
+    pub fn new(st: Status) -> Self {
+        Self {
+            st,
+            r: None,
+            msg: None,
+            retry: None,
+            cap: None,
+            eph_key: Vec::new(),
+            auth: Vec::new(),
+            ext: HashMap::new(),
+        }
+    }
+
+    pub fn decline(reason: impl Into<Reason>, msg: &str) -> Self {
+        Self { r: Some(reason.into()), msg: Some(msg.to_string()), ..Self::new(Status::Decline) }
+    }
+
+    /// Busy (`st: 3`) with the seconds after which to try again.
+    pub fn busy(msg: &str, retry: u64) -> Self {
+        Self { st: Status::Busy, retry: Some(retry), ..Self::decline(RejectReason::Busy, msg) }
+    }
+
+    pub fn with_retry(self, retry: u64) -> Self {
+        Self { retry: Some(retry), ..self }
+    }
+}
+
+impl StagePayload for WelcomePayload {
+    const STAGE: Stage = Stage::Welcome;
+}
+
+/// WISH (spec §7.3). Fields of the requester's own, and the KNOCK fields
+/// `wishp send` takes from the same input, stay in `ext`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct WishPayload {
+    /// Revision, counting negotiation rounds.
+    #[serde(default)]
+    pub rev: u8,
+    /// The counter-proposal option a revised WISH takes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sel_opt: Option<u8>,
+    /// `{act, par, con, data}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offer: Option<Value>,
+    /// Whether a long task may be deferred and its GIFT delivered later.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dfr: bool,
+    #[serde(flatten)]
+    pub ext: HashMap<String, Value>,
+}
+
+impl WishPayload {
+    /// `task.act`, which routes the WISH.
+    pub fn action(&self) -> Option<&str> {
+        self.task.as_ref()?.get("act")?.as_str()
+    }
+
+    /// Offer type `t`, which the policy's `offer` rules match.
+    pub fn offer_type(&self) -> Option<u64> {
+        self.offer.as_ref()?.get("t")?.as_u64()
+    }
+}
+
+impl StagePayload for WishPayload {
+    const STAGE: Stage = Stage::Wish;
+}
+
+/// GRANT (spec §7.4).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GrantPayload {
+    pub st: Status,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r: Option<Reason>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg: Option<String>,
+    /// Estimated time in seconds and cost of a granted task.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub est_t: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub est_c: Option<u32>,
+    /// Options offered with `st: 4`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counter: Option<CounterProposal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<u64>,
+    /// Ticket and expiry of a deferred task.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tkt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+    #[serde(flatten)]
+    pub ext: HashMap<String, Value>,
+}
+
+impl GrantPayload {
+    pub fn new(st: Status) -> Self {
+        Self {
+            st,
+            r: None,
+            msg: None,
+            est_t: None,
+            est_c: None,
+            counter: None,
+            retry: None,
+            tkt: None,
+            exp: None,
+            ext: HashMap::new(),
+        }
+    }
+
+    pub fn decline(reason: impl Into<Reason>, msg: &str) -> Self {
+        Self { r: Some(reason.into()), msg: Some(msg.to_string()), ..Self::new(Status::Decline) }
+    }
+}
+
+impl StagePayload for GrantPayload {
+    const STAGE: Stage = Stage::Grant;
+}
+
+/// WRAP (spec §7.5): progress of a running task.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct WrapPayload {
+    /// Percent done.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prog: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stat: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg: Option<String>,
+    /// Seconds left.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eta: Option<u16>,
+    #[serde(flatten)]
+    pub ext: HashMap<String, Value>,
+}
+
+impl WrapPayload {
+    pub fn is_empty(&self) -> bool {
+        self.prog.is_none() && self.stat.is_none() && self.msg.is_none() && self.eta.is_none() && self.ext.is_empty()
+    }
+}
+
+impl StagePayload for WrapPayload {
+    const STAGE: Stage = Stage::Wrap;
+}
+
+/// GIFT (spec §7.6): the result.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GiftPayload {
+    pub ok: bool,
+    #[serde(default)]
+    pub res: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Value>,
+    /// Ticket of the deferred task this GIFT belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tkt: Option<String>,
+    /// Whether the requester cancelled the task.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cncl: bool,
+    #[serde(flatten)]
+    pub ext: HashMap<String, Value>,
+}
+
+impl GiftPayload {
+    pub fn new(ok: bool, res: Value) -> Self {
+        Self { ok, res, meta: None, tkt: None, cncl: false, ext: HashMap::new() }
+    }
+}
+
+impl StagePayload for GiftPayload {
+    const STAGE: Stage = Stage::Gift;
+}
+
+/// THANK (spec §7.7), closing the conversation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ThankPayload {
+    pub ctx: ThankContext,
+    /// Whether the sender understood why, after anything but success.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub und: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fb: Option<String>,
+    /// Whether the requester means to try again, after a timeout.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<bool>,
+    #[serde(flatten)]
+    pub ext: HashMap<String, Value>,
+}
+
+impl ThankPayload {
+    pub fn new(ctx: ThankContext, understood: bool, feedback: Option<&str>) -> Self {
+        Self {
+            ctx,
+            und: (ctx != ThankContext::Success).then_some(understood),
+            fb: feedback.map(str::to_string),
+            retry: None,
+            ext: HashMap::new(),
+        }
+    }
+}
+
+impl StagePayload for ThankPayload {
+    const STAGE: Stage = Stage::Thank;
+}
+
+/// ERROR (spec §10.2).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ErrorPayload {
+    pub code: ErrorCode,
+    #[serde(default)]
+    pub msg: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub det: Option<Value>,
+    #[serde(default)]
+    pub recov: bool,
+    #[serde(flatten)]
+    pub ext: HashMap<String, Value>,
+}
+
+impl StagePayload for ErrorPayload {
+    const STAGE: Stage = Stage::Error;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn map(value: Value) -> HashMap<String, Value> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_codes_on_the_wire() {
+        let welcome = WelcomePayload::busy("At capacity", 120);
+        let wire = welcome.to_map();
+        assert_eq!(wire["st"], 3);
+        assert_eq!(wire["r"], 1);
+        assert_eq!(wire["retry"], 120);
+        assert!(!wire.contains_key("cap"));
+        assert_eq!(WelcomePayload::from_map(&wire).unwrap(), welcome);
+
+        let thank = ThankPayload::new(ThankContext::Success, false, None).to_map();
+        assert_eq!(thank, map(json!({"ctx": 1})));
+        let grant = GrantPayload::decline(Reason::named("resource_constraints"), "No").to_map();
+        assert_eq!(grant["r"], "resource_constraints");
+        assert_eq!(Reason::named("blocked"), Reason::Code(RejectReason::Blocked));
+        assert_eq!(GrantPayload::from_map(&grant).unwrap().r, Some(Reason::Name("resource_constraints".to_string())));
+    }
+
+    #[test]
+    fn test_unknown_fields_kept() {
+        let wire = map(json!({"c": 3, "pri": 4, "prev": "hi", "eph_key": [1, 2], "auth": [], "lang": "de"}));
+        let knock = KnockPayload::from_map(&wire).unwrap();
+        assert_eq!((knock.c, knock.pri), (Category::Question, Priority::Urgent));
+        assert_eq!(knock.ext["lang"], "de");
+        assert_eq!(knock.to_map(), wire);
+    }
+
+    #[test]
+    fn test_invalid_payloads_rejected() {
+        let invalid = [
+            (Stage::Knock, json!({"c": 9, "pri": 2, "eph_key": [], "auth": []})),
+            (Stage::Knock, json!({"c": 1, "pri": 2})),
+            (Stage::Welcome, json!({"st": "yes", "eph_key": [], "auth": []})),
+            (Stage::Grant, json!({"st": 1, "est_t": -5})),
+            (Stage::Thank, json!({"ctx": 7})),
+            (Stage::Error, json!({"code": 200})),
+            (Stage::Gift, json!({"res": 1})),
+        ];
+        for (stage, payload) in invalid {
+            let message = Message {
+                stage: stage.to_u8(),
+                counter: 1,
+                timestamp: 0,
+                from: "a".to_string(),
+                to: "b".to_string(),
+                payload: map(payload),
+            };
+            let err = validate(&message).unwrap_err();
+            let error = ProtocolError::from_anyhow(&err, ErrorCode::InternalError);
+            assert_eq!(error.code, ErrorCode::InvalidFormat, "{:?}", stage);
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use crate::payload::{Category, Priority};
+use crate::protocol::RejectReason;
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveTime;
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+
+/// What a policy rule does with a matching KNOCK or WISH.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    /// Accept without asking the handler.
+    Accept,
+    Decline,
+    /// Leave it to the handler.
+    #[default]
+    Handler,
+}
+
+impl Decision {
+    pub fn name(self) -> &'static str {
+        match self {
+            Decision::Accept => "accept",
+            Decision::Decline => "decline",
+            Decision::Handler => "handler",
+        }
+    }
+}
+
+/// The policy file: `[[rule]]` tables tried in order, and the decision when
+/// none matches.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub default: Decision,
+    pub rule: Vec<Rule>,
+}
+
+/// One `[[rule]]`. It fires when every criterion given matches; an empty
+/// list matches anything. `max_size` and `offer` are then requirements:
+/// a WISH that fails them is declined whatever the rule's decision. They
+/// are not checked on the KNOCK, which need not carry the offer.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rule {
+    pub name: Option<String>,
+    pub peer: Vec<String>,
+    pub label: Vec<String>,
+    pub category: Vec<Category>,
+    pub priority: Vec<Priority>,
+    /// Local time windows such as `"09:00-17:30"`; may wrap past midnight.
+    pub hours: Vec<Window>,
+    /// Largest encoded WISH in bytes.
+    pub max_size: Option<usize>,
+    /// Offer types (`t`) of which the conversation must offer one.
+    pub offer: Vec<u64>,
+    pub decision: Decision,
+    /// Decline reason name (default `policy_violation`).
+    pub reason: Option<String>,
+    /// Text for the WELCOME or GRANT `msg`.
+    pub msg: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Window {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, String> {
+        let parse = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M");
+        match text.split_once('-').map(|(start, end)| (parse(start), parse(end))) {
+            Some((Ok(start), Ok(end))) => Ok(Window { start, end }),
+            _ => Err(format!("Invalid hours {:?}, expected HH:MM-HH:MM", text)),
+        }
+    }
+}
+
+/// What the daemon knows when judging a KNOCK (`size` None) or a WISH.
+pub struct Subject<'a> {
+    pub peer: &'a str,
+    pub labels: &'a [String],
+    pub category: Option<Category>,
+    pub priority: Option<Priority>,
+    /// Offer type of the message judged.
+    pub offer: Option<u64>,
+    pub size: Option<usize>,
+    pub time: NaiveTime,
+}
+
+/// The outcome of a policy evaluation and the rule that produced it.
+#[derive(Debug, PartialEq)]
+pub struct Verdict {
+    pub rule: String,
+    pub decision: Decision,
+    pub reason: Option<String>,
+    pub msg: Option<String>,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rule {}: {}", self.rule, self.decision.name())?;
+        if let Some(reason) = &self.reason {
+            write!(f, " ({})", reason)?;
+        }
+        Ok(())
+    }
+}
+
+pub const DEFAULT_RULE: &str = "default";
+
+impl Policy {
+    /// Reads the policy file. A missing file leaves every decision to the
+    /// handler.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let text = std::fs::read_to_string(path)?;
+        let policy: Policy =
+            toml::from_str(&text).with_context(|| format!("Invalid policy file {}", path.display()))?;
+        for rule in &policy.rule {
+            if let Some(reason) = &rule.reason {
+                if RejectReason::from_name(reason).is_none() {
+                    return Err(anyhow!("Unknown decline reason {} in policy", reason));
+                }
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn evaluate(&self, subject: &Subject) -> Verdict {
+        let Some((i, rule)) = self.rule.iter().enumerate().find(|(_, r)| r.matches(subject)) else {
+            return Verdict {
+                rule: DEFAULT_RULE.to_string(),
+                decision: self.default,
+                reason: (self.default == Decision::Decline).then(|| RejectReason::PolicyViolation.name().to_string()),
+                msg: None,
+            };
+        };
+        let name = rule.name.clone().unwrap_or_else(|| format!("rule[{}]", i));
+
+        let wish = subject.size.is_some();
+        let unmet = if subject.size.zip(rule.max_size).is_some_and(|(size, max)| size > max) {
+            Some(RejectReason::ExcessiveRequest)
+        } else if wish && !rule.offer.is_empty() && !subject.offer.is_some_and(|t| rule.offer.contains(&t)) {
+            Some(RejectReason::InsufficientOffer)
+        } else {
+            None
+        };
+        if let Some(reason) = unmet {
+            return Verdict {
+                rule: name,
+                decision: Decision::Decline,
+                reason: Some(reason.name().to_string()),
+                msg: None,
+            };
+        }
+
+        let reason = match rule.decision {
+            Decision::Decline => Some(rule.reason.clone().unwrap_or_else(|| RejectReason::PolicyViolation.name().to_string())),
+            _ => None,
+        };
+        Verdict {
+            rule: name,
+            decision: rule.decision,
+            reason,
+            msg: rule.msg.clone(),
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, subject: &Subject) -> bool {
+        let peer = self.peer.is_empty() || self.peer.iter().any(|p| crate::router::wildcard_match(p, subject.peer));
+        let label = self.label.is_empty() || self.label.iter().any(|l| subject.labels.contains(l));
+        let category = self.category.is_empty() || subject.category.is_some_and(|c| self.category.contains(&c));
+        let priority = self.priority.is_empty() || subject.priority.is_some_and(|p| self.priority.contains(&p));
+        let hours = self.hours.is_empty() || self.hours.iter().any(|w| w.contains(subject.time));
+        peer && label && category && priority && hours
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subject<'a>(peer: &'a str, labels: &'a [String], size: Option<usize>, offer: Option<u64>, time: &str) -> Subject<'a> {
+        Subject {
+            peer,
+            labels,
+            category: Some(Category::TaskRequest),
+            priority: Some(Priority::Normal),
+            offer,
+            size,
+            time: NaiveTime::parse_from_str(time, "%H:%M").unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_first_matching_rule_decides() {
+        let policy: Policy = toml::from_str(
+            r#"
+            default = "decline"
+
+            [[rule]]
+            name = "friends"
+            label = ["friend"]
+            max_size = 1000
+            decision = "accept"
+
+            [[rule]]
+            name = "office-hours"
+            hours = ["09:00-17:00"]
+            offer = [2, 4]
+            decision = "handler"
+
+            [[rule]]
+            peer = ["spam-*"]
+            decision = "decline"
+            reason = "trust_issue"
+            "#,
+        )
+        .unwrap();
+        let friend = vec!["friend".to_string()];
+
+        let verdict = policy.evaluate(&subject("quest-1", &friend, Some(10), None, "03:00"));
+        assert_eq!((verdict.rule.as_str(), verdict.decision), ("friends", Decision::Accept));
+
+        let verdict = policy.evaluate(&subject("quest-1", &friend, Some(5000), None, "03:00"));
+        assert_eq!(verdict.decision, Decision::Decline);
+        assert_eq!(verdict.reason.as_deref(), Some("excessive_request"));
+
+        let verdict = policy.evaluate(&subject("quest-1", &[], Some(10), Some(4), "10:00"));
+        assert_eq!((verdict.rule.as_str(), verdict.decision), ("office-hours", Decision::Handler));
+        let verdict = policy.evaluate(&subject("quest-1", &[], Some(10), Some(1), "10:00"));
+        assert_eq!(verdict.reason.as_deref(), Some("insufficient_offer"));
+
+        let verdict = policy.evaluate(&subject("spam-1", &[], None, None, "20:00"));
+        assert_eq!((verdict.rule.as_str(), verdict.reason.as_deref()), ("rule[2]", Some("trust_issue")));
+
+        let verdict = policy.evaluate(&subject("quest-1", &[], None, None, "20:00"));
+        assert_eq!((verdict.rule.as_str(), verdict.decision), (DEFAULT_RULE, Decision::Decline));
+    }
+
+    #[test]
+    fn test_offer_checked_on_wish_only() {
+        let policy: Policy = toml::from_str(
+            r#"
+            [[rule]]
+            name = "paid"
+            offer = [2]
+            decision = "accept"
+            "#,
+        )
+        .unwrap();
+
+        // Our client's KNOCK carries no offer; it must still reach the WISH.
+        let verdict = policy.evaluate(&subject("quest-1", &[], None, None, "10:00"));
+        assert_eq!((verdict.rule.as_str(), verdict.decision), ("paid", Decision::Accept));
+
+        let verdict = policy.evaluate(&subject("quest-1", &[], Some(10), Some(2), "10:00"));
+        assert_eq!(verdict.decision, Decision::Accept);
+        let verdict = policy.evaluate(&subject("quest-1", &[], Some(10), None, "10:00"));
+        assert_eq!((verdict.decision, verdict.reason.as_deref()), (Decision::Decline, Some("insufficient_offer")));
+    }
+
+    #[test]
+    fn test_hours_wrap_midnight() {
+        let window = Window::try_from("22:00-06:30".to_string()).unwrap();
+        let at = |t| NaiveTime::parse_from_str(t, "%H:%M").unwrap();
+        assert!(window.contains(at("23:15")));
+        assert!(window.contains(at("06:00")));
+        assert!(!window.contains(at("12:00")));
+        assert!(Window::try_from("9-5".to_string()).is_err());
+    }
+}
@@ -1,9 +1,12 @@
+use crate::payload::{self, ErrorPayload, StagePayload, ThankContext, ThankPayload, WrapPayload};
 use anyhow::{anyhow, Result};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 pub const PROTOCOL_VERSION: u8 = 2;
+pub const DEFAULT_PORT: u16 = 7779;
 
 pub const MAX_KNOCK_SIZE: usize = 2 * 1024;
 pub const MAX_WELCOME_SIZE: usize = 2 * 1024;
@@ -13,7 +16,9 @@ pub const MAX_WRAP_SIZE: usize = 2 * 1024;
 pub const MAX_GIFT_SIZE: usize = 20 * 1024 * 1024;
 pub const MAX_THANK_SIZE: usize = 4 * 1024;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub const MAX_NEGOTIATION_ROUNDS: u8 = 3;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
 pub struct Message {
     pub stage: u8,
     pub counter: u32,
@@ -23,6 +28,176 @@ pub struct Message {
     pub payload: HashMap<String, serde_json::Value>,
 }
 
+impl Message {
+    /// The payload as `T`, failing with `invalid_format` if the message is
+    /// another stage or the payload does not fit.
+    pub fn parse<T: StagePayload>(&self) -> Result<T> {
+        if self.stage != T::STAGE.to_u8() {
+            return Err(crate::session::unexpected_stage(T::STAGE.name(), self.stage));
+        }
+        T::from_map(&self.payload)
+    }
+}
+
+/// GRANT `counter` map sent with status 4 (spec §8.4).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct CounterProposal {
+    pub opts: Vec<CounterOption>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct CounterOption {
+    pub id: u8,
+    #[serde(default)]
+    pub d: String,
+    #[serde(rename = "mod", default)]
+    pub modifications: serde_json::Map<String, serde_json::Value>,
+}
+
+impl CounterProposal {
+    pub fn option(&self, id: u8) -> Option<&CounterOption> {
+        self.opts.iter().find(|o| o.id == id)
+    }
+}
+
+/// Where a peer can be reached: the host part of a wish URL, or an
+/// address book entry in the keyring.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Endpoint {
+    Direct { host: String, port: u16 },
+    Rendezvous { server: String, port: u16 },
+    Unix { path: String },
+}
+
+impl Endpoint {
+    /// Parses `host[:port]`, `[v6]:port`, `rdv:server[:port]` or
+    /// `unix:/path/to/socket`.
+    pub fn parse(input: &str) -> Result<Self> {
+        if let Some(path) = input.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(anyhow!("Missing socket path in {}", input));
+            }
+            return Ok(Endpoint::Unix { path: path.to_string() });
+        }
+
+        match input.strip_prefix("rdv:") {
+            Some(server) => {
+                let (server, port) = parse_host_port(server)?;
+                Ok(Endpoint::Rendezvous { server, port })
+            }
+            None => {
+                let (host, port) = parse_host_port(input)?;
+                Ok(Endpoint::Direct { host, port })
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (prefix, host, port) = match self {
+            Endpoint::Direct { host, port } => ("", host, port),
+            Endpoint::Rendezvous { server, port } => ("rdv:", server, port),
+            Endpoint::Unix { path } => return write!(f, "unix:{}", path),
+        };
+        if host.contains(':') {
+            write!(f, "{}[{}]:{}", prefix, host, port)
+        } else {
+            write!(f, "{}{}:{}", prefix, host, port)
+        }
+    }
+}
+
+/// A parsed peer address (spec §2.1).
+///
+/// Accepts `wish://agent@host:port/`, the bare `agent@host[:port]` form,
+/// bracketed IPv6 literals and the `rdv:` rendezvous form. The port
+/// defaults to 7779.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WishUrl {
+    pub agent_id: String,
+    pub endpoint: Endpoint,
+}
+
+impl WishUrl {
+    pub fn parse(input: &str) -> Result<Self> {
+        let rest = input.strip_prefix("wish://").unwrap_or(input);
+        let rest = rest.strip_suffix('/').unwrap_or(rest);
+
+        if rest.contains('/') {
+            return Err(anyhow!("Invalid wish URL {}: unexpected path", input));
+        }
+
+        let (agent_id, address) = rest
+            .split_once('@')
+            .ok_or_else(|| anyhow!("Invalid wish URL {}: expected agent@host", input))?;
+
+        validate_agent_id(agent_id)?;
+
+        Ok(Self {
+            agent_id: agent_id.to_string(),
+            endpoint: Endpoint::parse(address)?,
+        })
+    }
+}
+
+impl std::fmt::Display for WishUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "wish://{}@{}/", self.agent_id, self.endpoint)
+    }
+}
+
+fn parse_host_port(address: &str) -> Result<(String, u16)> {
+    let (host, port) = if let Some(bracketed) = address.strip_prefix('[') {
+        let (host, after) = bracketed
+            .split_once(']')
+            .ok_or_else(|| anyhow!("Invalid IPv6 address: {}", address))?;
+        host.parse::<std::net::Ipv6Addr>()
+            .map_err(|_| anyhow!("Invalid IPv6 address: {}", host))?;
+        match after {
+            "" => (host, None),
+            _ => {
+                let port = after
+                    .strip_prefix(':')
+                    .ok_or_else(|| anyhow!("Invalid address: {}", address))?;
+                (host, Some(port))
+            }
+        }
+    } else if address.parse::<std::net::Ipv6Addr>().is_ok() {
+        (address, None)
+    } else {
+        match address.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (address, None),
+        }
+    };
+
+    if host.is_empty() {
+        return Err(anyhow!("Missing host in address: {}", address));
+    }
+
+    let port = match port {
+        Some(port) => port
+            .parse::<u16>()
+            .map_err(|_| anyhow!("Invalid port: {}", port))?,
+        None => DEFAULT_PORT,
+    };
+
+    Ok((host.to_string(), port))
+}
+
+/// Checks the `[name]-[fingerprint]` shape from spec §2.4 loosely:
+/// alphanumerics and hyphens, at most 41 characters.
+pub fn validate_agent_id(agent_id: &str) -> Result<()> {
+    if agent_id.is_empty() || agent_id.len() > 41 {
+        return Err(anyhow!("Invalid agent ID length: {}", agent_id));
+    }
+    if !agent_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(anyhow!("Invalid characters in agent ID: {}", agent_id));
+    }
+    Ok(())
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum Stage {
     Knock = 1,
@@ -50,7 +225,7 @@ impl Stage {
         }
     }
 
-    pub fn to_u8(&self) -> u8 {
+    pub fn to_u8(self) -> u8 {
         match self {
             Stage::Knock => 1,
             Stage::Welcome => 2,
@@ -63,7 +238,20 @@ impl Stage {
         }
     }
 
-    pub fn max_size(&self) -> usize {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Knock => "KNOCK",
+            Stage::Welcome => "WELCOME",
+            Stage::Wish => "WISH",
+            Stage::Grant => "GRANT",
+            Stage::Wrap => "WRAP",
+            Stage::Gift => "GIFT",
+            Stage::Thank => "THANK",
+            Stage::Error => "ERROR",
+        }
+    }
+
+    pub fn max_size(self) -> usize {
         match self {
             Stage::Knock => MAX_KNOCK_SIZE,
             Stage::Welcome => MAX_WELCOME_SIZE,
@@ -77,12 +265,275 @@ impl Stage {
     }
 }
 
+/// Reason codes for a WELCOME or GRANT decline (spec §9.2).
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum RejectReason {
+    Busy = 1,
+    Overloaded = 2,
+    ExcessiveRequest = 3,
+    CapabilityMismatch = 4,
+    InsufficientOffer = 5,
+    PolicyViolation = 6,
+    TrustIssue = 7,
+    ResourceUnavailable = 8,
+    RateLimited = 9,
+    Blocked = 10,
+}
+
+impl RejectReason {
+    const ALL: [RejectReason; 10] = [
+        RejectReason::Busy,
+        RejectReason::Overloaded,
+        RejectReason::ExcessiveRequest,
+        RejectReason::CapabilityMismatch,
+        RejectReason::InsufficientOffer,
+        RejectReason::PolicyViolation,
+        RejectReason::TrustIssue,
+        RejectReason::ResourceUnavailable,
+        RejectReason::RateLimited,
+        RejectReason::Blocked,
+    ];
+
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RejectReason::Busy => "busy",
+            RejectReason::Overloaded => "overloaded",
+            RejectReason::ExcessiveRequest => "excessive_request",
+            RejectReason::CapabilityMismatch => "capability_mismatch",
+            RejectReason::InsufficientOffer => "insufficient_offer",
+            RejectReason::PolicyViolation => "policy_violation",
+            RejectReason::TrustIssue => "trust_issue",
+            RejectReason::ResourceUnavailable => "resource_unavailable",
+            RejectReason::RateLimited => "rate_limited",
+            RejectReason::Blocked => "blocked",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|r| r.name() == name)
+    }
+}
+
+impl TryFrom<u8> for RejectReason {
+    type Error = String;
+
+    fn try_from(code: u8) -> Result<Self, String> {
+        Self::ALL
+            .into_iter()
+            .find(|r| r.code() == code)
+            .ok_or_else(|| format!("Invalid reason code: {}", code))
+    }
+}
+
+impl From<RejectReason> for u8 {
+    fn from(reason: RejectReason) -> u8 {
+        reason.code()
+    }
+}
+
+/// Error codes carried in the ERROR stage (spec §10.2).
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum ErrorCode {
+    Timeout = 1,
+    ConnectionLost = 2,
+    InvalidFormat = 3,
+    EncryptionFailed = 4,
+    AuthenticationFailed = 5,
+    InternalError = 6,
+    ResourceExhausted = 7,
+    TaskFailed = 8,
+    MessageTooLarge = 9,
+    ReplayDetected = 10,
+    CounterMismatch = 11,
+}
+
+impl ErrorCode {
+    pub fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(ErrorCode::Timeout),
+            2 => Ok(ErrorCode::ConnectionLost),
+            3 => Ok(ErrorCode::InvalidFormat),
+            4 => Ok(ErrorCode::EncryptionFailed),
+            5 => Ok(ErrorCode::AuthenticationFailed),
+            6 => Ok(ErrorCode::InternalError),
+            7 => Ok(ErrorCode::ResourceExhausted),
+            8 => Ok(ErrorCode::TaskFailed),
+            9 => Ok(ErrorCode::MessageTooLarge),
+            10 => Ok(ErrorCode::ReplayDetected),
+            11 => Ok(ErrorCode::CounterMismatch),
+            _ => Err(anyhow!("Invalid error code: {}", value)),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::ConnectionLost => "connection_lost",
+            ErrorCode::InvalidFormat => "invalid_format",
+            ErrorCode::EncryptionFailed => "encryption_failed",
+            ErrorCode::AuthenticationFailed => "authentication_failed",
+            ErrorCode::InternalError => "internal_error",
+            ErrorCode::ResourceExhausted => "resource_exhausted",
+            ErrorCode::TaskFailed => "task_failed",
+            ErrorCode::MessageTooLarge => "message_too_large",
+            ErrorCode::ReplayDetected => "replay_detected",
+            ErrorCode::CounterMismatch => "counter_mismatch",
+        }
+    }
+}
+
+impl TryFrom<u8> for ErrorCode {
+    type Error = String;
+
+    fn try_from(code: u8) -> Result<Self, String> {
+        Self::from_u8(code).map_err(|e| e.to_string())
+    }
+}
+
+impl From<ErrorCode> for u8 {
+    fn from(code: ErrorCode) -> u8 {
+        code.to_u8()
+    }
+}
+
+/// Longest `msg` we put in an ERROR so the payload stays well inside the
+/// stage size limit.
+const MAX_ERROR_MSG_LEN: usize = 1024;
+
+/// A failure that maps onto an ERROR payload (`code/msg/det/recov`).
+///
+/// Code paths that know which spec error they hit return this inside their
+/// `anyhow::Error`; anything else is reported with a fallback code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub msg: String,
+    pub det: Option<serde_json::Value>,
+    pub recov: bool,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, msg: impl Into<String>) -> Self {
+        Self {
+            code,
+            msg: msg.into(),
+            det: None,
+            recov: false,
+        }
+    }
+
+    pub fn with_details(mut self, det: serde_json::Value) -> Self {
+        self.det = Some(det);
+        self
+    }
+
+    pub fn recoverable(mut self) -> Self {
+        self.recov = true;
+        self
+    }
+
+    /// Finds the `ProtocolError` inside `err`, or wraps its message with
+    /// `fallback`.
+    pub fn from_anyhow(err: &anyhow::Error, fallback: ErrorCode) -> Self {
+        match err.downcast_ref::<ProtocolError>() {
+            Some(protocol_error) => protocol_error.clone(),
+            None => ProtocolError::new(fallback, err.to_string()),
+        }
+    }
+
+    pub fn to_payload(&self) -> ErrorPayload {
+        ErrorPayload {
+            code: self.code,
+            msg: truncate(&self.msg, MAX_ERROR_MSG_LEN),
+            det: self.det.clone(),
+            recov: self.recov,
+            ext: HashMap::new(),
+        }
+    }
+
+    pub fn from_payload(payload: &HashMap<String, serde_json::Value>) -> Result<Self> {
+        let payload = ErrorPayload::from_map(payload)?;
+        Ok(Self {
+            code: payload.code,
+            msg: payload.msg,
+            det: payload.det,
+            recov: payload.recov,
+        })
+    }
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}): {}", self.code.name(), self.code.to_u8(), self.msg)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// An ERROR stage received from the other side of the conversation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerError(pub ProtocolError);
+
+impl std::fmt::Display for PeerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Peer reported {}", self.0)
+    }
+}
+
+impl std::error::Error for PeerError {}
+
+/// Whether `message` is a THANK cancelling the task.
+pub fn is_cancel(message: &Message) -> bool {
+    message.parse::<ThankPayload>().is_ok_and(|thank| thank.ctx == ThankContext::Cancel)
+}
+
+/// Longest `stat` or `msg` we put in a WRAP, keeping it under MAX_WRAP_SIZE.
+const MAX_WRAP_TEXT_LEN: usize = 512;
+
+/// Builds a WRAP payload from a handler progress event, keeping only the
+/// spec fields (§7.5) and trimming them to fit.
+pub fn build_wrap_payload(event: &serde_json::Value) -> WrapPayload {
+    let text = |key: &str| event.get(key).and_then(|v| v.as_str()).map(|t| truncate(t, MAX_WRAP_TEXT_LEN));
+    WrapPayload {
+        prog: event.get("prog").and_then(|v| v.as_u64()).map(|p| p.min(100) as u8),
+        stat: text("stat"),
+        msg: text("msg"),
+        eta: event.get("eta").and_then(|v| v.as_u64()).map(|e| e.min(u16::MAX as u64) as u16),
+        ext: HashMap::new(),
+    }
+}
+
+/// `text` cut to at most `max` bytes on a char boundary.
+fn truncate(text: &str, max: usize) -> String {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text[..end].to_string()
+}
+
 pub fn encode_message(message: &Message) -> Result<Vec<u8>> {
     rmp_serde::to_vec(message).map_err(|e| anyhow!("Serialization failed: {}", e))
 }
 
+/// Decodes a message and checks its payload against its stage's
+/// `StagePayload`, so malformed messages fail here with `invalid_format`.
 pub fn decode_message(bytes: &[u8]) -> Result<Message> {
-    rmp_serde::from_slice(bytes).map_err(|e| anyhow!("Deserialization failed: {}", e))
+    let message: Message = rmp_serde::from_slice(bytes).map_err(|e| {
+        ProtocolError::new(ErrorCode::InvalidFormat, format!("Deserialization failed: {}", e))
+    })?;
+    payload::validate(&message)?;
+    Ok(message)
 }
 
 pub async fn send_framed_message<W>(writer: &mut W, data: &[u8]) -> Result<()>
@@ -106,13 +557,25 @@ where
     let len = u32::from_be_bytes(len_bytes) as usize;
 
     if len > MAX_GIFT_SIZE + 1 {
-        return Err(anyhow!("Message too large: {} bytes (max {})", len, MAX_GIFT_SIZE + 1));
+        return Err(ProtocolError::new(
+            ErrorCode::MessageTooLarge,
+            format!("Message too large: {} bytes (max {})", len, MAX_GIFT_SIZE + 1),
+        )
+        .with_details(serde_json::json!({"max": MAX_GIFT_SIZE + 1, "received": len}))
+        .into());
+    }
+    if len == 0 {
+        return Err(ProtocolError::new(ErrorCode::InvalidFormat, "Empty frame").into());
     }
 
     let mut version = [0u8; 1];
     reader.read_exact(&mut version).await?;
     if version[0] != PROTOCOL_VERSION {
-        return Err(anyhow!("Invalid protocol version: {} (expected {})", version[0], PROTOCOL_VERSION));
+        return Err(ProtocolError::new(
+            ErrorCode::InvalidFormat,
+            format!("Invalid protocol version: {} (expected {})", version[0], PROTOCOL_VERSION),
+        )
+        .into());
     }
 
     let mut buf = vec![0u8; len - 1];
@@ -134,20 +597,24 @@ pub fn current_timestamp() -> u32 {
         .unwrap_or(0)
 }
 
+pub fn key_field(bytes: &[u8], field: &str) -> Result<[u8; 32]> {
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("Invalid {} length: {}", field, bytes.len()))
+}
+
 pub fn validate_size(stage: u8, size: usize) -> Result<()> {
-    let limit = match stage {
-        1 => MAX_KNOCK_SIZE,
-        2 => MAX_WELCOME_SIZE,
-        3 => MAX_WISH_SIZE,
-        4 => MAX_GRANT_SIZE,
-        5 => MAX_WRAP_SIZE,
-        6 => MAX_GIFT_SIZE,
-        7 => MAX_THANK_SIZE,
-        _ => return Err(anyhow!("Unknown stage: {}", stage)),
-    };
+    let limit = Stage::from_u8(stage)
+        .map_err(|_| anyhow!("Unknown stage: {}", stage))?
+        .max_size();
 
     if size > limit {
-        return Err(anyhow!("Message too large for stage {}: {} bytes (max {})", stage, size, limit));
+        return Err(ProtocolError::new(
+            ErrorCode::MessageTooLarge,
+            format!("Message too large for stage {}: {} bytes (max {})", stage, size, limit),
+        )
+        .with_details(serde_json::json!({"max": limit, "received": size, "stage": stage}))
+        .into());
     }
     Ok(())
 }
@@ -158,8 +625,14 @@ mod tests {
 
     #[test]
     fn test_encode_decode() {
-        let mut payload = HashMap::new();
-        payload.insert("key".to_string(), serde_json::json!("value"));
+        let payload = serde_json::from_value(serde_json::json!({
+            "c": 1,
+            "pri": 2,
+            "eph_key": vec![7u8; 32],
+            "auth": [],
+            "key": "value",
+        }))
+        .unwrap();
 
         let message = Message {
             stage: Stage::Knock.to_u8(),
@@ -184,12 +657,140 @@ mod tests {
         assert!(Stage::from_u8(100).is_err());
     }
 
+    #[test]
+    fn test_error_payload_roundtrip() {
+        let error = ProtocolError::new(ErrorCode::TaskFailed, "Processing failed at document 347")
+            .with_details(serde_json::json!({"processed": 346, "failed_at": 347}))
+            .recoverable();
+
+        let payload = error.to_payload().to_map();
+        assert_eq!(payload["code"], 8);
+        assert_eq!(ProtocolError::from_payload(&payload).unwrap(), error);
+
+        let mut unknown = payload.clone();
+        unknown.insert("code".to_string(), serde_json::json!(200));
+        assert!(ProtocolError::from_payload(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_error_msg_truncated() {
+        let error = ProtocolError::new(ErrorCode::InternalError, "é".repeat(2000));
+        let payload = error.to_payload();
+        assert!(payload.msg.len() <= MAX_ERROR_MSG_LEN);
+    }
+
+    #[test]
+    fn test_wrap_payload_fits() {
+        let event = serde_json::json!({
+            "prog": 250,
+            "stat": "analyzing",
+            "msg": "x".repeat(5000),
+            "eta": 60,
+            "debug": "dropped",
+        });
+        let wrap = build_wrap_payload(&event);
+        assert_eq!(wrap.prog, Some(100));
+        assert_eq!(wrap.eta, Some(60));
+        let payload = wrap.to_map();
+        assert!(!payload.contains_key("debug"));
+
+        let message = Message {
+            stage: Stage::Wrap.to_u8(),
+            counter: 5,
+            timestamp: 1678886400,
+            from: "alice-12345678".to_string(),
+            to: "bob-87654321".to_string(),
+            payload,
+        };
+        let encoded = encode_message(&message).unwrap();
+        assert!(validate_size(message.stage, encoded.len()).is_ok());
+    }
+
+    #[test]
+    fn test_oversize_maps_to_error_code() {
+        let err = validate_size(Stage::Knock.to_u8(), MAX_KNOCK_SIZE + 1).unwrap_err();
+        let protocol_error = ProtocolError::from_anyhow(&err, ErrorCode::InternalError);
+        assert_eq!(protocol_error.code, ErrorCode::MessageTooLarge);
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed_payload() {
+        let message = Message {
+            stage: Stage::Grant.to_u8(),
+            counter: 3,
+            timestamp: 1678886400,
+            from: "alice-12345678".to_string(),
+            to: "bob-87654321".to_string(),
+            payload: HashMap::from([("st".to_string(), serde_json::json!(9))]),
+        };
+        let err = decode_message(&encode_message(&message).unwrap()).unwrap_err();
+        let error = ProtocolError::from_anyhow(&err, ErrorCode::InternalError);
+        assert_eq!(error.code, ErrorCode::InvalidFormat);
+        assert!(error.msg.starts_with("Invalid GRANT payload"), "{}", error.msg);
+    }
+
     #[test]
     fn test_build_aad() {
         let aad = build_aad(2, "alice", "bob");
         assert_eq!(aad, b"\x02alicebob".to_vec());
     }
 
+    #[test]
+    fn test_parse_wish_url() {
+        let url = WishUrl::parse("wish://churi-7b9e4d2a@192.168.1.100:7780/").unwrap();
+        assert_eq!(url.agent_id, "churi-7b9e4d2a");
+        assert_eq!(url.endpoint, Endpoint::Direct { host: "192.168.1.100".to_string(), port: 7780 });
+
+        let url = WishUrl::parse("wish://nono@agent.example.com/").unwrap();
+        assert_eq!(url.endpoint, Endpoint::Direct { host: "agent.example.com".to_string(), port: DEFAULT_PORT });
+
+        let url = WishUrl::parse("churi-7b9e4d2a@192.168.1.100").unwrap();
+        assert_eq!(url.endpoint, Endpoint::Direct { host: "192.168.1.100".to_string(), port: DEFAULT_PORT });
+
+        let url = WishUrl::parse("wish://alice@rdv:rendezvous.example.com/").unwrap();
+        assert_eq!(url.endpoint, Endpoint::Rendezvous { server: "rendezvous.example.com".to_string(), port: DEFAULT_PORT });
+    }
+
+    #[test]
+    fn test_parse_wish_url_ipv6() {
+        let url = WishUrl::parse("wish://nono@[::1]:9000/").unwrap();
+        assert_eq!(url.endpoint, Endpoint::Direct { host: "::1".to_string(), port: 9000 });
+        assert_eq!(url.to_string(), "wish://nono@[::1]:9000/");
+
+        let url = WishUrl::parse("nono@[fe80::1]").unwrap();
+        assert_eq!(url.endpoint, Endpoint::Direct { host: "fe80::1".to_string(), port: DEFAULT_PORT });
+
+        let url = WishUrl::parse("nono@2001:db8::1").unwrap();
+        assert_eq!(url.endpoint, Endpoint::Direct { host: "2001:db8::1".to_string(), port: DEFAULT_PORT });
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(
+            Endpoint::parse("unix:/run/wishp.sock").unwrap(),
+            Endpoint::Unix { path: "/run/wishp.sock".to_string() }
+        );
+        assert_eq!(
+            Endpoint::parse("rdv:rdv.example.com:9000").unwrap(),
+            Endpoint::Rendezvous { server: "rdv.example.com".to_string(), port: 9000 }
+        );
+        for input in ["10.0.0.1:7779", "[::1]:7779", "rdv:rdv.example.com:7779", "unix:/tmp/s"] {
+            assert_eq!(Endpoint::parse(input).unwrap().to_string(), input);
+        }
+        assert!(Endpoint::parse("unix:").is_err());
+    }
+
+    #[test]
+    fn test_parse_wish_url_invalid() {
+        assert!(WishUrl::parse("churi-7b9e4d2a").is_err());
+        assert!(WishUrl::parse("wish://@host/").is_err());
+        assert!(WishUrl::parse("wish://nono@/").is_err());
+        assert!(WishUrl::parse("wish://nono@host:99999/").is_err());
+        assert!(WishUrl::parse("wish://nono@host/path").is_err());
+        assert!(WishUrl::parse("wish://no no@host/").is_err());
+        assert!(WishUrl::parse("nono@[::1").is_err());
+    }
+
     #[tokio::test]
     async fn test_framed_message() {
         use tokio::io::duplex;
@@ -0,0 +1,346 @@
+use crate::protocol;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+const HOUR: u64 = 3600;
+const DAY: u64 = 24 * HOUR;
+
+/// Limits applied to one peer (spec §13.1).
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Limits {
+    pub knocks_per_hour: u64,
+    pub messages_per_day: u64,
+    pub bytes_per_hour: u64,
+    pub bytes_per_day: u64,
+    pub conversation_bytes: u64,
+    pub conversation_messages: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            knocks_per_hour: 100,
+            messages_per_day: 1000,
+            bytes_per_hour: 100 * 1024 * 1024,
+            bytes_per_day: 1024 * 1024 * 1024,
+            conversation_bytes: 20 * 1024 * 1024,
+            conversation_messages: 100,
+        }
+    }
+}
+
+/// Per-peer changes to the default limits; unset fields keep the default.
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LimitOverrides {
+    pub knocks_per_hour: Option<u64>,
+    pub messages_per_day: Option<u64>,
+    pub bytes_per_hour: Option<u64>,
+    pub bytes_per_day: Option<u64>,
+    pub conversation_bytes: Option<u64>,
+    pub conversation_messages: Option<u64>,
+}
+
+impl LimitOverrides {
+    pub fn apply(&self, defaults: &Limits) -> Limits {
+        Limits {
+            knocks_per_hour: self.knocks_per_hour.unwrap_or(defaults.knocks_per_hour),
+            messages_per_day: self.messages_per_day.unwrap_or(defaults.messages_per_day),
+            bytes_per_hour: self.bytes_per_hour.unwrap_or(defaults.bytes_per_hour),
+            bytes_per_day: self.bytes_per_day.unwrap_or(defaults.bytes_per_day),
+            conversation_bytes: self.conversation_bytes.unwrap_or(defaults.conversation_bytes),
+            conversation_messages: self.conversation_messages.unwrap_or(defaults.conversation_messages),
+        }
+    }
+}
+
+impl Limits {
+    /// Checks the running totals of one conversation, both directions.
+    pub fn check_conversation(&self, bytes: u64, messages: u64) -> Result<(), RateLimited> {
+        if bytes > self.conversation_bytes {
+            return Err(RateLimited {
+                msg: format!("Conversation size limit exceeded ({})", format_bytes(self.conversation_bytes)),
+                retry_after: 0,
+            });
+        }
+        if messages > self.conversation_messages {
+            return Err(RateLimited {
+                msg: format!("Message count limit exceeded ({})", self.conversation_messages),
+                retry_after: 0,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A request over one of the rate limits. `retry_after` is the number of
+/// seconds until the window it hit resets (0 for per-conversation caps).
+#[derive(Debug)]
+pub struct RateLimited {
+    pub msg: String,
+    pub retry_after: u64,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (retry in {}s)", self.msg, self.retry_after)
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// A fixed window: `used` since `start`, reset once `len` seconds pass.
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+struct Window {
+    used: u64,
+    start: u64,
+}
+
+impl Window {
+    fn roll(&mut self, now: u64, len: u64) {
+        if self.used == 0 || self.start + len <= now {
+            self.used = 0;
+            self.start = now;
+        }
+    }
+
+    fn retry_after(&self, now: u64, len: u64) -> u64 {
+        (self.start + len).saturating_sub(now)
+    }
+
+    fn expired(&self, now: u64, len: u64) -> bool {
+        self.start + len <= now
+    }
+
+    /// What counts against the limit at `now`.
+    fn current(&self, now: u64, len: u64) -> u64 {
+        if self.expired(now, len) {
+            0
+        } else {
+            self.used
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct PeerUsage {
+    knocks: Window,
+    messages: Window,
+    bytes_hour: Window,
+    bytes_day: Window,
+}
+
+/// One peer's usage in its current windows, as shown by `wishp limits`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Usage {
+    pub peer: String,
+    pub knocks_hour: u64,
+    pub messages_day: u64,
+    pub bytes_hour: u64,
+    pub bytes_day: u64,
+}
+
+/// Per-peer usage, saved so that quotas survive a daemon restart.
+pub struct RateLimiter {
+    usage: HashMap<String, PeerUsage>,
+    path: PathBuf,
+}
+
+impl RateLimiter {
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let usage = if path.exists() {
+            let data = std::fs::read(&path)?;
+            rmp_serde::from_slice(&data)
+                .map_err(|e| anyhow!("Cannot read rate limit state {}: {}", path.display(), e))?
+        } else {
+            HashMap::new()
+        };
+        Ok(Self { usage, path })
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        let now = protocol::current_timestamp() as u64;
+        self.usage
+            .retain(|_, u| !(u.knocks.expired(now, HOUR) && u.messages.expired(now, DAY) && u.bytes_day.expired(now, DAY)));
+
+        let data = rmp_serde::to_vec_named(&self.usage)?;
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, data)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Usage of every peer with something counted in a current window.
+    pub fn usage(&self) -> Vec<Usage> {
+        self.usage_at(protocol::current_timestamp() as u64)
+    }
+
+    fn usage_at(&self, now: u64) -> Vec<Usage> {
+        let mut usage: Vec<_> = self
+            .usage
+            .iter()
+            .map(|(peer, u)| Usage {
+                peer: peer.clone(),
+                knocks_hour: u.knocks.current(now, HOUR),
+                messages_day: u.messages.current(now, DAY),
+                bytes_hour: u.bytes_hour.current(now, HOUR),
+                bytes_day: u.bytes_day.current(now, DAY),
+            })
+            .filter(|u| u.knocks_hour + u.messages_day + u.bytes_day > 0)
+            .collect();
+        usage.sort_by(|a, b| a.peer.cmp(&b.peer));
+        usage
+    }
+
+    /// Counts a KNOCK of `bytes` against the peer's limits.
+    pub fn check_knock(&mut self, agent_id: &str, limits: &Limits, bytes: u64) -> Result<(), RateLimited> {
+        self.check_knock_at(agent_id, limits, bytes, protocol::current_timestamp() as u64)
+    }
+
+    /// Counts any other message of `bytes` received from the peer.
+    pub fn check_message(&mut self, agent_id: &str, limits: &Limits, bytes: u64) -> Result<(), RateLimited> {
+        self.check_message_at(agent_id, limits, bytes, protocol::current_timestamp() as u64)
+    }
+
+    fn check_knock_at(&mut self, agent_id: &str, limits: &Limits, bytes: u64, now: u64) -> Result<(), RateLimited> {
+        let usage = self.usage.entry(agent_id.to_string()).or_default();
+        usage.knocks.roll(now, HOUR);
+        if usage.knocks.used >= limits.knocks_per_hour {
+            return Err(RateLimited {
+                msg: format!("Rate limit exceeded. {} KNOCK/hour max.", limits.knocks_per_hour),
+                retry_after: usage.knocks.retry_after(now, HOUR),
+            });
+        }
+
+        self.check_message_at(agent_id, limits, bytes, now)?;
+        if let Some(usage) = self.usage.get_mut(agent_id) {
+            usage.knocks.used += 1;
+        }
+        Ok(())
+    }
+
+    fn check_message_at(&mut self, agent_id: &str, limits: &Limits, bytes: u64, now: u64) -> Result<(), RateLimited> {
+        let usage = self.usage.entry(agent_id.to_string()).or_default();
+        usage.messages.roll(now, DAY);
+        usage.bytes_hour.roll(now, HOUR);
+        usage.bytes_day.roll(now, DAY);
+
+        if usage.messages.used >= limits.messages_per_day {
+            return Err(RateLimited {
+                msg: format!("Rate limit exceeded. {} messages/day max.", limits.messages_per_day),
+                retry_after: usage.messages.retry_after(now, DAY),
+            });
+        }
+        if usage.bytes_hour.used + bytes > limits.bytes_per_hour {
+            return Err(RateLimited {
+                msg: format!("Rate limit exceeded. {}/hour max.", format_bytes(limits.bytes_per_hour)),
+                retry_after: usage.bytes_hour.retry_after(now, HOUR),
+            });
+        }
+        if usage.bytes_day.used + bytes > limits.bytes_per_day {
+            return Err(RateLimited {
+                msg: format!("Rate limit exceeded. {}/day max.", format_bytes(limits.bytes_per_day)),
+                retry_after: usage.bytes_day.retry_after(now, DAY),
+            });
+        }
+
+        usage.messages.used += 1;
+        usage.bytes_hour.used += bytes;
+        usage.bytes_day.used += bytes;
+        Ok(())
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const MB: u64 = 1024 * 1024;
+    if bytes >= 1024 * MB && bytes % (1024 * MB) == 0 {
+        format!("{} GB", bytes / (1024 * MB))
+    } else if bytes >= MB && bytes % MB == 0 {
+        format!("{} MB", bytes / MB)
+    } else {
+        format!("{} bytes", bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempFile;
+
+    #[test]
+    fn test_knock_limit_and_retry() {
+        let mut limiter = RateLimiter::load(TempFile::new("ratelimit", "unused").to_path_buf()).unwrap();
+        let limits = Limits { knocks_per_hour: 2, ..Limits::default() };
+
+        limiter.check_knock_at("quest-1", &limits, 100, 1000).unwrap();
+        limiter.check_knock_at("quest-1", &limits, 100, 1100).unwrap();
+        let limited = limiter.check_knock_at("quest-1", &limits, 100, 1600).unwrap_err();
+        assert_eq!(limited.retry_after, 3000);
+
+        limiter.check_knock_at("quest-1", &limits, 100, 1000 + HOUR).unwrap();
+    }
+
+    #[test]
+    fn test_daily_quotas() {
+        let mut limiter = RateLimiter::load(TempFile::new("ratelimit", "unused").to_path_buf()).unwrap();
+        let limits = Limits { messages_per_day: 3, bytes_per_day: 250, ..Limits::default() };
+
+        limiter.check_message_at("quest-1", &limits, 100, 0).unwrap();
+        limiter.check_message_at("quest-1", &limits, 100, 10).unwrap();
+        let limited = limiter.check_message_at("quest-1", &limits, 100, 20).unwrap_err();
+        assert!(limited.msg.contains("/day"));
+        assert_eq!(limited.retry_after, DAY - 20);
+
+        limiter.check_message_at("quest-1", &limits, 50, 30).unwrap();
+        assert!(limiter.check_message_at("quest-1", &limits, 1, 40).is_err());
+        limiter.check_message_at("quest-1", &limits, 100, DAY).unwrap();
+    }
+
+    #[test]
+    fn test_usage_report() {
+        let mut limiter = RateLimiter::load(TempFile::new("ratelimit", "unused").to_path_buf()).unwrap();
+        let limits = Limits::default();
+        limiter.check_knock_at("quest-1", &limits, 100, 1000).unwrap();
+        limiter.check_message_at("quest-1", &limits, 50, 1010).unwrap();
+
+        let usage = limiter.usage_at(1020);
+        assert_eq!(usage.len(), 1);
+        assert_eq!((usage[0].knocks_hour, usage[0].messages_day, usage[0].bytes_day), (1, 2, 150));
+
+        let usage = limiter.usage_at(1000 + HOUR);
+        assert_eq!((usage[0].knocks_hour, usage[0].bytes_hour, usage[0].bytes_day), (0, 0, 150));
+        assert!(limiter.usage_at(1000 + DAY).is_empty());
+    }
+
+    #[test]
+    fn test_conversation_caps() {
+        let limits = Limits::default();
+        assert!(limits.check_conversation(1024, 100).is_ok());
+        assert!(limits.check_conversation(1024, 101).is_err());
+        assert!(limits.check_conversation(20 * 1024 * 1024 + 1, 1).is_err());
+    }
+
+    #[test]
+    fn test_overrides() {
+        let overrides = LimitOverrides { messages_per_day: Some(10_000), ..LimitOverrides::default() };
+        let limits = overrides.apply(&Limits::default());
+        assert_eq!(limits.messages_per_day, 10_000);
+        assert_eq!(limits.knocks_per_hour, 100);
+    }
+
+    #[test]
+    fn test_state_survives_reload() {
+        let path = TempFile::new("ratelimit", "reload");
+        let limits = Limits { knocks_per_hour: 1, ..Limits::default() };
+
+        let mut limiter = RateLimiter::load(path.to_path_buf()).unwrap();
+        limiter.check_knock("quest-1", &limits, 100).unwrap();
+        limiter.save().unwrap();
+
+        let mut reloaded = RateLimiter::load(path.to_path_buf()).unwrap();
+        assert!(reloaded.check_knock("quest-1", &limits, 100).is_err());
+    }
+}
@@ -0,0 +1,55 @@
+use crate::protocol::{ErrorCode, ProtocolError};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// How far a KNOCK timestamp may be from our clock, in seconds.
+pub const KNOCK_WINDOW: u32 = 300;
+
+/// The KNOCKs seen within the window, keyed by `from` and `eph_key`, so a
+/// recorded KNOCK cannot be played back while its timestamp is fresh.
+#[derive(Default)]
+pub struct KnockCache {
+    seen: Mutex<HashMap<(String, [u8; 32]), u32>>,
+}
+
+impl KnockCache {
+    /// Admits an authenticated KNOCK sent at `timestamp`. A stale, future
+    /// or repeated KNOCK is a `replay_detected` error.
+    pub fn check(&self, from: &str, eph_key: &[u8; 32], timestamp: u32, now: u32) -> Result<(), ProtocolError> {
+        if timestamp.abs_diff(now) > KNOCK_WINDOW {
+            return Err(ProtocolError::new(
+                ErrorCode::ReplayDetected,
+                format!("KNOCK timestamp is more than {}s from our clock", KNOCK_WINDOW),
+            ));
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, at| at.abs_diff(now) <= KNOCK_WINDOW);
+        if seen.insert((from.to_string(), *eph_key), timestamp).is_some() {
+            return Err(ProtocolError::new(ErrorCode::ReplayDetected, "KNOCK was already received"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_replayed_and_stale_knocks() {
+        let cache = KnockCache::default();
+        let now = 1_000_000;
+
+        assert!(cache.check("quest-1", &[1; 32], now - 10, now).is_ok());
+        let replayed = cache.check("quest-1", &[1; 32], now - 10, now + 5).unwrap_err();
+        assert_eq!(replayed.code, ErrorCode::ReplayDetected);
+
+        // Another key or another agent is a different KNOCK.
+        assert!(cache.check("quest-1", &[2; 32], now, now).is_ok());
+        assert!(cache.check("quest-2", &[1; 32], now, now).is_ok());
+
+        assert!(cache.check("quest-1", &[3; 32], now - KNOCK_WINDOW - 1, now).is_err());
+        assert!(cache.check("quest-1", &[3; 32], now + KNOCK_WINDOW + 1, now).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use crate::handler::{Builtin, Handler, HandlerOptions};
+use crate::payload::Category;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// One `[[handlers]]` entry. Every criterion given must match; an empty
+/// list matches anything.
+#[derive(Deserialize, Clone)]
+pub struct RouteConfig {
+    pub name: Option<String>,
+    /// KNOCK categories (`c`).
+    #[serde(default)]
+    pub category: Vec<Category>,
+    /// WISH `task.act` patterns. Not known at KNOCK, so routes with actions
+    /// only receive the evaluate, execute and thank calls.
+    #[serde(default)]
+    pub action: Vec<String>,
+    /// Peer ID patterns.
+    #[serde(default)]
+    pub peer: Vec<String>,
+    /// Keyring labels; the peer needs at least one of them.
+    #[serde(default)]
+    pub label: Vec<String>,
+    /// Handler program, run like `[openclaw]`. Exactly one of `path` and
+    /// `builtin` must be set.
+    pub path: Option<String>,
+    pub builtin: Option<Builtin>,
+    /// File the `inbox` builtin appends to.
+    pub inbox: Option<String>,
+    #[serde(flatten)]
+    pub run: HandlerOptions,
+}
+
+/// What is known about a conversation when picking its handler.
+pub struct Target<'a> {
+    pub category: Option<Category>,
+    pub action: Option<&'a str>,
+    pub peer: &'a str,
+    pub labels: &'a [String],
+}
+
+struct Route {
+    name: String,
+    config: RouteConfig,
+    handler: Handler,
+}
+
+impl Route {
+    fn matches(&self, target: &Target) -> bool {
+        let config = &self.config;
+        let category = config.category.is_empty()
+            || target.category.is_some_and(|c| config.category.contains(&c));
+        let action = config.action.is_empty()
+            || target.action.is_some_and(|a| config.action.iter().any(|p| wildcard_match(p, a)));
+        let peer = config.peer.is_empty() || config.peer.iter().any(|p| wildcard_match(p, target.peer));
+        let label = config.label.is_empty() || config.label.iter().any(|l| target.labels.contains(l));
+        category && action && peer && label
+    }
+}
+
+/// Picks the handler for each call: the first matching `[[handlers]]`
+/// route, or `[openclaw]` when none matches.
+pub struct Router {
+    routes: Vec<Route>,
+    default: Handler,
+}
+
+pub const DEFAULT_ROUTE: &str = "default";
+
+impl Router {
+    pub fn new(routes: &[RouteConfig], default: Handler) -> Result<Self> {
+        let routes = routes
+            .iter()
+            .enumerate()
+            .map(|(i, config)| {
+                let name = config.name.clone().unwrap_or_else(|| format!("handlers[{}]", i));
+                let handler = match (&config.path, config.builtin) {
+                    (Some(path), None) => Handler::new(path, &config.run),
+                    (None, Some(kind)) => {
+                        let inbox = config.inbox.as_deref().unwrap_or("~/.wish-protocol/inbox.jsonl");
+                        Handler::Builtin {
+                            kind,
+                            inbox: PathBuf::from(shellexpand::tilde(inbox).into_owned()),
+                        }
+                    }
+                    _ => return Err(anyhow!("Handler route {} needs exactly one of path and builtin", name)),
+                };
+                Ok(Route { name, config: config.clone(), handler })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { routes, default })
+    }
+
+    /// Every configured handler, routes first.
+    pub fn handlers(&self) -> impl Iterator<Item = &Handler> {
+        self.routes.iter().map(|r| &r.handler).chain(std::iter::once(&self.default))
+    }
+
+    /// The route name and handler for `target`.
+    pub fn route(&self, target: &Target) -> (&str, &Handler) {
+        self.routes
+            .iter()
+            .find(|r| r.matches(target))
+            .map(|r| (r.name.as_str(), &r.handler))
+            .unwrap_or((DEFAULT_ROUTE, &self.default))
+    }
+}
+
+/// Matches `text` against `pattern`, where `*` stands for any run of
+/// characters and `?` for exactly one.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes(toml_text: &str) -> Router {
+        #[derive(Deserialize)]
+        struct File {
+            handlers: Vec<RouteConfig>,
+        }
+        let file: File = toml::from_str(toml_text).unwrap();
+        let default = Handler::new("/bin/true", &HandlerOptions::default());
+        Router::new(&file.handlers, default).unwrap()
+    }
+
+    fn target<'a>(category: Option<Category>, action: Option<&'a str>, peer: &'a str, labels: &'a [String]) -> Target<'a> {
+        Target { category, action, peer, labels }
+    }
+
+    #[test]
+    fn test_first_matching_route_wins() {
+        let router = routes(
+            r#"
+            [[handlers]]
+            name = "tips"
+            category = [3]
+            builtin = "inbox"
+
+            [[handlers]]
+            name = "translate"
+            action = ["translate_*"]
+            label = ["trusted"]
+            path = "/usr/local/bin/translator"
+            mode = "worker"
+
+            [[handlers]]
+            name = "strangers"
+            peer = ["spam-*", "test-??"]
+            builtin = "decline"
+            "#,
+        );
+        let trusted = vec!["trusted".to_string()];
+
+        assert_eq!(router.route(&target(Some(Category::Question), None, "quest-1", &[])).0, "tips");
+        assert_eq!(router.route(&target(Some(Category::TaskRequest), None, "quest-1", &trusted)).0, DEFAULT_ROUTE);
+        assert_eq!(router.route(&target(Some(Category::TaskRequest), Some("translate_fr"), "quest-1", &trusted)).0, "translate");
+        assert_eq!(router.route(&target(Some(Category::TaskRequest), Some("translate_fr"), "quest-1", &[])).0, DEFAULT_ROUTE);
+        assert_eq!(router.route(&target(None, None, "spam-bot", &[])).0, "strangers");
+        assert_eq!(router.route(&target(None, None, "test-42", &[])).0, "strangers");
+        assert_eq!(router.route(&target(None, None, "test-420", &[])).0, DEFAULT_ROUTE);
+    }
+
+    #[test]
+    fn test_route_needs_one_handler() {
+        let file: Vec<RouteConfig> = vec![toml::from_str("name = \"empty\"").unwrap()];
+        let default = Handler::new("/bin/true", &HandlerOptions::default());
+        assert!(Router::new(&file, default).is_err());
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("churi-*", "churi-7b9e4d2a"));
+        assert!(wildcard_match("*-7b9e*", "churi-7b9e4d2a"));
+        assert!(!wildcard_match("churi-*", "nono-a3f28c91"));
+        assert!(wildcard_match("a*b*c", "aXbYbZc"));
+    }
+}
@@ -0,0 +1,336 @@
+use crate::handler::Progress;
+use crate::payload::{self, Category, Priority};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// How often a waiting task reports its place even when it has not moved,
+/// so the requester keeps extending its GIFT deadline.
+const REPORT_INTERVAL: Duration = Duration::from_secs(30);
+/// Execution times kept for estimates.
+const HISTORY: usize = 20;
+/// Assumed execution time, in seconds, until a task has finished.
+const DEFAULT_DURATION: u64 = 60;
+
+/// How many granted tasks run at once. The rest wait for a slot, highest
+/// KNOCK `pri` first.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct CapacityConfig {
+    /// Tasks running at once; 0 for no limit.
+    pub max_running: usize,
+    /// Tasks waiting for a slot; further KNOCKs are told the daemon is busy.
+    pub max_queued: usize,
+    /// Tasks running at once per KNOCK category, keyed by `c`.
+    pub categories: HashMap<String, usize>,
+}
+
+impl Default for CapacityConfig {
+    fn default() -> Self {
+        Self { max_running: 4, max_queued: 16, categories: HashMap::new() }
+    }
+}
+
+/// `CapacityConfig` with the category keys parsed.
+struct Capacity {
+    max_running: usize,
+    max_queued: usize,
+    categories: HashMap<Category, usize>,
+}
+
+impl TryFrom<&CapacityConfig> for Capacity {
+    type Error = anyhow::Error;
+
+    fn try_from(config: &CapacityConfig) -> Result<Self> {
+        let categories = config
+            .categories
+            .iter()
+            .map(|(c, max)| {
+                payload::parse_code(c)
+                    .map(|c| (c, *max))
+                    .map_err(|e| anyhow!("{} in [capacity.categories]", e))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { max_running: config.max_running, max_queued: config.max_queued, categories })
+    }
+}
+
+/// A granted task asking for a slot.
+#[derive(Clone, Copy, Debug)]
+pub struct Task {
+    /// KNOCK `pri`.
+    pub priority: Priority,
+    pub category: Category,
+    /// GRANT `est_t`, in seconds.
+    pub est: u64,
+}
+
+struct Waiting {
+    id: u64,
+    task: Task,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    per_category: HashMap<Category, usize>,
+    /// In the order they get slots.
+    waiting: Vec<Waiting>,
+    next_id: u64,
+    /// Recent execution times in seconds, newest last.
+    durations: VecDeque<u64>,
+}
+
+impl State {
+    fn has_room(&self, capacity: &Capacity, category: Category) -> bool {
+        let total = capacity.max_running == 0 || self.running < capacity.max_running;
+        total && self.category_has_room(capacity, category)
+    }
+
+    fn category_has_room(&self, capacity: &Capacity, category: Category) -> bool {
+        match capacity.categories.get(&category) {
+            Some(&max) => self.per_category.get(&category).copied().unwrap_or(0) < max,
+            None => true,
+        }
+    }
+
+    /// Place of waiting task `id` in the queue, counting only the tasks
+    /// ahead of it whose category has room; `None` once it may start.
+    fn position(&self, capacity: &Capacity, id: u64) -> Option<usize> {
+        let mut ahead = 0;
+        for waiting in &self.waiting {
+            if waiting.id == id {
+                if ahead == 0 && self.has_room(capacity, waiting.task.category) {
+                    return None;
+                }
+                return Some(ahead + 1);
+            }
+            if self.category_has_room(capacity, waiting.task.category) {
+                ahead += 1;
+            }
+        }
+        None
+    }
+
+    /// Seconds until the task at `position` in the queue can start.
+    fn estimate(&self, capacity: &Capacity, position: usize) -> u64 {
+        let average = match self.durations.len() as u64 {
+            0 => DEFAULT_DURATION,
+            n => self.durations.iter().sum::<u64>().div_ceil(n),
+        };
+        let slots = capacity.max_running.max(1) as u64;
+        (position as u64).div_ceil(slots).saturating_mul(average).max(1)
+    }
+}
+
+/// Hands out execution slots to granted tasks, shared by all connections.
+pub struct Scheduler {
+    capacity: Mutex<Capacity>,
+    state: Mutex<State>,
+    /// Bumped whenever a slot frees up, a task leaves the queue or the
+    /// capacity changes.
+    changed: watch::Sender<()>,
+}
+
+impl Scheduler {
+    pub fn new(config: &CapacityConfig) -> Result<Self> {
+        Ok(Self {
+            capacity: Mutex::new(Capacity::try_from(config)?),
+            state: Mutex::new(State::default()),
+            changed: watch::channel(()).0,
+        })
+    }
+
+    /// Applies a reloaded config; running tasks keep their slots.
+    pub fn configure(&self, config: &CapacityConfig) -> Result<()> {
+        *self.capacity.lock().unwrap() = Capacity::try_from(config)?;
+        self.wake();
+        Ok(())
+    }
+
+    /// Whether a KNOCK in `category` may go ahead: `Err` with the seconds
+    /// until it is worth trying again if the queue is full.
+    pub fn admit(&self, category: Category) -> Result<(), u64> {
+        let capacity = self.capacity.lock().unwrap();
+        let state = self.state.lock().unwrap();
+        let queued = state.waiting.len();
+        if queued < capacity.max_queued || (queued == 0 && state.has_room(&capacity, category)) {
+            return Ok(());
+        }
+        Err(state.estimate(&capacity, queued + 1))
+    }
+
+    /// Tasks running and waiting.
+    pub fn load(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        (state.running, state.waiting.len())
+    }
+
+    /// Waits for a slot for `task`, reporting its place in the queue to
+    /// `progress` as it moves. The slot is freed when dropped; dropping the
+    /// future leaves the queue.
+    pub async fn acquire(&self, task: Task, progress: Option<&Progress>) -> Slot<'_> {
+        let mut changed = self.changed.subscribe();
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            let id = state.next_id;
+            state.next_id += 1;
+            let at = state.waiting.iter().position(|w| w.task.priority < task.priority).unwrap_or(state.waiting.len());
+            state.waiting.insert(at, Waiting { id, task });
+            id
+        };
+        // Tasks behind this one have moved back.
+        self.wake();
+        let mut queued = Queued { scheduler: self, id: Some(id) };
+
+        let mut reported = None;
+        loop {
+            let report = {
+                let capacity = self.capacity.lock().unwrap();
+                let mut state = self.state.lock().unwrap();
+                match state.position(&capacity, id) {
+                    Some(position) => (reported != Some(position)).then(|| {
+                        let eta = state.estimate(&capacity, position).saturating_add(task.est);
+                        (position, eta)
+                    }),
+                    None => {
+                        state.waiting.retain(|w| w.id != id);
+                        state.running += 1;
+                        *state.per_category.entry(task.category).or_default() += 1;
+                        break;
+                    }
+                }
+            };
+            if let (Some((position, eta)), Some(progress)) = (report, progress) {
+                let _ = progress.send(serde_json::json!({
+                    "prog": 0,
+                    "stat": "queued",
+                    "msg": format!("Position {} in the queue", position),
+                    "eta": eta,
+                }));
+                reported = Some(position);
+            }
+            tokio::select! {
+                _ = changed.changed() => {}
+                _ = tokio::time::sleep(REPORT_INTERVAL) => reported = None,
+            }
+        }
+        queued.id = None;
+        Slot { scheduler: self, category: task.category, started: Instant::now() }
+    }
+
+    fn wake(&self) {
+        self.changed.send_replace(());
+    }
+}
+
+/// Takes a task that gave up waiting out of the queue.
+struct Queued<'a> {
+    scheduler: &'a Scheduler,
+    id: Option<u64>,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.scheduler.state.lock().unwrap().waiting.retain(|w| w.id != id);
+            self.scheduler.wake();
+        }
+    }
+}
+
+/// A running task's hold on a slot.
+pub struct Slot<'a> {
+    scheduler: &'a Scheduler,
+    category: Category,
+    started: Instant,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut state = self.scheduler.state.lock().unwrap();
+        state.running -= 1;
+        if let Some(count) = state.per_category.get_mut(&self.category) {
+            *count -= 1;
+        }
+        if state.durations.len() == HISTORY {
+            state.durations.pop_front();
+        }
+        state.durations.push_back(self.started.elapsed().as_secs());
+        drop(state);
+        self.scheduler.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler(max_running: usize, max_queued: usize, categories: &[(&str, usize)]) -> Scheduler {
+        let categories = categories.iter().map(|(c, max)| (c.to_string(), *max)).collect();
+        Scheduler::new(&CapacityConfig { max_running, max_queued, categories }).unwrap()
+    }
+
+    fn task(priority: Priority, category: Category) -> Task {
+        Task { priority, category, est: 10 }
+    }
+
+    #[tokio::test]
+    async fn test_priority_order_and_positions() {
+        let scheduler = scheduler(1, 4, &[]);
+        let running = scheduler.acquire(task(Priority::Normal, Category::TaskRequest), None).await;
+
+        let (progress, mut events) = tokio::sync::mpsc::unbounded_channel();
+        let mut low = Box::pin(scheduler.acquire(task(Priority::Low, Category::TaskRequest), Some(&progress)));
+        let mut urgent = Box::pin(scheduler.acquire(task(Priority::Urgent, Category::TaskRequest), None));
+
+        // Poll both into the queue: the urgent task goes ahead of the low one.
+        assert!(futures::poll!(&mut low).is_pending());
+        assert!(futures::poll!(&mut urgent).is_pending());
+        assert_eq!(events.recv().await.unwrap()["msg"], "Position 1 in the queue");
+        assert!(futures::poll!(&mut low).is_pending());
+        assert_eq!(events.recv().await.unwrap()["msg"], "Position 2 in the queue");
+        assert_eq!(scheduler.load(), (1, 2));
+
+        drop(running);
+        let urgent = urgent.await;
+        assert!(futures::poll!(&mut low).is_pending());
+        assert_eq!(scheduler.load(), (1, 1));
+        drop(urgent);
+        low.await;
+    }
+
+    #[tokio::test]
+    async fn test_category_limit() {
+        let scheduler = scheduler(2, 4, &[("3", 1)]);
+        let _first = scheduler.acquire(task(Priority::Normal, Category::Question), None).await;
+
+        // The second category 3 task waits; other categories pass it.
+        let mut second = Box::pin(scheduler.acquire(task(Priority::Normal, Category::Question), None));
+        assert!(futures::poll!(&mut second).is_pending());
+        let _other = scheduler.acquire(task(Priority::Low, Category::TaskRequest), None).await;
+        assert_eq!(scheduler.load(), (2, 1));
+
+        let categories = HashMap::from([("99".to_string(), 1)]);
+        assert!(Scheduler::new(&CapacityConfig { categories, ..CapacityConfig::default() }).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_admit_when_queue_full() {
+        let scheduler = scheduler(1, 1, &[]);
+        assert_eq!(scheduler.admit(Category::TaskRequest), Ok(()));
+        let _running = scheduler.acquire(task(Priority::Normal, Category::TaskRequest), None).await;
+        assert_eq!(scheduler.admit(Category::TaskRequest), Ok(()));
+
+        let mut waiting = Box::pin(scheduler.acquire(task(Priority::Normal, Category::TaskRequest), None));
+        assert!(futures::poll!(&mut waiting).is_pending());
+        assert_eq!(scheduler.admit(Category::TaskRequest), Err(2 * DEFAULT_DURATION));
+
+        // Giving up leaves the queue.
+        drop(waiting);
+        assert_eq!(scheduler.admit(Category::TaskRequest), Ok(()));
+    }
+}
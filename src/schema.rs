@@ -0,0 +1,223 @@
+use serde_json::Value;
+use std::fmt;
+
+/// A value that does not match its schema: the first failing field, as a
+/// path such as `task.par.tags[1]`, and what is wrong with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invalid {
+    pub field: String,
+    pub msg: String,
+}
+
+impl Invalid {
+    pub fn new(field: impl Into<String>, msg: impl Into<String>) -> Self {
+        Self { field: field.into(), msg: msg.into() }
+    }
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.msg)
+    }
+}
+
+/// The keywords `validate` enforces.
+const KEYWORDS: &[&str] = &[
+    "type", "enum", "const", "properties", "required", "additionalProperties", "items",
+    "minItems", "maxItems", "minLength", "maxLength", "minimum", "maximum",
+];
+
+/// Keywords that only describe a schema and need no checking.
+const ANNOTATIONS: &[&str] = &["$schema", "$comment", "title", "description", "default", "examples"];
+
+/// Checks that `schema` uses only keywords `validate` enforces, so a
+/// schema is never taken as stricter than it is. The error names the
+/// first offending keyword, starting from `path`.
+pub fn check_supported(schema: &Value, path: &str) -> Result<(), Invalid> {
+    let Some(schema) = schema.as_object() else {
+        return match schema {
+            Value::Bool(_) => Ok(()),
+            _ => Err(Invalid::new(path, "a schema must be an object or a boolean")),
+        };
+    };
+    for (keyword, value) in schema {
+        if !KEYWORDS.contains(&keyword.as_str()) && !ANNOTATIONS.contains(&keyword.as_str()) {
+            return Err(Invalid::new(path, format!("unsupported keyword {:?}", keyword)));
+        }
+        match keyword.as_str() {
+            "properties" => {
+                let properties = value.as_object().ok_or_else(|| Invalid::new(path, "properties must be an object"))?;
+                for (name, field) in properties {
+                    check_supported(field, &format!("{}.{}", path, name))?;
+                }
+            }
+            "additionalProperties" => check_supported(value, &format!("{}.*", path))?,
+            "items" => check_supported(value, &format!("{}[]", path))?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Checks `value` against a JSON Schema, supporting the keywords action
+/// declarations need: `type`, `enum`, `const`, `properties`, `required`,
+/// `additionalProperties`, `items`, `minItems`/`maxItems`,
+/// `minLength`/`maxLength` and `minimum`/`maximum`. Schemas are checked
+/// with `check_supported` when loaded, so no other keyword reaches here.
+/// The error names the first failing field, starting from `path`.
+pub fn validate(schema: &Value, value: &Value, path: &str) -> Result<(), Invalid> {
+    let Some(schema) = schema.as_object() else {
+        // `true` and `{}` accept anything; `false` nothing.
+        return match schema {
+            Value::Bool(false) => Err(Invalid::new(path, "not allowed")),
+            _ => Ok(()),
+        };
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let types: Vec<&str> = match expected {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|t| has_type(value, t)) {
+            return Err(Invalid::new(path, format!("expected {}, got {}", types.join(" or "), type_name(value))));
+        }
+    }
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            return Err(Invalid::new(path, format!("must be one of {}", Value::Array(allowed.clone()))));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            return Err(Invalid::new(path, format!("must be {}", expected)));
+        }
+    }
+
+    match value {
+        Value::Object(map) => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for name in schema.get("required").and_then(Value::as_array).into_iter().flatten() {
+                if let Some(name) = name.as_str() {
+                    if !map.contains_key(name) {
+                        return Err(Invalid::new(format!("{}.{}", path, name), "required"));
+                    }
+                }
+            }
+            for (name, field) in map {
+                let field_path = format!("{}.{}", path, name);
+                match properties.and_then(|p| p.get(name)) {
+                    Some(field_schema) => validate(field_schema, field, &field_path)?,
+                    None => match schema.get("additionalProperties") {
+                        Some(Value::Bool(false)) => return Err(Invalid::new(field_path, "unexpected field")),
+                        Some(extra) => validate(extra, field, &field_path)?,
+                        None => {}
+                    },
+                }
+            }
+        }
+        Value::Array(items) => {
+            check_bound(schema, "minItems", items.len() as f64, |n, min| n >= min, path, "at least {} items")?;
+            check_bound(schema, "maxItems", items.len() as f64, |n, max| n <= max, path, "at most {} items")?;
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate(item_schema, item, &format!("{}[{}]", path, i))?;
+                }
+            }
+        }
+        Value::String(text) => {
+            let len = text.chars().count() as f64;
+            check_bound(schema, "minLength", len, |n, min| n >= min, path, "at least {} characters")?;
+            check_bound(schema, "maxLength", len, |n, max| n <= max, path, "at most {} characters")?;
+        }
+        Value::Number(number) => {
+            let n = number.as_f64().unwrap_or_default();
+            check_bound(schema, "minimum", n, |n, min| n >= min, path, "at least {}")?;
+            check_bound(schema, "maximum", n, |n, max| n <= max, path, "at most {}")?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn check_bound(
+    schema: &serde_json::Map<String, Value>,
+    keyword: &str,
+    actual: f64,
+    within: fn(f64, f64) -> bool,
+    path: &str,
+    requirement: &str,
+) -> Result<(), Invalid> {
+    match schema.get(keyword).and_then(Value::as_f64) {
+        Some(bound) if !within(actual, bound) => {
+            Err(Invalid::new(path, format!("must be {}", requirement.replace("{}", &bound.to_string()))))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "integer" => value.as_i64().is_some() || value.as_u64().is_some(),
+        "number" => value.is_number(),
+        other => type_name(value) == other,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate() {
+        let schema = json!({
+            "type": "object",
+            "required": ["lang"],
+            "additionalProperties": false,
+            "properties": {
+                "lang": {"type": "string", "enum": ["en", "de"]},
+                "max_words": {"type": "integer", "minimum": 10, "maximum": 1000},
+                "tags": {"type": "array", "maxItems": 2, "items": {"type": "string", "maxLength": 5}},
+            },
+        });
+        let check = |value: Value| validate(&schema, &value, "task.par").map_err(|e| e.to_string());
+
+        assert_eq!(check(json!({"lang": "en", "max_words": 200, "tags": ["a"]})), Ok(()));
+        assert_eq!(check(json!({"max_words": 200})), Err("task.par.lang: required".to_string()));
+        assert_eq!(check(json!({"lang": "fr"})), Err(r#"task.par.lang: must be one of ["en","de"]"#.to_string()));
+        assert_eq!(check(json!({"lang": "en", "max_words": 2.5})), Err("task.par.max_words: expected integer, got number".to_string()));
+        assert_eq!(check(json!({"lang": "en", "max_words": 5})), Err("task.par.max_words: must be at least 10".to_string()));
+        assert_eq!(check(json!({"lang": "en", "tags": ["a", "toolong"]})), Err("task.par.tags[1]: must be at most 5 characters".to_string()));
+        assert_eq!(check(json!({"lang": "en", "extra": 1})), Err("task.par.extra: unexpected field".to_string()));
+        assert_eq!(check(json!([])), Err("task.par: expected object, got array".to_string()));
+        assert_eq!(validate(&json!({}), &json!(1), "x"), Ok(()));
+        assert_eq!(validate(&schema, &json!({}), "task.par"), Err(Invalid::new("task.par.lang", "required")));
+        assert_eq!(check_supported(&schema, "par"), Ok(()));
+    }
+
+    #[test]
+    fn test_unsupported_keywords_rejected() {
+        let schema = json!({
+            "type": "object",
+            "description": "Review options",
+            "properties": {"lang": {"type": "string", "pattern": "^[a-z]+$"}},
+        });
+        assert_eq!(check_supported(&schema, "par"), Err(Invalid::new("par.lang", r#"unsupported keyword "pattern""#)));
+        assert!(check_supported(&json!({"items": {"oneOf": []}}), "par").is_err());
+        assert!(check_supported(&json!({"$ref": "#/defs/x"}), "par").is_err());
+        assert!(check_supported(&json!(true), "par").is_ok());
+    }
+}
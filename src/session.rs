@@ -0,0 +1,363 @@
+use crate::crypto;
+use crate::payload::{StagePayload, ThankContext, ThankPayload};
+use crate::protocol::{self, ErrorCode, Message, PeerError, ProtocolError, Stage, PROTOCOL_VERSION};
+use anyhow::Result;
+use std::future::Future;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+/// How long we wait for the closing THANK after sending an ERROR.
+const ERROR_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How far `interrupt` moves the counter ahead, leaving room for the
+/// messages the peer sends before it reads ours.
+const INTERRUPT_GAP: u32 = 1024;
+
+/// The encrypted part of a conversation, after KNOCK/WELCOME. Both sides
+/// share one counter that increases with every message sent in either
+/// direction.
+pub struct Session {
+    key: [u8; 32],
+    counter: u32,
+    my_id: String,
+    peer_id: String,
+    bytes: u64,
+    messages: u64,
+    /// Counter of the last message the peer sent before reading our
+    /// `interrupt`, once we have sent one.
+    interrupted: Option<u32>,
+}
+
+impl Session {
+    pub fn new(key: [u8; 32], counter: u32, my_id: &str, peer_id: &str) -> Self {
+        Self {
+            key,
+            counter,
+            my_id: my_id.to_string(),
+            peer_id: peer_id.to_string(),
+            bytes: 0,
+            messages: 0,
+            interrupted: None,
+        }
+    }
+
+    /// Envelope bytes and messages exchanged so far, both directions.
+    pub fn traffic(&self) -> (u64, u64) {
+        (self.bytes, self.messages)
+    }
+
+    /// Sends `payload` as the next message of its stage.
+    pub async fn send<W, P>(&mut self, writer: &mut W, payload: &P) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+        P: StagePayload,
+    {
+        self.counter += 1;
+        let timestamp = protocol::current_timestamp();
+
+        let message = Message {
+            stage: P::STAGE.to_u8(),
+            counter: self.counter,
+            timestamp,
+            from: self.my_id.clone(),
+            to: self.peer_id.clone(),
+            payload: payload.to_map(),
+        };
+
+        let plaintext = protocol::encode_message(&message)?;
+        protocol::validate_size(message.stage, plaintext.len())?;
+        let aad = protocol::build_aad(PROTOCOL_VERSION, &self.my_id, &self.peer_id);
+
+        let encrypted = crypto::encrypt_message(&self.key, self.counter, timestamp, &plaintext, &aad)?;
+
+        let mut envelope = Vec::new();
+        envelope.extend(self.counter.to_be_bytes());
+        envelope.extend(timestamp.to_be_bytes());
+        envelope.extend(encrypted);
+
+        protocol::send_framed_message(writer, &envelope).await?;
+        self.bytes += envelope.len() as u64;
+        self.messages += 1;
+        Ok(())
+    }
+
+    /// Sends a message without waiting for our turn, while the peer may be
+    /// sending too. It skips the counter ahead by `INTERRUPT_GAP` so neither
+    /// side reuses a counter, and from then on messages the peer sent before
+    /// it saw ours are still accepted.
+    pub async fn interrupt<W, P>(&mut self, writer: &mut W, payload: &P) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+        P: StagePayload,
+    {
+        self.interrupted = Some(self.counter);
+        self.counter = self.counter.saturating_add(INTERRUPT_GAP - 1);
+        self.send(writer, payload).await
+    }
+
+    /// Receives the next message and the size of its envelope. Failures carry
+    /// a `ProtocolError` with the matching spec code; an ERROR from the peer
+    /// comes back as `PeerError`.
+    pub async fn receive<R>(&mut self, reader: &mut R) -> Result<(Message, usize)>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let envelope = protocol::receive_framed_message(reader).await.map_err(|e| {
+            match e.downcast_ref::<std::io::Error>() {
+                Some(io_error) => ProtocolError::new(ErrorCode::ConnectionLost, io_error.to_string()).into(),
+                None => e,
+            }
+        })?;
+        let envelope_size = envelope.len();
+        self.bytes += envelope_size as u64;
+        self.messages += 1;
+
+        if envelope.len() < 8 {
+            return Err(ProtocolError::new(
+                ErrorCode::InvalidFormat,
+                format!("Envelope too short: {} bytes", envelope.len()),
+            )
+            .into());
+        }
+
+        let remote_counter = u32::from_be_bytes(envelope[0..4].try_into()?);
+        let remote_timestamp = u32::from_be_bytes(envelope[4..8].try_into()?);
+        let ciphertext = &envelope[8..];
+
+        let crossed = self.interrupted.filter(|&last| remote_counter > last && remote_counter <= self.counter);
+        if remote_counter <= self.counter && crossed.is_none() {
+            return Err(ProtocolError::new(ErrorCode::ReplayDetected, "Message counter did not increase")
+                .with_details(serde_json::json!({
+                    "expected": format!("> {}", self.counter),
+                    "received": remote_counter,
+                }))
+                .into());
+        }
+        match crossed {
+            Some(_) => self.interrupted = Some(remote_counter),
+            None => {
+                self.counter = remote_counter;
+                self.interrupted = None;
+            }
+        }
+
+        let aad = protocol::build_aad(PROTOCOL_VERSION, &self.peer_id, &self.my_id);
+        let plaintext = crypto::decrypt_message(&self.key, remote_counter, remote_timestamp, ciphertext, &aad)
+            .map_err(|e| ProtocolError::new(ErrorCode::EncryptionFailed, e.to_string()))?;
+
+        let message = protocol::decode_message(&plaintext)?;
+        protocol::validate_size(message.stage, plaintext.len())?;
+
+        if message.from != self.peer_id || message.to != self.my_id {
+            return Err(ProtocolError::new(
+                ErrorCode::AuthenticationFailed,
+                format!(
+                    "Message addressed {} -> {}, expected {} -> {}",
+                    message.from, message.to, self.peer_id, self.my_id
+                ),
+            )
+            .into());
+        }
+
+        if message.stage == Stage::Error.to_u8() {
+            return Err(PeerError(ProtocolError::from_payload(&message.payload)?).into());
+        }
+
+        Ok((message, envelope_size))
+    }
+
+    /// `receive` for a message whose first byte `next_byte` already read.
+    pub async fn receive_rest<R>(&mut self, first: u8, reader: &mut R) -> Result<(Message, usize)>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let first = [first];
+        self.receive(&mut (&first[..]).chain(reader)).await
+    }
+
+    /// `receive` with a deadline; see `within`.
+    pub async fn receive_within<R>(
+        &mut self,
+        reader: &mut R,
+        limit: Duration,
+        awaiting: Stage,
+    ) -> Result<(Message, usize)>
+    where
+        R: AsyncRead + Unpin,
+    {
+        within(limit, awaiting, self.receive(reader)).await
+    }
+
+    /// Closes the conversation after `err` (spec §10.3). If the peer sent the
+    /// ERROR we answer with THANK ctx=3; otherwise we send our own ERROR and
+    /// wait briefly for the peer's THANK. After a timeout we send the
+    /// auto-THANK ourselves instead of waiting (spec §10.4). Returns `err`
+    /// for the caller to propagate.
+    pub async fn fail<S>(&mut self, stream: &mut S, err: anyhow::Error, fallback: ErrorCode) -> anyhow::Error
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if err.downcast_ref::<PeerError>().is_some() {
+            let thank = ThankPayload::new(ThankContext::Error, true, None);
+            let _ = self.send(stream, &thank).await;
+            return err;
+        }
+
+        let error = ProtocolError::from_anyhow(&err, fallback);
+        if error.code == ErrorCode::ConnectionLost {
+            return err;
+        }
+        if self.send(stream, &error.to_payload()).await.is_err() {
+            return err;
+        }
+
+        match error.code {
+            // Spec §10.6: close immediately after a replay.
+            ErrorCode::ReplayDetected => {}
+            ErrorCode::Timeout => {
+                let thank = ThankPayload {
+                    retry: Some(true),
+                    ..ThankPayload::new(ThankContext::Error, true, Some("Connection timed out."))
+                };
+                let _ = self.send(stream, &thank).await;
+            }
+            _ => {
+                let _ = tokio::time::timeout(ERROR_CLOSE_TIMEOUT, self.receive(stream)).await;
+            }
+        }
+        err
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        crypto::zeroize_key(&mut self.key);
+    }
+}
+
+/// Waits for the next message to arrive and returns its first byte, for
+/// `receive_rest`. Unlike `receive` it reads nothing if dropped before it
+/// completes, so it can race other events.
+pub async fn next_byte<R>(reader: &mut R) -> Result<u8>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut first = [0u8; 1];
+    match reader.read(&mut first).await {
+        Ok(0) => Err(ProtocolError::new(ErrorCode::ConnectionLost, "Connection closed").into()),
+        Ok(_) => Ok(first[0]),
+        Err(e) => Err(ProtocolError::new(ErrorCode::ConnectionLost, e.to_string()).into()),
+    }
+}
+
+/// Runs `fut`, failing with a timeout `ProtocolError` if it has not
+/// finished after `limit`. `awaiting` is the stage we were waiting for.
+pub async fn within<T, F>(limit: Duration, awaiting: Stage, fut: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    match tokio::time::timeout(limit, fut).await {
+        Ok(result) => result,
+        Err(_) => Err(ProtocolError::new(
+            ErrorCode::Timeout,
+            format!("No response within {} seconds", limit.as_secs()),
+        )
+        .with_details(serde_json::json!({"at_stage": awaiting.to_u8()}))
+        .into()),
+    }
+}
+
+/// Error for a message that arrived at the wrong point in the conversation.
+pub fn unexpected_stage(expected: &str, stage: u8) -> anyhow::Error {
+    ProtocolError::new(ErrorCode::InvalidFormat, format!("Expected {}, got stage {}", expected, stage))
+        .with_details(serde_json::json!({"at_stage": stage}))
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::{GiftPayload, WrapPayload};
+
+    fn pair() -> (Session, Session) {
+        let key = [9u8; 32];
+        (
+            Session::new(key, 2, "quest-1", "churi-2"),
+            Session::new(key, 2, "churi-2", "quest-1"),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_error_reaches_peer_as_peer_error() {
+        let (mut requester, mut responder) = pair();
+        let (mut a, mut b) = tokio::io::duplex(64 * 1024);
+
+        let error = ProtocolError::new(ErrorCode::TaskFailed, "handler exited").recoverable();
+        responder.send(&mut b, &error.to_payload()).await.unwrap();
+
+        let err = requester.receive(&mut a).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<PeerError>(), Some(&PeerError(error)));
+    }
+
+    #[tokio::test]
+    async fn test_replayed_message_rejected() {
+        let (mut requester, mut responder) = pair();
+        let (mut a, mut b) = tokio::io::duplex(64 * 1024);
+
+        responder.send(&mut b, &WrapPayload::default()).await.unwrap();
+        requester.receive(&mut a).await.unwrap();
+
+        responder.counter -= 1;
+        responder.send(&mut b, &WrapPayload::default()).await.unwrap();
+        let err = requester.receive(&mut a).await.unwrap_err();
+        let error = ProtocolError::from_anyhow(&err, ErrorCode::InternalError);
+        assert_eq!(error.code, ErrorCode::ReplayDetected);
+    }
+
+    #[tokio::test]
+    async fn test_interrupt_crosses_messages_in_flight() {
+        let (mut requester, mut responder) = pair();
+        let (mut a, mut b) = tokio::io::duplex(64 * 1024);
+
+        responder.send(&mut b, &WrapPayload::default()).await.unwrap();
+        requester.interrupt(&mut a, &ThankPayload::new(ThankContext::Cancel, true, None)).await.unwrap();
+        responder.send(&mut b, &WrapPayload::default()).await.unwrap();
+
+        // Both WRAPs were sent before the responder read the THANK.
+        requester.receive(&mut a).await.unwrap();
+        requester.receive(&mut a).await.unwrap();
+        let (thank, _) = responder.receive(&mut b).await.unwrap();
+        assert_eq!(thank.stage, Stage::Thank.to_u8());
+
+        responder.send(&mut b, &GiftPayload::new(false, serde_json::Value::Null)).await.unwrap();
+        let (gift, _) = requester.receive(&mut a).await.unwrap();
+        assert_eq!(gift.stage, Stage::Gift.to_u8());
+
+        // A crossed message cannot be replayed.
+        responder.counter = 3;
+        responder.send(&mut b, &WrapPayload::default()).await.unwrap();
+        let err = requester.receive(&mut a).await.unwrap_err();
+        let error = ProtocolError::from_anyhow(&err, ErrorCode::InternalError);
+        assert_eq!(error.code, ErrorCode::ReplayDetected);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_sends_error_and_auto_thank() {
+        let (mut requester, mut responder) = pair();
+        let (mut a, mut b) = tokio::io::duplex(64 * 1024);
+
+        let err = responder
+            .receive_within(&mut b, Duration::from_millis(20), Stage::Wish)
+            .await
+            .unwrap_err();
+        let err = responder.fail(&mut b, err, ErrorCode::InternalError).await;
+        let error = ProtocolError::from_anyhow(&err, ErrorCode::InternalError);
+        assert_eq!(error.code, ErrorCode::Timeout);
+        assert_eq!(error.det, Some(serde_json::json!({"at_stage": 3})));
+
+        let err = requester.receive(&mut a).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<PeerError>().unwrap().0.code, ErrorCode::Timeout);
+        let (thank, _) = requester.receive(&mut a).await.unwrap();
+        assert_eq!(thank.stage, Stage::Thank.to_u8());
+        assert_eq!(thank.payload["ctx"], 3);
+    }
+}
@@ -0,0 +1,38 @@
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A file for a test, `wishp-<module>-<name>-<pid>` in the temp dir. It is
+/// removed when dropped, even if the test fails.
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    pub fn new(module: &str, name: &str) -> Self {
+        let file = format!("wishp-{}-{}-{}", module, name, std::process::id());
+        Self(std::env::temp_dir().join(file))
+    }
+
+    /// The path as a string, for APIs that take one.
+    pub fn to_str(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Deref for TempFile {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempFile {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
@@ -0,0 +1,348 @@
+use crate::handler::{self, HandlerOptions, Progress};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout};
+use tokio::sync::oneshot;
+
+/// First delay before restarting a worker that crashed soon after starting.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// A worker that ran at least this long restarts without delay.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+type Reply = std::result::Result<serde_json::Value, String>;
+type Pending = Arc<Mutex<HashMap<u64, Waiter>>>;
+
+/// Tells the worker to stop on a request nobody waits for any more.
+#[derive(Serialize)]
+struct Cancel {
+    id: u64,
+    cancel: bool,
+}
+
+/// A request sent to the worker and not yet answered.
+struct Waiter {
+    reply: oneshot::Sender<Reply>,
+    progress: Option<Progress>,
+}
+
+/// One line from the daemon to the worker.
+#[derive(Serialize)]
+struct Request<'a> {
+    id: u64,
+    msg: &'a serde_json::Value,
+}
+
+/// One line from the worker: `res` on success, `err` on failure, or a
+/// `wrap` progress event for a request still running.
+#[derive(Deserialize)]
+struct Response {
+    id: u64,
+    #[serde(default)]
+    res: serde_json::Value,
+    err: Option<String>,
+    wrap: Option<serde_json::Value>,
+}
+
+/// A handler process kept running across conversations. Requests and
+/// responses are newline-delimited JSON matched by `id`, so any number of
+/// requests can be in flight at once.
+pub struct Worker {
+    path: String,
+    options: HandlerOptions,
+    state: Arc<tokio::sync::Mutex<State>>,
+    pending: Pending,
+    next_id: AtomicU64,
+}
+
+#[derive(Default)]
+struct State {
+    process: Option<Process>,
+    started: Option<Instant>,
+    delay: Option<Duration>,
+}
+
+struct Process {
+    stdin: ChildStdin,
+    exited: Arc<AtomicBool>,
+}
+
+impl Worker {
+    pub fn new(path: &str, options: &HandlerOptions) -> Self {
+        Self {
+            path: path.to_string(),
+            options: options.clone(),
+            state: Arc::new(tokio::sync::Mutex::new(State::default())),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn options(&self) -> &HandlerOptions {
+        &self.options
+    }
+
+    /// Sends `request` to the worker, starting it if needed, and waits up to
+    /// the handler timeout for the matching response. If the call times out
+    /// or is dropped first, the worker is told to cancel the request.
+    pub async fn call(&self, request: &serde_json::Value, progress: Option<&Progress>) -> Result<serde_json::Value> {
+        let limit = Duration::from_secs(self.options.timeout);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut abandoned = Abandoned { id, pending: self.pending.clone(), state: Some(self.state.clone()) };
+
+        let reply = tokio::time::timeout(limit, async {
+            let receiver = self.send(id, request, progress.cloned()).await?;
+            receiver
+                .await
+                .map_err(|_| handler::crashed("Handler worker exited before responding".to_string()))
+        })
+        .await;
+
+        match reply {
+            Ok(Ok(Ok(value))) => {
+                abandoned.state = None;
+                Ok(value)
+            }
+            Ok(Ok(Err(msg))) => {
+                abandoned.state = None;
+                Err(handler::task_failed(msg))
+            }
+            Ok(Err(e)) => {
+                abandoned.state = None;
+                Err(e)
+            }
+            Err(_) => Err(handler::timed_out(self.options.timeout)),
+        }
+    }
+
+    async fn send(
+        &self,
+        id: u64,
+        request: &serde_json::Value,
+        progress: Option<Progress>,
+    ) -> Result<oneshot::Receiver<Reply>> {
+        let mut line = serde_json::to_vec(&Request { id, msg: request })?;
+        line.push(b'\n');
+
+        let mut state = self.state.lock().await;
+        let running = state.process.as_ref().is_some_and(|p| !p.exited.load(Ordering::SeqCst));
+        if !running {
+            state.process = None;
+            self.restart(&mut state).await?;
+        }
+        let Some(process) = state.process.as_mut() else {
+            return Err(anyhow!("Handler worker is not running"));
+        };
+
+        // Register before checking `exited`: the reader sets it before
+        // failing everything pending, so we cannot miss both.
+        let (reply, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, Waiter { reply, progress });
+        if process.exited.load(Ordering::SeqCst) {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(handler::crashed("Handler worker exited".to_string()));
+        }
+
+        if let Err(e) = process.stdin.write_all(&line).await {
+            self.pending.lock().unwrap().remove(&id);
+            state.process = None;
+            return Err(handler::crashed(format!("Cannot write to handler worker: {}", e)));
+        }
+        Ok(receiver)
+    }
+
+    async fn restart(&self, state: &mut State) -> Result<()> {
+        // Back off when the previous worker crashed soon after starting.
+        if let Some(started) = state.started {
+            if started.elapsed() < STABLE_AFTER {
+                let delay = state.delay.unwrap_or(RESTART_DELAY);
+                eprintln!("Restarting handler worker in {}s", delay.as_secs());
+                tokio::time::sleep(delay).await;
+                state.delay = Some((delay * 2).min(MAX_RESTART_DELAY));
+            } else {
+                state.delay = None;
+            }
+        }
+        state.started = Some(Instant::now());
+
+        let mut command = handler::command(&self.path, &self.options);
+        command.stderr(Stdio::inherit());
+        let mut child = command
+            .spawn()
+            .map_err(|e| handler::task_failed(format!("Cannot start handler worker {}: {}", self.path, e)))?;
+        let stdin = child.stdin.take().ok_or_else(|| anyhow!("Failed to open stdin"))?;
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("Failed to open stdout"))?;
+
+        let exited = Arc::new(AtomicBool::new(false));
+        tokio::spawn(read_responses(
+            child,
+            stdout,
+            self.pending.clone(),
+            exited.clone(),
+            self.options.max_output,
+        ));
+        state.process = Some(Process { stdin, exited });
+        Ok(())
+    }
+}
+
+/// Cancels request `id` with the worker when dropped, unless `state` was
+/// taken because the worker answered.
+struct Abandoned {
+    id: u64,
+    pending: Pending,
+    state: Option<Arc<tokio::sync::Mutex<State>>>,
+}
+
+impl Drop for Abandoned {
+    fn drop(&mut self) {
+        let Some(state) = self.state.take() else {
+            return;
+        };
+        self.pending.lock().unwrap().remove(&self.id);
+        let Ok(mut line) = serde_json::to_vec(&Cancel { id: self.id, cancel: true }) else {
+            return;
+        };
+        line.push(b'\n');
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Some(process) = state.lock().await.process.as_mut() {
+                    let _ = process.stdin.write_all(&line).await;
+                }
+            });
+        }
+    }
+}
+
+/// Delivers the worker's responses until it exits, then fails whatever is
+/// still waiting on it.
+async fn read_responses(
+    mut child: Child,
+    stdout: ChildStdout,
+    pending: Pending,
+    exited: Arc<AtomicBool>,
+    max_line: usize,
+) {
+    let mut reader = BufReader::new(stdout);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match (&mut reader).take(max_line as u64 + 1).read_until(b'\n', &mut line).await {
+            Ok(0) => break,
+            Ok(_) if line.len() > max_line => {
+                eprintln!("Handler worker wrote a line over {} bytes", max_line);
+                break;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Cannot read from handler worker: {}", e);
+                break;
+            }
+        }
+
+        let response: Response = match serde_json::from_slice(&line) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Ignoring invalid line from handler worker: {}", e);
+                continue;
+            }
+        };
+        let mut pending = pending.lock().unwrap();
+        if let Some(event) = response.wrap {
+            if let Some(progress) = pending.get(&response.id).and_then(|w| w.progress.as_ref()) {
+                let _ = progress.send(event);
+            }
+            continue;
+        }
+        let Some(waiter) = pending.remove(&response.id) else {
+            continue;
+        };
+        let reply = match response.err {
+            Some(err) => Err(err),
+            None => Ok(response.res),
+        };
+        let _ = waiter.reply.send(reply);
+    }
+
+    exited.store(true, Ordering::SeqCst);
+    let _ = child.start_kill();
+    match child.wait().await {
+        Ok(status) => eprintln!("Handler worker {}", handler::describe_exit(status)),
+        Err(e) => eprintln!("Handler worker lost: {}", e),
+    }
+    pending.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ErrorCode, ProtocolError};
+    use crate::test_util::TempFile;
+
+    fn script(name: &str, body: &str) -> TempFile {
+        use std::os::unix::fs::PermissionsExt;
+        let path = TempFile::new("worker", name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_responses_matched_by_id() {
+        // Answers the second request before the first.
+        let path = script(
+            "reorder",
+            "read a; read b\necho \"$b\" | sed 's/\"msg\":/\"res\":/'\necho \"$a\" | sed 's/\"msg\":/\"res\":/'\nsleep 5\n",
+        );
+        let worker = Worker::new(path.to_str(), &HandlerOptions::default());
+
+        let first = serde_json::json!({"n": 1});
+        let second = serde_json::json!({"n": 2});
+        let (a, b) = tokio::join!(worker.call(&first, None), worker.call(&second, None));
+        assert_eq!(a.unwrap(), first);
+        assert_eq!(b.unwrap(), second);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_call_cancels_request() {
+        let out = TempFile::new("worker", "cancelled");
+        let path = script("cancel", &format!("read a; read b\necho \"$b\" > {}\nsleep 5\n", out.display()));
+        let worker = Worker::new(path.to_str(), &HandlerOptions::default());
+
+        let request = serde_json::json!({"n": 1});
+        let call = worker.call(&request, None);
+        assert!(tokio::time::timeout(Duration::from_millis(300), call).await.is_err());
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let line = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(line.trim(), r#"{"id":1,"cancel":true}"#);
+        assert!(worker.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_restarts_after_crash() {
+        // Handles one request, then exits.
+        let path = script("once", "read a\necho \"$a\" | sed 's/\"msg\":.*/\"err\":\"nope\"}/'\n");
+        let worker = Worker::new(path.to_str(), &HandlerOptions::default());
+        let request = serde_json::json!({"n": 1});
+
+        for _ in 0..2 {
+            let err = worker.call(&request, None).await.unwrap_err();
+            let error = ProtocolError::from_anyhow(&err, ErrorCode::InternalError);
+            assert_eq!(error.code, ErrorCode::TaskFailed);
+            assert_eq!((error.msg.as_str(), error.recov), ("nope", false));
+            // Let the reader notice the exit before the next request.
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+}